@@ -0,0 +1,73 @@
+//! Variable lookup with interned symbols, against the string keys environments used to have
+//!
+//! Run with `cargo +nightly bench`
+
+#![feature(test)]
+
+extern crate rustyscheme;
+extern crate test;
+
+use rustyscheme::data::env::Environment;
+use rustyscheme::data::{SchemeObject, Sym};
+use std::collections::HashMap;
+use std::rc::Rc;
+use test::{black_box, Bencher};
+
+/// Depth of the environment chain: about as deep as a few nested lets inside a procedure
+const DEPTH: usize = 8;
+/// Bindings in each environment
+const WIDTH: usize = 32;
+
+fn name(depth: usize, i: usize) -> String {
+    format!("variable-{}-{}", depth, i)
+}
+
+/// Every name is looked up from the innermost environment, so most lookups walk several parents
+#[bench]
+fn interned(b: &mut Bencher) {
+    let mut env = Environment::new(None);
+    for depth in 0..DEPTH {
+        for i in 0..WIDTH {
+            let val = Rc::new(SchemeObject::Integer(i as i64));
+            env.borrow_mut().set(Sym::intern(&name(depth, i)), val);
+        }
+        env = Environment::new(Some(env));
+    }
+
+    let names: Vec<Sym> = (0..DEPTH)
+        .flat_map(|depth| (0..WIDTH).map(move |i| Sym::intern(&name(depth, i))))
+        .collect();
+
+    b.iter(|| {
+        for &sym in &names {
+            black_box(env.borrow().lookup(sym));
+        }
+    });
+}
+
+/// The same lookups in a chain of maps keyed by name, as environments were before interning
+#[bench]
+fn strings(b: &mut Bencher) {
+    let chain: Vec<HashMap<String, Rc<SchemeObject>>> = (0..DEPTH)
+        .map(|depth| {
+            (0..WIDTH)
+                .map(|i| (name(depth, i), Rc::new(SchemeObject::Integer(i as i64))))
+                .collect()
+        })
+        .collect();
+
+    let names: Vec<String> = (0..DEPTH)
+        .flat_map(|depth| (0..WIDTH).map(move |i| name(depth, i)))
+        .collect();
+
+    b.iter(|| {
+        for name in &names {
+            black_box(
+                chain
+                    .iter()
+                    .rev()
+                    .find_map(|names| names.get(name).cloned()),
+            );
+        }
+    });
+}
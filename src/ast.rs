@@ -1,9 +1,11 @@
 //! Constructs an Abstract Syntax Tree from the token stream
 
 use super::data::*;
-use super::tokenise::TokenIterator;
+use super::tokenise::{Token, TokenIterator};
+use span::{Position, Span};
 use std::collections::LinkedList;
 use std::iter::FromIterator;
+use std::str::Chars;
 use ParseError;
 
 /// Iterator over `SchemeObject`s
@@ -22,6 +24,11 @@ where
     pub fn new(source: TokenIterator<T>) -> Self {
         Self { source }
     }
+
+    /// Get back the `TokenIterator` (e.g. to take its lookahead character)
+    pub fn into_inner(self) -> TokenIterator<T> {
+        self.source
+    }
 }
 
 /// Create this from anything from which we can create a `TokenIterator`
@@ -56,6 +63,90 @@ where
     }
 }
 
+/// Parse the contents of a source file
+/// A `#!` line at the start (so that scripts can be run directly) is skipped. Source locations
+/// still count it.
+pub fn parse_source(source: &str) -> ObjectIterator<Chars<'_>> {
+    if source.starts_with("#!") {
+        let rest = match source.find('\n') {
+            Some(end) => &source[end + 1..],
+            None => "",
+        };
+        let start = Position { line: 2, col: 1 };
+        ObjectIterator::new(TokenIterator::new(rest.chars()).starting_at(start))
+    } else {
+        ObjectIterator::from(source.chars())
+    }
+}
+
+/// Keeps track of source which arrives a piece at a time (e.g. lines typed into the REPL) so that
+/// we know when it adds up to complete forms which can be parsed. Only the new text is looked at
+/// each time.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct InputState {
+    /// number of brackets which haven't been closed yet
+    depth: usize,
+    /// inside "..."
+    in_string: bool,
+    /// after a ';' and before the end of the line
+    in_comment: bool,
+    /// inside |...|
+    in_bars: bool,
+    /// the next character is escaped by a backslash
+    escaped: bool,
+    /// a ' or # is still waiting for what comes after it
+    prefixed: bool,
+    /// the last character was part of a symbol or number, so a '#' doesn't begin a new token
+    in_token: bool,
+}
+
+impl InputState {
+    /// Start with no input
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take account of the next piece of the source
+    pub fn feed(&mut self, source: &str) {
+        for c in source.chars() {
+            if self.in_comment {
+                self.in_comment = c != '\n';
+            } else if self.escaped {
+                self.escaped = false;
+            } else if c == '\\' {
+                self.escaped = true;
+                self.prefixed = false;
+                self.in_token = !self.in_string;
+            } else if self.in_string {
+                self.in_string = c != '"';
+            } else if self.in_bars {
+                self.in_bars = c != '|';
+            } else if c == ';' {
+                self.in_comment = true;
+                self.in_token = false;
+            } else if c.is_whitespace() {
+                self.in_token = false;
+            } else {
+                self.prefixed = c == '\'' || (c == '#' && !self.in_token);
+                self.in_token = !"()'\"".contains(c) && !self.prefixed;
+                match c {
+                    '"' => self.in_string = true,
+                    '|' => self.in_bars = true,
+                    '(' => self.depth += 1,
+                    // an unmatched ')' is an error for the parser to report
+                    ')' => self.depth = self.depth.saturating_sub(1),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// Does the source so far end part way through a form?
+    pub fn needs_more(&self) -> bool {
+        self.depth > 0 || self.in_string || self.in_bars || self.escaped || self.prefixed
+    }
+}
+
 /// fully consume a token iterator to produce a Vector of scheme objects
 pub fn parse_tokens<T, I>(to_object_iter: I) -> Result<Vec<SchemeObject>, ParseError>
 where
@@ -66,7 +157,6 @@ where
 }
 
 /// Consume a stream of tokens and output the first token generated
-/// This function just dispatches to the right helper function
 fn parse_token<T>(
     token_iter: &mut TokenIterator<T>,
     mode: &ParseMode,
@@ -74,31 +164,50 @@ fn parse_token<T>(
 where
     T: Iterator<Item = char>,
 {
-    let mode = match token_iter.next() {
-        None => {
-            return {
-                match mode {
-                    TokenOptional => Err(ParseError::EmptyStream),
-                    TokenRequired => Err(ParseError::MissingToken),
-                }
-            }
-        }
-        Some(s) => s,
-    };
+    match token_iter.next() {
+        None => match mode {
+            TokenOptional => Err(ParseError::EmptyStream),
+            TokenRequired => Err(ParseError::MissingToken),
+        },
+        Some(token) => parse_from(token, token_iter),
+    }
+}
+
+/// Parse the object starting with `token`
+/// This function just dispatches to the right helper function
+fn parse_from<T>(
+    token: Token,
+    token_iter: &mut TokenIterator<T>,
+) -> Result<SchemeObject, ParseError>
+where
+    T: Iterator<Item = char>,
+{
+    let span = token.span;
+
+    // anything written between bars is a symbol, even |(| or |12|
+    if token.quoted {
+        return Ok(SchemeObject::sym_from(&token.text));
+    }
 
     // dispatches to helper functions by the starting symbol
     // see tokenise.rs::is_special()
-    match mode.as_str() {
-        "(" => parse_token_form(token_iter), // (...)
+    // errors which don't already have a more precise location are reported at this token
+    match token.text.as_str() {
+        "(" => parse_token_form(token_iter, span), // (...)
         ")" => Err(ParseError::ClosingBracket),
         "'" => parse_token_quoted(token_iter), // quoted
         "#" => parse_token_hash(token_iter),   // #t, #f, #(...)
         s => parse_token_other(s),             // "string", symbol
     }
+    .map_err(|e| e.at(span))
 }
 
 /// Recursively parses a form (...)
-fn parse_token_form<T>(token_iter: &mut TokenIterator<T>) -> Result<SchemeObject, ParseError>
+/// `open` is the location of the opening bracket
+fn parse_token_form<T>(
+    token_iter: &mut TokenIterator<T>,
+    open: Span,
+) -> Result<SchemeObject, ParseError>
 where
     T: Iterator<Item = char>,
 {
@@ -106,16 +215,17 @@ where
 
     loop {
         // parse each item in this list
-        let obj = match parse_token(token_iter, &TokenRequired) {
-            Ok(o) => o,
-            Err(ParseError::ClosingBracket) => break,
-            Err(e) => return Err(e),
+        let token = match token_iter.next() {
+            Some(t) => t,
+            None => return Err(ParseError::MissingToken),
         };
 
-        lst.push_back(obj);
-    }
+        if token.text == ")" {
+            return Ok(SchemeObject::List(lst, Some(open.to(token.span))));
+        }
 
-    Ok(SchemeObject::List(lst))
+        lst.push_back(parse_from(token, token_iter)?);
+    }
 }
 
 /// Parse a hash token
@@ -123,9 +233,20 @@ fn parse_token_hash<T>(token_iter: &mut TokenIterator<T>) -> Result<SchemeObject
 where
     T: Iterator<Item = char>,
 {
-    match parse_token(token_iter, &TokenRequired)? {
-        SchemeObject::Symbol(s) => string_to_bool(s.as_str()), // #t, #f
-        SchemeObject::List(l) => Ok(SchemeObject::Vector(Vec::from_iter(l))), // #(...)
+    let token = match token_iter.next() {
+        Some(t) => t,
+        None => return Err(ParseError::MissingToken),
+    };
+
+    // the tokeniser keeps the backslash of #\x
+    if token.text.starts_with('\\') {
+        return parse_char(&token.text[1..]);
+    }
+
+    match parse_from(token, token_iter)? {
+        SchemeObject::Symbol(ref s) if s == "u8" => parse_bytevector(token_iter), // #u8(...)
+        SchemeObject::Symbol(s) => string_to_bool(&s.name()),                     // #t, #f
+        SchemeObject::List(l, _) => Ok(SchemeObject::Vector(Vec::from_iter(l))),  // #(...)
         obj => Err(ParseError::from(format!(
             "Syntax error: # followed by {:?}",
             obj
@@ -133,6 +254,59 @@ where
     }
 }
 
+/// helper function for `parse_token_hash`
+/// Parses the (...) part of #u8(...)
+fn parse_bytevector<T>(token_iter: &mut TokenIterator<T>) -> Result<SchemeObject, ParseError>
+where
+    T: Iterator<Item = char>,
+{
+    let lst = match parse_token(token_iter, &TokenRequired)? {
+        SchemeObject::List(l, _) => l,
+        obj => {
+            return Err(ParseError::from(format!(
+                "Syntax error: #u8 followed by {:?}",
+                obj
+            )))
+        }
+    };
+
+    let mut bytes = Vec::with_capacity(lst.len());
+    for obj in lst {
+        match obj {
+            SchemeObject::Integer(i) if (0..256).contains(&i) => bytes.push(i as u8),
+            obj => {
+                return Err(ParseError::from(format!(
+                    "Syntax error: {:?} is not a byte",
+                    obj
+                )))
+            }
+        }
+    }
+
+    Ok(SchemeObject::Bytevector(bytes))
+}
+
+/// helper function for `parse_token_hash`
+/// Parses the x part of #\x
+fn parse_char(name: &str) -> Result<SchemeObject, ParseError> {
+    let mut chars = name.chars();
+
+    match (chars.next(), chars.next()) {
+        (Some(c), None) => Ok(SchemeObject::Char(c)),
+        _ => match name {
+            "space" => Ok(SchemeObject::Char(' ')),
+            "newline" => Ok(SchemeObject::Char('\n')),
+            "tab" => Ok(SchemeObject::Char('\t')),
+            "return" => Ok(SchemeObject::Char('\r')),
+            "null" => Ok(SchemeObject::Char('\0')),
+            _ => Err(ParseError::from(format!(
+                "Syntax error: unknown character #\\{}",
+                name
+            ))),
+        },
+    }
+}
+
 /// Parse a quoted token
 fn parse_token_quoted<T>(token_iter: &mut TokenIterator<T>) -> Result<SchemeObject, ParseError>
 where
@@ -173,6 +347,8 @@ fn parse_token_other(token: &str) -> Result<SchemeObject, ParseError> {
     } else if token.is_empty() {
         // empty symbol
         Err(ParseError::PartialStream)
+    } else if let Ok(i) = token.parse() {
+        Ok(SchemeObject::Integer(i))
     } else {
         // valid symbol
         Ok(SchemeObject::sym_from(token))
@@ -183,6 +359,7 @@ fn parse_token_other(token: &str) -> Result<SchemeObject, ParseError> {
 mod tests {
     use ast::ParseError;
     use data::*;
+    use span::{Position, Span};
     use std::collections::LinkedList;
     use std::iter::FromIterator;
     use tokenise::tokenise;
@@ -207,7 +384,8 @@ mod tests {
     #[test]
     fn bad_hash() {
         let expected = ParseError::from("Syntax error: # followed by \"error\"");
-        run_test("#error", Err(expected))
+        let span = Span::at(Position { line: 1, col: 1 });
+        run_test("#error", Err(expected.at(span)))
     }
 
     #[test]
@@ -233,7 +411,7 @@ mod tests {
         lst.push_back(SchemeObject::sym_from("one"));
         lst.push_back(SchemeObject::sym_from("two"));
 
-        let expected = vec![SchemeObject::List(lst)];
+        let expected = vec![SchemeObject::from(lst)];
         run_test("(one two)", Ok(expected))
     }
 
@@ -242,12 +420,12 @@ mod tests {
         let mut inner_lst = LinkedList::new();
         inner_lst.push_back(SchemeObject::sym_from("one"));
         inner_lst.push_back(SchemeObject::sym_from("two"));
-        let inner_obj = SchemeObject::List(inner_lst);
+        let inner_obj = SchemeObject::from(inner_lst);
 
         let mut outer_lst = LinkedList::new();
         outer_lst.push_back(inner_obj);
         outer_lst.push_back(SchemeObject::from("three"));
-        let outer_obj = SchemeObject::List(outer_lst);
+        let outer_obj = SchemeObject::from(outer_lst);
 
         let expected = vec![outer_obj];
         run_test("((one two) \"three\")", Ok(expected))
@@ -271,9 +449,161 @@ mod tests {
             "symbol",
         ))));
         expected.push(SchemeObject::from("string"));
-        expected.push(SchemeObject::Quoted(Box::new(SchemeObject::List(l))));
+        expected.push(SchemeObject::Quoted(Box::new(SchemeObject::from(l))));
         expected.push(SchemeObject::Quoted(Box::new(SchemeObject::Vector(v))));
 
         run_test(scm, Ok(expected));
     }
+
+    #[test]
+    fn integers() {
+        let expected = vec![
+            SchemeObject::Integer(42),
+            SchemeObject::Integer(-7),
+            SchemeObject::sym_from("-"),
+        ];
+        run_test("42 -7 -", Ok(expected))
+    }
+
+    #[test]
+    fn bytevector() {
+        let expected = vec![SchemeObject::Bytevector(vec![0, 255])];
+        run_test("#u8(0 255)", Ok(expected))
+    }
+
+    #[test]
+    fn bad_bytevector() {
+        let expected = ParseError::from("Syntax error: 256 is not a byte");
+        let span = Span::at(Position { line: 1, col: 1 });
+        run_test("#u8(256)", Err(expected.at(span)))
+    }
+
+    #[test]
+    fn characters() {
+        let expected = vec![
+            SchemeObject::Char('a'),
+            SchemeObject::Char(' '),
+            SchemeObject::Char('('),
+            SchemeObject::Char('\n'),
+        ];
+        run_test("#\\a #\\space #\\( #\\newline", Ok(expected))
+    }
+
+    /// `read(write(x)) == x`: printing data with `write` and parsing it gives back the same data
+    #[test]
+    fn write_round_trip() {
+        use data::print::{print, Style};
+
+        let mut inner = LinkedList::new();
+        inner.push_back(SchemeObject::Char('\n'));
+        inner.push_back(SchemeObject::Char('"'));
+        inner.push_back(SchemeObject::Vector(vec![SchemeObject::Integer(-3)]));
+
+        let mut outer = LinkedList::new();
+        outer.push_back(SchemeObject::from(
+            "quote \" backslash \\ newline \n tab \t;",
+        ));
+        outer.push_back(SchemeObject::from(inner));
+        outer.push_back(SchemeObject::Quoted(Box::new(SchemeObject::sym_from(
+            "sym",
+        ))));
+        outer.push_back(SchemeObject::Bytevector(vec![0, 1, 255]));
+        outer.push_back(SchemeObject::from(LinkedList::new()));
+        outer.push_back(SchemeObject::from(false));
+
+        let data = vec![
+            SchemeObject::from(outer),
+            SchemeObject::from(""),
+            SchemeObject::Char('\\'),
+            SchemeObject::Char(' '),
+            SchemeObject::Char(')'),
+            SchemeObject::sym_from("a-symbol"),
+            SchemeObject::sym_from(""),
+            SchemeObject::sym_from("12"),
+            SchemeObject::sym_from("-3"),
+            SchemeObject::sym_from("."),
+            SchemeObject::sym_from("("),
+        ];
+
+        for style in [Style::Write, Style::WriteShared, Style::WriteSimple].iter() {
+            for x in &data {
+                let written = print(x, *style);
+                let read = super::parse_tokens(written.chars());
+                assert_eq!(read, Ok(vec![x.clone()]), "{}", written);
+            }
+        }
+    }
+
+    #[test]
+    fn shebang() {
+        let source = "#!/usr/bin/env rustyscheme\n(one)";
+        let res: Result<Vec<SchemeObject>, ParseError> = super::parse_source(source).collect();
+
+        match res.unwrap().as_slice() {
+            [SchemeObject::List(_, Some(span))] => assert_eq!(span.start.line, 2),
+            o => panic!("expected one list, got {:?}", o),
+        }
+    }
+
+    #[test]
+    fn input_state() {
+        let needs_more = |lines: &[&str]| {
+            let mut state = super::InputState::new();
+            for line in lines {
+                state.feed(line);
+                state.feed("\n");
+            }
+            state.needs_more()
+        };
+
+        assert!(!needs_more(&[]));
+        assert!(!needs_more(&["(a b) c"]));
+        assert!(needs_more(&["(define (f x)"]));
+        assert!(!needs_more(&["(define (f x)", "  (g x))"]));
+        assert!(needs_more(&["(display \"a", "b)"]));
+        assert!(!needs_more(&["(display \"a", "b\")"]));
+        assert!(!needs_more(&["(list #\\( \"(\\\"\") ; ("]));
+        assert!(needs_more(&["'"]));
+        assert!(!needs_more(&["'", "a"]));
+        assert!(!needs_more(&["a)"]));
+        assert!(!needs_more(&["'|a (|"]));
+        assert!(needs_more(&["'|a (", "b"]));
+        assert!(!needs_more(&["'|a (", "b|"]));
+        assert!(!needs_more(&["a#"]));
+        assert!(needs_more(&["a #"]));
+    }
+
+    #[test]
+    fn unclosed_form() {
+        let span = Span::at(Position { line: 2, col: 3 });
+        run_test("(one)\n  (two", Err(ParseError::MissingToken.at(span)))
+    }
+
+    #[test]
+    fn stray_bracket() {
+        let span = Span::at(Position { line: 1, col: 6 });
+        run_test("(one))", Err(ParseError::ClosingBracket.at(span)))
+    }
+
+    #[test]
+    fn form_span() {
+        let res = super::parse_tokens(" (one\n (two))".chars()).unwrap();
+        let span = |l1, c1, l2, c2| {
+            Some(Span::new(
+                Position { line: l1, col: c1 },
+                Position { line: l2, col: c2 },
+            ))
+        };
+
+        match &res[0] {
+            SchemeObject::List(lst, outer) => {
+                assert_eq!(*outer, span(1, 2, 2, 7));
+                match lst.back() {
+                    Some(SchemeObject::List(_, inner)) => assert_eq!(*inner, span(2, 2, 2, 6)),
+                    o => panic!("expected a list, got {:?}", o),
+                }
+            }
+            o => panic!("expected a list, got {:?}", o),
+        }
+    }
 }
@@ -0,0 +1,102 @@
+//! REPL commands, which start with a comma e.g. ,help
+
+use super::{error_report, eval_input, load_file, paint, Stop, ERROR_COLOUR};
+
+use rustyscheme::ast;
+use rustyscheme::data::env::PackedEnv;
+use rustyscheme::data::expand::expand;
+use rustyscheme::data::help;
+use rustyscheme::span::Position;
+use rustyscheme::tokenise::TokenIterator;
+
+use std::ops::ControlFlow;
+use std::time::Instant;
+
+/// The commands and what they do
+const COMMANDS: [(&str, &str); 6] = [
+    (",help [name]", "describe name, or list these commands"),
+    (",env", "list everything which is bound"),
+    (",time expr", "evaluate expr and say how long it took"),
+    (",expand expr", "show expr with its derived forms expanded"),
+    (",load file", "evaluate the contents of file"),
+    (",quit", "leave the REPL"),
+];
+
+/// Does this line of input hold a command rather than scheme?
+pub fn is_command(line: &str) -> bool {
+    line.trim_start().starts_with(',')
+}
+
+/// Run the command in `line`, which starts at `start` in the `transcript` of the session
+/// Breaks with the status to exit with when the REPL should stop
+pub fn run(
+    line: &str,
+    start: Position,
+    env: &PackedEnv,
+    transcript: &str,
+    colour: bool,
+) -> ControlFlow<i32> {
+    let command = line.trim_start();
+    let (name, arg) = match command.find(char::is_whitespace) {
+        Some(i) => (&command[..i], &command[i..]),
+        None => (command, ""),
+    };
+    // where arg starts (so that errors in it can be shown)
+    let arg_pos = Position {
+        line: start.line,
+        col: start.col + line.chars().count() - arg.chars().count(),
+    };
+    let error = |msg: &str| println!("{}", paint(msg, ERROR_COLOUR, colour));
+
+    match name {
+        ",help" => match arg.trim() {
+            "" => {
+                for (command, what) in COMMANDS.iter() {
+                    println!("{:<14} {}", command, what);
+                }
+            }
+            name => match help::describe(name, env) {
+                Some(description) => println!("{}", description),
+                None => error(&format!("{} isn't bound", name)),
+            },
+        },
+        ",env" => {
+            for (name, val) in env.borrow().all_bindings() {
+                println!("{}", help::summary(&name, &val));
+            }
+        }
+        ",time" => {
+            let started = Instant::now();
+            eval_input(arg, arg_pos, env, transcript, colour)?;
+            println!("; {:?}", started.elapsed());
+        }
+        ",expand" => {
+            let tokens = TokenIterator::new(arg.chars()).starting_at(arg_pos);
+            for obj in ast::ObjectIterator::new(tokens) {
+                match obj {
+                    Ok(obj) => println!("{:?}", expand(&obj)),
+                    Err(e) => {
+                        let msg = format!("Parse Error: {:?}", e.root());
+                        error(&error_report("<repl>", transcript, e.span(), &msg));
+                        break;
+                    }
+                }
+            }
+        }
+        ",load" => match arg.trim() {
+            "" => error(",load expects a file name"),
+            file => match load_file(file, env) {
+                Ok(()) => (),
+                Err(Stop::Exit(status)) => return ControlFlow::Break(status),
+                Err(Stop::Error(report)) => error(&report),
+            },
+        },
+        ",quit" => return ControlFlow::Break(0),
+        _ => error(&format!(
+            "Unknown command {}. Try ,help for a list of commands",
+            name
+        )),
+    }
+
+    ControlFlow::Continue(())
+}
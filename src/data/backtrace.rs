@@ -0,0 +1,177 @@
+//! Scheme-level backtraces for runtime errors
+
+use super::scm_obj::SchemeObject;
+use span::Span;
+
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+/// How many of the innermost frames to keep
+const INNER_FRAMES: usize = 16;
+/// How many of the outermost frames to keep
+const OUTER_FRAMES: usize = 16;
+/// Rendered frames are cut short after this many characters
+const MAX_FRAME_WIDTH: usize = 72;
+
+/// A function call which an error passed through on its way up
+#[derive(Debug, PartialEq, Clone)]
+pub struct Frame {
+    /// Name of the function (`lambda` for anonymous functions)
+    pub name: String,
+    /// Location of the code which made the call. `None` for calls made from rust.
+    pub call_site: Option<Span>,
+    /// The (evaluated) arguments to the call
+    pub args: Vec<Rc<SchemeObject>>,
+    /// How many tail calls replaced the frame. `call_site` is where the latest one was made.
+    pub tail_calls: usize,
+}
+
+impl Frame {
+    /// Create a new `Frame` with no call site
+    pub fn new<'a, I>(name: &str, args: I) -> Self
+    where
+        I: IntoIterator<Item = &'a Rc<SchemeObject>>,
+    {
+        Self {
+            name: String::from(name),
+            call_site: None,
+            args: args.into_iter().cloned().collect(),
+            tail_calls: 0,
+        }
+    }
+
+    /// Print the frame like the call which made it e.g. (f "a" #t)
+    fn render_call(&self) -> String {
+        let mut call = format!("({}", self.name);
+        for arg in &self.args {
+            call += &format!(" {:?}", arg);
+        }
+        call.push(')');
+
+        if call.chars().count() > MAX_FRAME_WIDTH {
+            call = call.chars().take(MAX_FRAME_WIDTH - 3).collect();
+            call += "...";
+        }
+
+        call
+    }
+}
+
+/// The frames an error passed through, innermost first
+/// Deep recursion would make this grow without bound so only the innermost and outermost frames
+/// are kept. The number of frames dropped from the middle is counted instead.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Backtrace {
+    inner: Vec<Frame>,
+    outer: VecDeque<Frame>,
+    elided: usize,
+}
+
+impl Backtrace {
+    /// Create an empty `Backtrace`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the next frame out
+    pub fn push(&mut self, frame: Frame) {
+        if self.inner.len() < INNER_FRAMES {
+            self.inner.push(frame);
+            return;
+        }
+
+        if self.outer.len() == OUTER_FRAMES {
+            self.outer.pop_front();
+            self.elided += 1;
+        }
+        self.outer.push_back(frame);
+    }
+
+    /// The outermost frame so far
+    pub fn outermost_mut(&mut self) -> Option<&mut Frame> {
+        match self.outer.back_mut() {
+            Some(f) => Some(f),
+            None => self.inner.last_mut(),
+        }
+    }
+
+    /// The kept frames, innermost first
+    pub fn frames(&self) -> impl Iterator<Item = &Frame> {
+        self.inner.iter().chain(self.outer.iter())
+    }
+
+    /// Number of frames which were dropped to keep the backtrace small
+    pub fn elided(&self) -> usize {
+        self.elided
+    }
+
+    /// Format the backtrace for the user. Call sites are reported as being in `file`.
+    pub fn render(&self, file: &str) -> String {
+        let mut out = String::from("Backtrace (most recent call first):");
+
+        for (i, frame) in self.inner.iter().enumerate() {
+            out += &render_frame(i, frame, file);
+        }
+
+        if self.elided > 0 {
+            out += &format!("\n  ... {} frames omitted ...", self.elided);
+        }
+
+        let first_outer = self.inner.len() + self.elided;
+        for (i, frame) in self.outer.iter().enumerate() {
+            out += &render_frame(first_outer + i, frame, file);
+        }
+
+        out
+    }
+}
+
+/// Helper for `Backtrace::render`
+fn render_frame(index: usize, frame: &Frame, file: &str) -> String {
+    let mut out = format!("\n  {}: {}", index, frame.render_call());
+
+    if let Some(span) = frame.call_site {
+        out += &format!(" at {}:{}", file, span);
+    }
+
+    match frame.tail_calls {
+        0 => {}
+        1 => out += "\n     ... 1 tail call elided",
+        n => out += &format!("\n     ... {} tail calls elided", n),
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use span::Position;
+
+    #[test]
+    fn bounded() {
+        let mut bt = Backtrace::new();
+        let total = INNER_FRAMES + OUTER_FRAMES + 10;
+        for i in 0..total {
+            bt.push(Frame::new(&i.to_string(), &[]));
+        }
+
+        assert_eq!(bt.frames().count(), INNER_FRAMES + OUTER_FRAMES);
+        assert_eq!(bt.elided(), 10);
+        assert_eq!(bt.frames().next().unwrap().name, "0");
+        assert_eq!(bt.frames().last().unwrap().name, (total - 1).to_string());
+    }
+
+    #[test]
+    fn render() {
+        let mut bt = Backtrace::new();
+        bt.push(Frame::new("f", &[Rc::new(SchemeObject::from("a"))]));
+        bt.push(Frame::new("g", &[]));
+        bt.outermost_mut().unwrap().call_site = Some(Span::at(Position { line: 3, col: 1 }));
+
+        assert_eq!(
+            bt.render("test.scm"),
+            "Backtrace (most recent call first):\n  0: (f \"a\")\n  1: (g) at test.scm:3:1"
+        );
+    }
+}
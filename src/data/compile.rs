@@ -0,0 +1,1100 @@
+//! Compiles scheme code into bytecode for the virtual machine (see vm.rs)
+//!
+//! Variables are resolved when the code is compiled. Arguments and variables bound by let are kept
+//! in the function's frame on the VM's stack, so they are found by their slot there. Functions
+//! capture the values of the variables they use from enclosing functions when they are made (there
+//! is no set! so the values can't change), and find those by index. Other variables are looked up
+//! by name in the environment the code is run in.
+//!
+//! Calls in tail position (the last thing a function body does, through if, let and the forms
+//! binding multiple values) are compiled to `Op::TailCall`, which reuses the caller's frame.
+
+use super::record::{self, RecordType};
+use super::scm_obj::SchemeObject;
+use super::symbol::Sym;
+use super::RuntimeError;
+use span::Span;
+use stdlib::get_none;
+
+use std::collections::LinkedList;
+use std::rc::Rc;
+
+/// One virtual machine instruction
+/// Instructions work on a stack of values. Their arguments index the tables in `Code`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    /// Push a constant
+    Const(u32),
+    /// Push the variable in a slot of the current frame
+    Local(u32),
+    /// Push a variable captured by the current function
+    Free(u32),
+    /// Push the value of a variable found by name
+    Global(Sym),
+    /// Pop a value and define a variable as it in the global environment. Push None.
+    Define(Sym),
+    /// Name the anonymous function on top of the stack (e.g. (define f (lambda ..)))
+    Name(Sym),
+    /// Push a function, capturing the variables it uses
+    Closure(u32),
+    /// Replace the function on top of the stack with a promise to call it. True for delay-force,
+    /// where the function gives another promise.
+    Delay(bool),
+    /// Pop this many values and push a list of them
+    List(u32),
+    /// Pop this many arguments and then the function to call with them. Push the result.
+    Call(u32),
+    /// Like `Call`, for a call whose result the current function returns. A scheme function
+    /// called from a scheme function replaces the caller's frame instead of pushing a new one.
+    TailCall(u32),
+    /// Pop multiple values and then the function to call with them as its arguments. Push the
+    /// result.
+    CallValues,
+    /// Pop multiple values and push them: exactly this many, or when true at least this many with
+    /// the rest pushed as a list after them
+    Unpack(u32, bool),
+    /// Remove this many let variables from under the value on top of the stack
+    EndLet(u32),
+    /// Continue from an instruction
+    Jump(u32),
+    /// Pop a value and jump if it isn't #t
+    JumpUnlessTrue(u32),
+    /// Throw away the top of the stack
+    Pop,
+    /// Run a guard form. Push the result.
+    Guard(u32),
+    /// (import ...) where the constant is the rest of the form. Push None.
+    Import(u32),
+    /// (define-library ...) where the constant is the rest of the form. Push None.
+    DefineLibrary(u32),
+    /// Raise an error found while compiling (e.g. a badly formed let)
+    Fail(u32),
+    /// Return the top of the stack from the current function
+    Return,
+}
+
+impl Op {
+    /// How much the instruction changes the height of the stack
+    fn stack_effect(self) -> i64 {
+        match self {
+            Op::Const(_)
+            | Op::Local(_)
+            | Op::Free(_)
+            | Op::Global(_)
+            | Op::Closure(_)
+            | Op::Guard(_)
+            | Op::Import(_)
+            | Op::DefineLibrary(_)
+            | Op::Fail(_) => 1,
+            Op::Define(_) | Op::Name(_) | Op::Delay(_) | Op::Jump(_) => 0,
+            Op::List(n) => 1 - i64::from(n),
+            Op::Call(argc) | Op::TailCall(argc) | Op::EndLet(argc) => -i64::from(argc),
+            Op::JumpUnlessTrue(_) | Op::Pop | Op::CallValues | Op::Return => -1,
+            Op::Unpack(n, rest) => i64::from(n) + i64::from(rest) - 1,
+        }
+    }
+}
+
+/// Compiled code
+#[derive(Debug, Default)]
+pub struct Code {
+    pub(crate) ops: Vec<Op>,
+    /// Where each instruction came from: the innermost form with a known location
+    pub(crate) spans: Vec<Option<Span>>,
+    pub(crate) consts: Vec<Rc<SchemeObject>>,
+    pub(crate) lambdas: Vec<Rc<Lambda>>,
+    pub(crate) guards: Vec<Guard>,
+    pub(crate) errors: Vec<RuntimeError>,
+}
+
+/// A function as it was written, along with its compiled body
+#[derive(Debug)]
+pub struct Lambda {
+    /// Argument names
+    pub args: Vec<Sym>,
+    /// The body as written
+    pub body: SchemeObject,
+    /// The docstring
+    pub doc: Option<String>,
+    pub(crate) code: Rc<Code>,
+    /// Where the variables the function uses from enclosing functions are found when it is made
+    pub(crate) captures: Vec<Capture>,
+}
+
+/// Where a captured variable is found in the function making the closure
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Capture {
+    /// A slot of its frame
+    Local(u32),
+    /// One of its own captured variables
+    Free(u32),
+}
+
+/// A compiled (guard (var clause...) body...)
+/// The parts are compiled as functions so that they can be run on their own
+#[derive(Debug)]
+pub(crate) struct Guard {
+    /// Takes no arguments
+    pub(crate) body: Rc<Lambda>,
+    pub(crate) clauses: Vec<Clause>,
+}
+
+/// A compiled guard clause (test result...). The functions take var as their argument.
+#[derive(Debug)]
+pub(crate) struct Clause {
+    /// None for else
+    pub(crate) test: Option<Rc<Lambda>>,
+    /// None when the value of the test is the result
+    pub(crate) body: Option<Rc<Lambda>>,
+}
+
+/// The variables of a function being compiled
+#[derive(Default)]
+struct Function {
+    /// The variables which can be seen and their slots, innermost last
+    /// Variables replaced by a global definition are None (see `Compiler::define`).
+    locals: Vec<(Option<Sym>, u32)>,
+    /// Where the variables of each binding form (the arguments and each let) start in `locals`
+    blocks: Vec<usize>,
+    /// How many values are in the frame: variables and values being worked on
+    height: u32,
+    /// The variables captured from enclosing functions, by index
+    free: Vec<(Sym, Capture)>,
+}
+
+impl Function {
+    /// A function taking `args`
+    fn new(args: &[Sym]) -> Self {
+        Self {
+            locals: args
+                .iter()
+                .enumerate()
+                .map(|(slot, &name)| (Some(name), slot as u32))
+                .collect(),
+            blocks: vec![0],
+            height: args.len() as u32,
+            free: Vec::new(),
+        }
+    }
+
+    /// Find a variable in the frame (the last binding of a name wins)
+    fn local(&self, name: Sym) -> Option<u32> {
+        self.locals
+            .iter()
+            .rev()
+            .find(|&&(n, _)| n == Some(name))
+            .map(|&(_, slot)| slot)
+    }
+
+    /// Find a variable this function has already captured
+    fn captured(&self, name: Sym) -> Option<u32> {
+        self.free
+            .iter()
+            .position(|&(n, _)| n == name)
+            .map(|i| i as u32)
+    }
+}
+
+/// Compile code to be run in any environment
+pub fn compile(obj: &SchemeObject) -> Code {
+    let mut functions = vec![Function::new(&[])];
+    let mut compiler = Compiler::new(&mut functions);
+    compiler.expr(obj);
+    compiler.finish()
+}
+
+/// Compiles the body of the innermost of `functions`
+struct Compiler<'f> {
+    code: Code,
+    /// The functions the code is inside, innermost last
+    functions: &'f mut Vec<Function>,
+    /// Location of the form being compiled
+    span: Option<Span>,
+}
+
+impl<'f> Compiler<'f> {
+    fn new(functions: &'f mut Vec<Function>) -> Self {
+        Self {
+            code: Code::default(),
+            functions,
+            span: None,
+        }
+    }
+
+    /// Finish the code by returning the last value
+    fn finish(mut self) -> Code {
+        self.emit(Op::Return);
+        self.code
+    }
+
+    fn function(&mut self) -> &mut Function {
+        self.functions.last_mut().unwrap()
+    }
+
+    /// Compile a function taking `args`. `body` compiles its body.
+    fn lambda_code<F>(
+        &mut self,
+        args: Vec<Sym>,
+        source: SchemeObject,
+        doc: Option<String>,
+        body: F,
+    ) -> Rc<Lambda>
+    where
+        F: FnOnce(&mut Compiler),
+    {
+        self.functions.push(Function::new(&args));
+        let mut nested = Compiler::new(self.functions);
+        nested.span = self.span;
+        body(&mut nested);
+        let code = Rc::new(nested.finish());
+        let function = self.functions.pop().unwrap();
+
+        Rc::new(Lambda {
+            args,
+            body: source,
+            doc,
+            code,
+            captures: function.free.into_iter().map(|(_, c)| c).collect(),
+        })
+    }
+
+    fn emit(&mut self, op: Op) {
+        let height = i64::from(self.function().height) + op.stack_effect();
+        self.function().height = height as u32;
+        self.code.ops.push(op);
+        self.code.spans.push(self.span);
+    }
+
+    /// Where the next instruction will go
+    fn here(&self) -> u32 {
+        self.code.ops.len() as u32
+    }
+
+    /// Point the jump at `at` to the next instruction
+    fn patch(&mut self, at: u32) {
+        let target = self.here();
+        match self.code.ops[at as usize] {
+            Op::Jump(ref mut t) | Op::JumpUnlessTrue(ref mut t) => *t = target,
+            op => unreachable!("{:?} isn't a jump", op),
+        }
+    }
+
+    fn constant(&mut self, obj: Rc<SchemeObject>) -> u32 {
+        self.code.consts.push(obj);
+        self.code.consts.len() as u32 - 1
+    }
+
+    /// Compile code to push the value of `obj`
+    fn expr(&mut self, obj: &SchemeObject) {
+        self.expr_at(obj, false);
+    }
+
+    /// Compile code to push the value of `obj`, which the function returns when `tail_pos`
+    fn expr_at(&mut self, obj: &SchemeObject, tail_pos: bool) {
+        match obj {
+            SchemeObject::List(lst, span) => {
+                let outer = self.span;
+                if span.is_some() {
+                    self.span = *span;
+                }
+
+                // errors in the form are raised when it is run
+                let start = self.code.ops.len();
+                let function = self.function();
+                let saved = (
+                    function.height,
+                    function.locals.len(),
+                    function.blocks.len(),
+                );
+                if let Err(e) = self.form(lst, tail_pos) {
+                    self.code.ops.truncate(start);
+                    self.code.spans.truncate(start);
+                    let function = self.function();
+                    function.height = saved.0;
+                    function.locals.truncate(saved.1);
+                    function.blocks.truncate(saved.2);
+                    self.code.errors.push(e);
+                    let i = self.code.errors.len() as u32 - 1;
+                    self.emit(Op::Fail(i));
+                }
+
+                self.span = outer;
+            }
+            SchemeObject::Symbol(name) => self.variable(*name),
+            SchemeObject::None => {
+                let c = self.constant(get_none());
+                self.emit(Op::Const(c));
+            }
+            obj => {
+                let c = self.constant(Rc::new(obj.clone()));
+                self.emit(Op::Const(c));
+            }
+        }
+    }
+
+    /// Compile code to push the values of `forms` one after the other, leaving the last one
+    /// (or None if there aren't any). The last one is in tail position when `tail_pos`.
+    fn sequence<'a, I>(&mut self, forms: I, tail_pos: bool)
+    where
+        I: IntoIterator<Item = &'a SchemeObject>,
+    {
+        let mut forms = forms.into_iter().peekable();
+        let mut first = true;
+        while let Some(form) = forms.next() {
+            if !first {
+                self.emit(Op::Pop);
+            }
+            self.expr_at(form, tail_pos && forms.peek().is_none());
+            first = false;
+        }
+
+        if first {
+            self.expr(&SchemeObject::None);
+        }
+    }
+
+    /// Push a variable: a local variable if it is bound by an enclosing form, or else a global one
+    fn variable(&mut self, name: Sym) {
+        let innermost = self.functions.len() - 1;
+        let op = match resolve(self.functions, innermost, name) {
+            Some(Capture::Local(slot)) => Op::Local(slot),
+            Some(Capture::Free(i)) => Op::Free(i),
+            None => Op::Global(name),
+        };
+        self.emit(op);
+    }
+
+    /// Compile a code list: a special form or a function call
+    fn form(&mut self, lst: &LinkedList<SchemeObject>, tail_pos: bool) -> Result<(), RuntimeError> {
+        let tail: Vec<&SchemeObject> = lst.iter().skip(1).collect();
+
+        match lst.front() {
+            None => Err(RuntimeError::from("Executing empty codelist")),
+            Some(SchemeObject::Symbol(cmd)) => match &*cmd.name() {
+                "define" => self.define(&tail),
+                "let" => self.scm_let(&tail, tail_pos),
+                "lambda" => self.lambda(&tail),
+                "if" => self.scm_if(&tail, tail_pos),
+                "guard" => self.guard(&tail),
+                "delay" => self.delay(&tail, false),
+                "delay-force" => self.delay(&tail, true),
+                "stream-cons" => self.stream_cons(&tail),
+                "define-record-type" => self.define_record_type(&tail),
+                "call-with-values" => self.call_with_values(&tail),
+                "receive" => self.receive(&tail, tail_pos),
+                "let-values" => self.let_values(&tail, false, tail_pos),
+                "let*-values" => self.let_values(&tail, true, tail_pos),
+                "define-values" => self.define_values(&tail),
+                "import" => self.library_form(&tail, Op::Import),
+                "define-library" => self.library_form(&tail, Op::DefineLibrary),
+                _ => self.call(lst, tail_pos),
+            },
+            Some(SchemeObject::List(_, _)) => self.call(lst, tail_pos),
+            Some(obj) => Err(RuntimeError::from(format!(
+                "{:?} found; function name expected",
+                obj
+            ))),
+        }
+    }
+
+    /// (f arg...)
+    fn call(&mut self, lst: &LinkedList<SchemeObject>, tail_pos: bool) -> Result<(), RuntimeError> {
+        for obj in lst {
+            self.expr(obj);
+        }
+
+        let argc = lst.len() as u32 - 1;
+        self.emit(if tail_pos {
+            Op::TailCall(argc)
+        } else {
+            Op::Call(argc)
+        });
+        Ok(())
+    }
+
+    /// (define name value) or (define (name arg...) [docstring] body)
+    fn define(&mut self, tail: &[&SchemeObject]) -> Result<(), RuntimeError> {
+        let name = self.binding(tail)?;
+        self.define_global(name);
+        Ok(())
+    }
+
+    /// Pop a value and define `name` as it
+    fn define_global(&mut self, name: Sym) {
+        // we don't want any local variables in the innermost binding form to shadow the new
+        // definition
+        let function = self.function();
+        let block = *function.blocks.last().unwrap();
+        for local in function.locals[block..].iter_mut() {
+            if local.0 == Some(name) {
+                local.0 = None;
+            }
+        }
+
+        self.emit(Op::Define(name));
+    }
+
+    /// Compile code to push the value for a binding (name value) or ((name arg...) body), named
+    /// after the variable. Used by define and let. Returns the name.
+    fn binding(&mut self, binding: &[&SchemeObject]) -> Result<Sym, RuntimeError> {
+        // function bindings can have a docstring as well
+        let is_function = matches!(binding.first(), Some(SchemeObject::List(_, _)));
+        if binding.len() != 2 && !(is_function && binding.len() == 3) {
+            return Err(RuntimeError::from("Expected 2 arguments"));
+        }
+
+        let name = match binding[0] {
+            // ordinary variable binding
+            SchemeObject::Symbol(name) => {
+                self.expr(binding[1]);
+                *name
+            }
+            // function binding
+            SchemeObject::List(lst, _) => {
+                let mut lst_iter = lst.iter();
+
+                // first list item is the function name
+                let name = match lst_iter.next() {
+                    Some(SchemeObject::Symbol(name)) => *name,
+                    Some(_) => return Err(RuntimeError::from("You can't name a function that")),
+                    None => return Err(RuntimeError::from("Empty assignment list")),
+                };
+
+                self.closure(lst_iter, &binding[1..])?;
+                name
+            }
+            // neither a function binding nor a symbol
+            _ => return Err(RuntimeError::from("You can't name a variable that")),
+        };
+
+        self.emit(Op::Name(name));
+        Ok(name)
+    }
+
+    /// (lambda (arg...) [docstring] body)
+    fn lambda(&mut self, tail: &[&SchemeObject]) -> Result<(), RuntimeError> {
+        // two arguments: argument names and the function body (with an optional docstring between)
+        // TODO additional arguments are more code statements for the fn body like in a let?
+        if tail.len() != 2 && tail.len() != 3 {
+            return Err(RuntimeError::from(
+                "Expected 2 arguments to lambda (3 with a docstring)",
+            ));
+        }
+
+        match tail[0] {
+            SchemeObject::List(lst, _) => self.closure(lst.iter(), &tail[1..]),
+            _ => Err(RuntimeError::from(
+                "Expected the first argument to lambda to be a list",
+            )),
+        }
+    }
+
+    /// Compile a function with the argument names `names` and the docstring and body in `rest`,
+    /// and code to push a closure of it
+    fn closure<'a, I>(&mut self, names: I, rest: &[&SchemeObject]) -> Result<(), RuntimeError>
+    where
+        I: Iterator<Item = &'a SchemeObject>,
+    {
+        // read in argument names
+        let mut args = Vec::new();
+        for obj in names {
+            match obj {
+                SchemeObject::Symbol(name) => args.push(*name),
+                _ => return Err(RuntimeError::from("You can't call a variable that")),
+            }
+        }
+
+        let (doc, body) = doc_and_body(rest)?;
+
+        let lambda = self.lambda_code(args, body.clone(), doc, |c| c.expr_at(body, true));
+        self.code.lambdas.push(lambda);
+        let i = self.code.lambdas.len() as u32 - 1;
+        self.emit(Op::Closure(i));
+        Ok(())
+    }
+
+    /// (let ((name value)...) body...)
+    /// Function bindings ((name arg...) body) can be used as well
+    fn scm_let(&mut self, tail: &[&SchemeObject], tail_pos: bool) -> Result<(), RuntimeError> {
+        if tail.len() < 2 {
+            return Err(RuntimeError::from("let should have at least 2 arguments"));
+        }
+
+        // this should be the list of lists of variables and mappings
+        let bindings = match tail[0] {
+            SchemeObject::List(lst, _) => lst,
+            _ => return Err(RuntimeError::from("You incorrect let form")),
+        };
+
+        // the values are worked out before any of the variables can be seen, and are left on the
+        // stack as the variables
+        let mut vars = Vec::with_capacity(bindings.len());
+        for binding in bindings {
+            match binding {
+                SchemeObject::List(lst, _) => {
+                    let binding: Vec<&SchemeObject> = lst.iter().collect();
+                    let slot = self.function().height;
+                    vars.push((Some(self.binding(&binding)?), slot));
+                }
+                _ => return Err(RuntimeError::from("Let bindings should be 2 element lists")),
+            }
+        }
+
+        let n = vars.len() as u32;
+        let function = self.function();
+        function.blocks.push(function.locals.len());
+        function.locals.extend(vars);
+
+        self.sequence(tail[1..].iter().cloned(), tail_pos);
+
+        let function = self.function();
+        let block = function.blocks.pop().unwrap();
+        function.locals.truncate(block);
+        self.emit(Op::EndLet(n));
+        Ok(())
+    }
+
+    /// (call-with-values producer consumer)
+    /// call-with-values is a procedure as well (see stdlib), but calls to it by name are compiled
+    /// here so that calling the consumer is an ordinary call made by the virtual machine
+    fn call_with_values(&mut self, tail: &[&SchemeObject]) -> Result<(), RuntimeError> {
+        let (producer, consumer) = match tail {
+            [producer, consumer] => (*producer, *consumer),
+            _ => return Err(RuntimeError::from("call-with-values expects 2 arguments")),
+        };
+
+        self.expr(consumer);
+        self.expr(producer);
+        self.emit(Op::Call(0));
+        self.emit(Op::CallValues);
+        Ok(())
+    }
+
+    /// (receive formals expr body...)
+    fn receive(&mut self, tail: &[&SchemeObject], tail_pos: bool) -> Result<(), RuntimeError> {
+        if tail.len() < 3 {
+            return Err(RuntimeError::from(
+                "receive expects (receive formals expr body...)",
+            ));
+        }
+
+        self.bind_values(&[(tail[0], tail[1])], &tail[2..], false, tail_pos)
+    }
+
+    /// (let-values ((formals expr)...) body...) or the same with let*-values when `sequential`
+    fn let_values(
+        &mut self,
+        tail: &[&SchemeObject],
+        sequential: bool,
+        tail_pos: bool,
+    ) -> Result<(), RuntimeError> {
+        let usage = "let-values expects ((formals expr)...) followed by a body";
+        let bindings = match tail.first() {
+            Some(SchemeObject::List(lst, _)) if tail.len() >= 2 => lst,
+            _ => return Err(RuntimeError::from(usage)),
+        };
+
+        let mut pairs = Vec::with_capacity(bindings.len());
+        for binding in bindings {
+            match binding {
+                SchemeObject::List(lst, _) if lst.len() == 2 => {
+                    pairs.push((lst.front().unwrap(), lst.back().unwrap()))
+                }
+                _ => return Err(RuntimeError::from(usage)),
+            }
+        }
+
+        self.bind_values(&pairs, &tail[1..], sequential, tail_pos)
+    }
+
+    /// Bind the formals of each pair to the values of its expression while `body` is run, like let
+    /// does with single values. When `sequential` each expression can see the variables before it.
+    fn bind_values(
+        &mut self,
+        bindings: &[(&SchemeObject, &SchemeObject)],
+        body: &[&SchemeObject],
+        sequential: bool,
+        tail_pos: bool,
+    ) -> Result<(), RuntimeError> {
+        let function = self.function();
+        function.blocks.push(function.locals.len());
+
+        // the variables of let-values can't be seen until all of the expressions have been run
+        let mut vars = Vec::new();
+        for &(formals, expr) in bindings {
+            let (names, rest) = formals_names(formals)?;
+            let slot = self.function().height;
+            self.expr(expr);
+            self.emit(Op::Unpack(names.len() as u32, rest.is_some()));
+
+            let names = names.into_iter().chain(rest).enumerate();
+            vars.extend(names.map(|(i, name)| (Some(name), slot + i as u32)));
+            if sequential {
+                self.function().locals.append(&mut vars);
+            }
+        }
+        self.function().locals.append(&mut vars);
+
+        self.sequence(body.iter().cloned(), tail_pos);
+
+        let function = self.function();
+        let block = function.blocks.pop().unwrap();
+        let n = (function.locals.len() - block) as u32;
+        function.locals.truncate(block);
+        self.emit(Op::EndLet(n));
+        Ok(())
+    }
+
+    /// (define-values formals expr)
+    fn define_values(&mut self, tail: &[&SchemeObject]) -> Result<(), RuntimeError> {
+        let (formals, expr) = match tail {
+            [formals, expr] => (*formals, *expr),
+            _ => return Err(RuntimeError::from("define-values expects 2 arguments")),
+        };
+
+        let (names, rest) = formals_names(formals)?;
+        self.expr(expr);
+        self.emit(Op::Unpack(names.len() as u32, rest.is_some()));
+
+        // the last value is on top of the stack
+        let names: Vec<Sym> = names.into_iter().chain(rest).collect();
+        for (i, &name) in names.iter().rev().enumerate() {
+            if i > 0 {
+                self.emit(Op::Pop);
+            }
+            self.define_global(name);
+        }
+        if names.is_empty() {
+            self.expr(&SchemeObject::None);
+        }
+        Ok(())
+    }
+
+    /// (if condition then [else])
+    fn scm_if(&mut self, tail: &[&SchemeObject], tail_pos: bool) -> Result<(), RuntimeError> {
+        if tail.len() < 2 {
+            return Err(RuntimeError::from(
+                "If statement needs to at least specify a condition and something to do on true",
+            ));
+        }
+
+        self.expr(tail[0]);
+        let to_else = self.here();
+        self.emit(Op::JumpUnlessTrue(0));
+
+        let height = self.function().height;
+        self.expr_at(tail[1], tail_pos);
+        let to_end = self.here();
+        self.emit(Op::Jump(0));
+
+        // only one branch is taken
+        self.function().height = height;
+        self.patch(to_else);
+        self.expr_at(
+            tail.get(2).cloned().unwrap_or(&SchemeObject::None),
+            tail_pos,
+        );
+        self.patch(to_end);
+        Ok(())
+    }
+
+    /// (guard (var clause...) body...)
+    /// If the body raises an error, var is bound to the error object and the clauses are tried like
+    /// the clauses of a cond: the first clause whose test isn't #f is executed. `else` always
+    /// matches. If no clause matches, the error is raised again.
+    fn guard(&mut self, tail: &[&SchemeObject]) -> Result<(), RuntimeError> {
+        let mut spec = match tail.first() {
+            Some(SchemeObject::List(lst, _)) => lst.iter(),
+            _ => {
+                return Err(RuntimeError::from(
+                    "guard expects (guard (var clause...) body...)",
+                ))
+            }
+        };
+
+        let var = match spec.next() {
+            Some(SchemeObject::Symbol(name)) => *name,
+            _ => return Err(RuntimeError::from("guard expects a variable name")),
+        };
+
+        let source = SchemeObject::None;
+        let body = self.lambda_code(Vec::new(), source, None, |c| {
+            c.sequence(tail[1..].iter().cloned(), false)
+        });
+        let clauses = spec.map(|clause| self.guard_clause(var, clause)).collect();
+
+        self.code.guards.push(Guard { body, clauses });
+        let i = self.code.guards.len() as u32 - 1;
+        self.emit(Op::Guard(i));
+        Ok(())
+    }
+
+    /// Helper for `guard`
+    fn guard_clause(&mut self, var: Sym, clause: &SchemeObject) -> Clause {
+        let mut forms = match clause {
+            SchemeObject::List(lst, _) if !lst.is_empty() => lst.iter(),
+            // only complain if the clause is needed
+            _ => {
+                let fail = self.lambda_code(vec![var], SchemeObject::None, None, |c| {
+                    let e = RuntimeError::from("guard clauses should be non-empty lists");
+                    c.code.errors.push(e);
+                    c.emit(Op::Fail(0));
+                });
+                return Clause {
+                    test: Some(fail),
+                    body: None,
+                };
+            }
+        };
+
+        let test = match forms.next().unwrap() {
+            SchemeObject::Symbol(s) if *s == "else" => None,
+            form => Some(self.lambda_code(vec![var], SchemeObject::None, None, |c| c.expr(form))),
+        };
+
+        let body = if forms.len() == 0 {
+            None
+        } else {
+            let body = |c: &mut Compiler| c.sequence(forms, false);
+            Some(self.lambda_code(vec![var], SchemeObject::None, None, body))
+        };
+
+        Clause { test, body }
+    }
+
+    /// (delay expr) or (delay-force expr)
+    fn delay(&mut self, tail: &[&SchemeObject], forces: bool) -> Result<(), RuntimeError> {
+        let expr = match tail {
+            [expr] => *expr,
+            _ => return Err(RuntimeError::from("delay expects one expression")),
+        };
+
+        self.delayed(forces, |c| c.expr(expr));
+        Ok(())
+    }
+
+    /// Compile code to push a promise of the code `body` compiles (see `Op::Delay`)
+    fn delayed<F>(&mut self, forces: bool, body: F)
+    where
+        F: FnOnce(&mut Compiler),
+    {
+        let lambda = self.lambda_code(Vec::new(), SchemeObject::None, None, body);
+        self.code.lambdas.push(lambda);
+        let i = self.code.lambdas.len() as u32 - 1;
+        self.emit(Op::Closure(i));
+        self.emit(Op::Delay(forces));
+    }
+
+    /// (stream-cons obj stream)
+    /// A stream is a promise of either the empty list or a stream pair: a list of a promise of
+    /// the first item and the stream of the rest. Neither obj nor stream is evaluated until needed.
+    fn stream_cons(&mut self, tail: &[&SchemeObject]) -> Result<(), RuntimeError> {
+        let (first, rest) = match tail {
+            [first, rest] => (*first, *rest),
+            _ => return Err(RuntimeError::from("stream-cons expects 2 arguments")),
+        };
+
+        self.delayed(false, |c| {
+            c.delayed(false, |c| c.expr(first));
+            c.delayed(true, |c| c.expr(rest));
+            c.emit(Op::List(2));
+        });
+        Ok(())
+    }
+
+    /// (define-record-type <name> (constructor field...) predicate (field accessor [modifier])...)
+    /// The type is made when the form is compiled. The procedures are functions which call the
+    /// built-in functions in record.rs with the type.
+    fn define_record_type(&mut self, tail: &[&SchemeObject]) -> Result<(), RuntimeError> {
+        let usage = "define-record-type expects (define-record-type <name> (constructor field...) \
+                     predicate (field accessor [modifier])...)";
+        let (type_name, constructor, predicate, specs) = match tail {
+            [SchemeObject::Symbol(t), SchemeObject::List(c, _), SchemeObject::Symbol(p), specs @ ..] => {
+                (*t, c, *p, specs)
+            }
+            _ => return Err(RuntimeError::from(usage)),
+        };
+
+        // (field accessor [modifier])
+        let mut fields = Vec::new();
+        let mut procedures = Vec::new();
+        for spec in specs {
+            let spec: Vec<Sym> = match spec {
+                SchemeObject::List(lst, _) => lst
+                    .iter()
+                    .map(|obj| match obj {
+                        SchemeObject::Symbol(s) => Ok(*s),
+                        _ => Err(RuntimeError::from(usage)),
+                    })
+                    .collect::<Result<_, _>>()?,
+                _ => return Err(RuntimeError::from(usage)),
+            };
+            if spec.len() != 2 && spec.len() != 3 {
+                return Err(RuntimeError::from(usage));
+            }
+            if fields.contains(&spec[0]) {
+                return Err(RuntimeError::from(format!(
+                    "Field {} of {} is declared more than once",
+                    spec[0], type_name
+                )));
+            }
+            procedures.push((fields.len(), spec[1], spec.get(2).cloned()));
+            fields.push(spec[0]);
+        }
+
+        let mut constructor = constructor.iter();
+        let constructor_name = match constructor.next() {
+            Some(SchemeObject::Symbol(name)) => *name,
+            _ => return Err(RuntimeError::from(usage)),
+        };
+        let mut args = Vec::new();
+        let mut positions = Vec::new();
+        for arg in constructor {
+            let position = match arg {
+                SchemeObject::Symbol(name) => fields.iter().position(|f| f == name),
+                _ => None,
+            };
+            match position {
+                Some(i) if positions.contains(&i) => {
+                    return Err(RuntimeError::from(format!(
+                        "Field {} is an argument of {} more than once",
+                        fields[i], constructor_name
+                    )))
+                }
+                Some(i) => {
+                    args.push(fields[i]);
+                    positions.push(i);
+                }
+                None => {
+                    return Err(RuntimeError::from(format!(
+                        "{:?} isn't a field of {}",
+                        arg, type_name
+                    )))
+                }
+            }
+        }
+
+        let record_type = Rc::new(RecordType::new(&type_name.name(), fields, positions));
+        let type_obj = SchemeObject::RecordType(record_type);
+        let c = self.constant(Rc::new(type_obj.clone()));
+        self.emit(Op::Const(c));
+        self.define_global(type_name);
+
+        let consts = vec![type_obj.clone()];
+        self.record_procedure(constructor_name, record::constructor(), &consts, args);
+        self.record_procedure(
+            predicate,
+            record::predicate(),
+            &consts,
+            vec![Sym::from("obj")],
+        );
+        for (i, accessor, modifier) in procedures {
+            let consts = vec![type_obj.clone(), SchemeObject::Integer(i as i64)];
+            let record = Sym::from("record");
+            self.record_procedure(accessor, record::accessor(), &consts, vec![record]);
+            if let Some(modifier) = modifier {
+                let args = vec![record, Sym::from("value")];
+                self.record_procedure(modifier, record::modifier(), &consts, args);
+            }
+        }
+        Ok(())
+    }
+
+    /// Helper for `define_record_type`: define `name` as a function taking `args` which calls
+    /// `builtin` with `consts` followed by the arguments. Leaves the stack as it was.
+    fn record_procedure(
+        &mut self,
+        name: Sym,
+        builtin: SchemeObject,
+        consts: &[SchemeObject],
+        args: Vec<Sym>,
+    ) {
+        self.emit(Op::Pop);
+
+        // the body as it would be written, for printing
+        let mut source = LinkedList::new();
+        source.push_back(builtin);
+        source.extend(consts.iter().cloned());
+        source.extend(args.iter().map(|&arg| SchemeObject::Symbol(arg)));
+
+        let body = source.clone();
+        let lambda = self.lambda_code(args, SchemeObject::from(source), None, |c| {
+            for obj in body.iter() {
+                match obj {
+                    SchemeObject::Symbol(arg) => c.variable(*arg),
+                    obj => {
+                        let i = c.constant(Rc::new(obj.clone()));
+                        c.emit(Op::Const(i));
+                    }
+                }
+            }
+            c.emit(Op::Call(body.len() as u32 - 1));
+        });
+        self.code.lambdas.push(lambda);
+        let i = self.code.lambdas.len() as u32 - 1;
+        self.emit(Op::Closure(i));
+        self.emit(Op::Name(name));
+        self.define_global(name);
+    }
+
+    /// import and define-library are run by library.rs
+    fn library_form<F>(&mut self, tail: &[&SchemeObject], op: F) -> Result<(), RuntimeError>
+    where
+        F: FnOnce(u32) -> Op,
+    {
+        let tail: LinkedList<SchemeObject> = tail.iter().map(|&obj| obj.clone()).collect();
+        let c = self.constant(Rc::new(SchemeObject::List(tail, None)));
+        self.emit(op(c));
+        Ok(())
+    }
+}
+
+/// Find a variable as it is seen from `functions[index]`, capturing it from enclosing functions
+/// if need be. None if it isn't a local variable of any of them.
+fn resolve(functions: &mut [Function], index: usize, name: Sym) -> Option<Capture> {
+    if let Some(slot) = functions[index].local(name) {
+        return Some(Capture::Local(slot));
+    }
+    if let Some(i) = functions[index].captured(name) {
+        return Some(Capture::Free(i));
+    }
+
+    // the top level code can't capture anything
+    if index == 0 {
+        return None;
+    }
+    let from = resolve(functions, index - 1, name)?;
+
+    let function = &mut functions[index];
+    function.free.push((name, from));
+    Some(Capture::Free(function.free.len() as u32 - 1))
+}
+
+/// The variables named by the formals of let-values and friends: (name...) for exactly that many
+/// values, or a single name for a list of all of them
+fn formals_names(formals: &SchemeObject) -> Result<(Vec<Sym>, Option<Sym>), RuntimeError> {
+    match formals {
+        SchemeObject::Symbol(rest) => Ok((Vec::new(), Some(*rest))),
+        SchemeObject::List(lst, _) => {
+            let names = lst.iter().map(|obj| match obj {
+                SchemeObject::Symbol(name) => Ok(*name),
+                obj => Err(RuntimeError::from(format!(
+                    "{:?} can't be the name of a variable",
+                    obj
+                ))),
+            });
+            Ok((names.collect::<Result<_, _>>()?, None))
+        }
+        obj => Err(RuntimeError::from(format!(
+            "Expected a list of variable names, got {:?}",
+            obj
+        ))),
+    }
+}
+
+/// Split what follows a function's argument names into its docstring (if any) and its body
+/// Like in Emacs Lisp, a string followed by the body is documentation
+fn doc_and_body<'a>(
+    forms: &[&'a SchemeObject],
+) -> Result<(Option<String>, &'a SchemeObject), RuntimeError> {
+    match forms {
+        [body] => Ok((None, body)),
+        [SchemeObject::String(doc), body] => Ok((Some(doc.clone()), body)),
+        _ => Err(RuntimeError::from(
+            "Expected a function body (optionally after a docstring)",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast;
+
+    fn compile_str(program: &str) -> Code {
+        compile(&ast::parse_tokens(program.chars()).unwrap()[0])
+    }
+
+    #[test]
+    fn variables() {
+        let code = compile_str("(lambda (a b) (let ((c a)) (f b c)))");
+        assert_eq!(code.ops, vec![Op::Closure(0), Op::Return]);
+
+        // the arguments are in slots 0 and 1 and c goes in the next slot
+        let body = &code.lambdas[0].code;
+        assert_eq!(
+            body.ops,
+            vec![
+                Op::Local(0),
+                Op::Name(Sym::from("c")),
+                Op::Global(Sym::from("f")),
+                Op::Local(1),
+                Op::Local(2),
+                Op::TailCall(2),
+                Op::EndLet(1),
+                Op::Return
+            ]
+        );
+    }
+
+    #[test]
+    fn captures() {
+        let code = compile_str("(lambda (a b) (lambda () (lambda () (f b))))");
+
+        // only b is captured, through the middle function
+        let middle = &code.lambdas[0].code.lambdas[0];
+        assert_eq!(middle.captures, vec![Capture::Local(1)]);
+        let inner = &middle.code.lambdas[0];
+        assert_eq!(inner.captures, vec![Capture::Free(0)]);
+        assert_eq!(inner.code.ops[1], Op::Free(0));
+    }
+
+    #[test]
+    fn tail_calls() {
+        let code = compile_str("(lambda (x) (if (f x) (g (h x)) (let ((y x)) (h y) (g y))))");
+        let calls: Vec<Op> = code.lambdas[0]
+            .code
+            .ops
+            .iter()
+            .cloned()
+            .filter(|op| matches!(op, Op::Call(_) | Op::TailCall(_)))
+            .collect();
+
+        // only the calls to g are the last thing the function does
+        assert_eq!(
+            calls,
+            vec![
+                Op::Call(1),
+                Op::Call(1),
+                Op::TailCall(1),
+                Op::Call(1),
+                Op::TailCall(1)
+            ]
+        );
+
+        // top level code isn't a function
+        assert_eq!(
+            compile_str("(f)").ops,
+            vec![Op::Global(Sym::from("f")), Op::Call(0), Op::Return]
+        );
+    }
+
+    #[test]
+    fn defined_names_are_global() {
+        let code = compile_str("(lambda () (let ((a 1)) (define a 2) a))");
+        let body = &code.lambdas[0].code;
+
+        assert_eq!(body.ops[body.ops.len() - 3], Op::Global(Sym::from("a")));
+    }
+
+    #[test]
+    fn errors_when_run() {
+        let code = compile_str("(if #t \"fine\" (let))");
+
+        assert!(code.ops.contains(&Op::Fail(0)));
+        assert_eq!(
+            code.errors,
+            vec![RuntimeError::from("let should have at least 2 arguments")]
+        );
+    }
+}
@@ -1,6 +1,12 @@
 //! Name lookup
 
+use super::gc;
+use super::interrupt::Interrupt;
+use super::library::Libraries;
+use super::limits::Limits;
+use super::port::CurrentPorts;
 use super::scm_obj::SchemeObject;
+use super::symbol::Sym;
 use std::cell::RefCell;
 use std::collections::HashMap;
 use std::rc::Rc;
@@ -15,45 +21,73 @@ pub struct Environment {
     /// Points to the next environment in the name resolution order
     parent: Option<PackedEnv>,
     /// Mapping of variable names to objects
-    names: HashMap<String, Rc<SchemeObject>>,
+    names: HashMap<Sym, Rc<SchemeObject>>,
+    /// The libraries which can be imported (only set in global environments)
+    libraries: Option<Rc<RefCell<Libraries>>>,
+    /// Limits on the code run here (only set in global environments)
+    limits: Option<Limits>,
+    /// Stops the code run here when triggered (only set in global environments)
+    interrupt: Option<Interrupt>,
+    /// The current ports for code run here instead of the thread's (only set in global
+    /// environments)
+    ports: Option<Rc<RefCell<CurrentPorts>>>,
 }
 
 impl Environment {
     /// Instance new Environment
     pub fn new(parent: Option<Rc<RefCell<Self>>>) -> PackedEnv {
-        let env = Self {
+        let env = Rc::new(RefCell::new(Self {
             parent,
-            names: HashMap::new(),
-        };
+            ..Self::empty()
+        }));
+
+        gc::track(&env);
+        env
+    }
 
-        Rc::new(RefCell::new(env))
+    /// An environment with nothing in it (not packed or tracked by the collector)
+    pub(crate) fn empty() -> Self {
+        Self {
+            parent: None,
+            names: HashMap::new(),
+            libraries: None,
+            limits: None,
+            interrupt: None,
+            ports: None,
+        }
     }
 
     /// Look up variable in environment
-    pub fn lookup(&self, name: &str) -> Option<Rc<SchemeObject>> {
-        match self.names.get(name) {
+    pub fn lookup<S: Into<Sym>>(&self, name: S) -> Option<Rc<SchemeObject>> {
+        self.lookup_sym(name.into())
+    }
+
+    /// `lookup` for a name which is already interned
+    fn lookup_sym(&self, name: Sym) -> Option<Rc<SchemeObject>> {
+        match self.names.get(&name) {
             Some(entry) => Some(entry.clone()), // just clones the Rc - no copy
             None => match &self.parent {
-                Some(p) => p.borrow().lookup(name),
+                Some(p) => p.borrow().lookup_sym(name),
                 None => None,
             },
         }
     }
 
     /// Set variable in this environment
-    pub fn set(&mut self, name: String, val: Rc<SchemeObject>) {
-        self.names.insert(name, val);
+    pub fn set<S: Into<Sym>>(&mut self, name: S, val: Rc<SchemeObject>) {
+        self.names.insert(name.into(), val);
     }
 
     /// Set variable in the global environment and change the local environment to avoid shadowing
-    pub fn set_global(&mut self, name: String, val: Rc<SchemeObject>) {
+    pub fn set_global<S: Into<Sym>>(&mut self, name: S, val: Rc<SchemeObject>) {
+        let name = name.into();
         // we don't want any local variables to shadow the new definition
-        self.names.remove(&name.clone());
+        self.names.remove(&name);
         self.set_global_priv(name, val);
     }
 
     /// Only do the traversal to the global environment. Don't touch the local environment
-    fn set_global_priv(&mut self, name: String, val: Rc<SchemeObject>) {
+    fn set_global_priv(&mut self, name: Sym, val: Rc<SchemeObject>) {
         match self.parent {
             None => self.set(name, val), // if self has no parent then it is global
             Some(ref mut p) => {
@@ -75,6 +109,97 @@ impl Environment {
         self.parent = parent;
     }
 
+    /// The variables set in this environment (not in its parents)
+    pub fn local_bindings(&self) -> impl Iterator<Item = (Sym, &Rc<SchemeObject>)> {
+        self.names.iter().map(|(&name, val)| (name, val))
+    }
+
+    /// Every binding visible from this environment, looking through all of the parent
+    /// environments, sorted by name. Where a name is bound in more than one environment only the
+    /// innermost binding is included because that is the one which would be looked up.
+    pub fn all_bindings(&self) -> Vec<(String, Rc<SchemeObject>)> {
+        let mut bindings = self.names.clone();
+
+        let mut frame = self.get_parent();
+        while let Some(env) = frame {
+            for (name, val) in &env.borrow().names {
+                bindings.entry(*name).or_insert_with(|| val.clone());
+            }
+            frame = env.borrow().get_parent();
+        }
+
+        let mut bindings: Vec<(String, Rc<SchemeObject>)> = bindings
+            .into_iter()
+            .map(|(name, val)| (name.to_string(), val))
+            .collect();
+        bindings.sort_by(|a, b| a.0.cmp(&b.0));
+        bindings
+    }
+
+    /// The libraries which can be imported here (found in the global environment)
+    pub fn libraries(&self) -> Option<Rc<RefCell<Libraries>>> {
+        match self.libraries {
+            Some(ref l) => Some(l.clone()),
+            None => match self.parent {
+                Some(ref p) => p.borrow().libraries(),
+                None => None,
+            },
+        }
+    }
+
+    /// The libraries set in this environment (not in its parents)
+    pub(crate) fn own_libraries(&self) -> Option<Rc<RefCell<Libraries>>> {
+        self.libraries.clone()
+    }
+
+    /// Set the libraries which can be imported into this environment and its children
+    pub fn set_libraries(&mut self, libraries: Rc<RefCell<Libraries>>) {
+        self.libraries = Some(libraries);
+    }
+
+    /// The limits on code run in this environment (found in the global environment)
+    pub fn limits(&self) -> Limits {
+        match (self.limits, &self.parent) {
+            (Some(limits), _) => limits,
+            (None, Some(p)) => p.borrow().limits(),
+            (None, None) => Limits::default(),
+        }
+    }
+
+    /// Set the limits on code run in this environment and its children
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = Some(limits);
+    }
+
+    /// The handle which stops code run in this environment (found in the global environment)
+    pub fn interrupt(&self) -> Option<Interrupt> {
+        match (&self.interrupt, &self.parent) {
+            (Some(interrupt), _) => Some(interrupt.clone()),
+            (None, Some(p)) => p.borrow().interrupt(),
+            (None, None) => None,
+        }
+    }
+
+    /// Stop code run in this environment and its children when `interrupt` is triggered
+    pub fn set_interrupt(&mut self, interrupt: Interrupt) {
+        self.interrupt = Some(interrupt);
+    }
+
+    /// The current ports for code run in this environment (found in the global environment)
+    /// `None` means the thread's current ports are used.
+    pub fn ports(&self) -> Option<Rc<RefCell<CurrentPorts>>> {
+        match (&self.ports, &self.parent) {
+            (Some(ports), _) => Some(ports.clone()),
+            (None, Some(p)) => p.borrow().ports(),
+            (None, None) => None,
+        }
+    }
+
+    /// Use `ports` as the current ports for code run in this environment and its children
+    pub fn set_ports(&mut self, ports: CurrentPorts) {
+        self.ports = Some(Rc::new(RefCell::new(ports)));
+    }
+
     /// shrink
     pub fn shrink(&mut self) {
         self.names.shrink_to_fit()
@@ -134,4 +259,33 @@ mod tests {
         assert!(g_env.borrow().lookup(&name).is_none());
         assert_eq!(g_env.borrow().lookup(&g_name), exp_res);
     }
+
+    #[test]
+    fn all_bindings() {
+        let g_env = Environment::new(None);
+        let env = Environment::new(Some(Environment::new(Some(g_env.clone()))));
+
+        g_env
+            .borrow_mut()
+            .set(String::from("b"), Rc::new(SchemeObject::from("global")));
+        g_env
+            .borrow_mut()
+            .set(String::from("a"), Rc::new(SchemeObject::from("global")));
+        env.borrow_mut()
+            .set(String::from("b"), Rc::new(SchemeObject::from("local")));
+
+        let names: Vec<(String, String)> = env
+            .borrow()
+            .all_bindings()
+            .into_iter()
+            .map(|(name, val)| (name, val.to_string()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                (String::from("a"), String::from("global")),
+                (String::from("b"), String::from("local")),
+            ]
+        );
+    }
 }
@@ -1,268 +1,51 @@
 //! Implements `SchemeObject::exec`
 
+use super::compile::compile;
 use super::env::*;
 use super::scm_obj::SchemeObject;
+use super::vm;
 use super::RuntimeError;
-use stdlib::{get_none, get_true};
 
-use std::collections::LinkedList;
 use std::rc::Rc;
 
+/// The special forms handled by the compiler and how they are used
+pub const SPECIAL_FORMS: [(&str, &str); 15] = [
+    (
+        "define",
+        "(define name value) or (define (name arg...) body)",
+    ),
+    ("let", "(let ((name value)...) body...)"),
+    ("lambda", "(lambda (arg...) body)"),
+    ("if", "(if condition then [else])"),
+    (
+        "guard",
+        "(guard (var (test result...)... [(else result...)]) body...)",
+    ),
+    ("receive", "(receive formals expr body...)"),
+    ("let-values", "(let-values ((formals expr)...) body...)"),
+    ("let*-values", "(let*-values ((formals expr)...) body...)"),
+    ("define-values", "(define-values formals expr)"),
+    ("delay", "(delay expr)"),
+    ("delay-force", "(delay-force promise-expr)"),
+    ("stream-cons", "(stream-cons obj stream)"),
+    (
+        "define-record-type",
+        "(define-record-type <name> (constructor field...) predicate (field accessor [modifier])...)",
+    ),
+    ("import", "(import import-set...)"),
+    (
+        "define-library",
+        "(define-library (name...) declaration...)",
+    ),
+];
+
 impl SchemeObject {
     /// If it is a List, execute it and return the result
     /// If it is a symbol, look it up and return the result
     /// Otherwise return as-is
+    /// The code is compiled to bytecode and run by the virtual machine.
     pub fn exec(&self, env: &PackedEnv) -> Result<Rc<Self>, RuntimeError> {
-        match self {
-            // execute code list
-            SchemeObject::List(lst) => exec_codelist(&lst, env),
-            // look up the symbol name in the environment
-            SchemeObject::Symbol(s) => env
-                .borrow_mut()
-                .lookup(&s)
-                .ok_or_else(|| RuntimeError::NameLookup(s.clone())),
-            // return another reference to None
-            SchemeObject::None => Ok(get_none()),
-            // return as-is
-            x => Ok(Rc::new(x.clone())),
-        }
-    }
-}
-
-/// helper function for `SchemeObject::exec`
-fn exec_codelist(
-    lst: &LinkedList<SchemeObject>,
-    env: &PackedEnv,
-) -> Result<Rc<SchemeObject>, RuntimeError> {
-    // the head of the code list is the function to execute
-    let scm_obj = match lst.front() {
-        Some(c) => c,
-        None => return Err(RuntimeError::from("Executing empty codelist")),
-    };
-
-    // get list tail - TODO immutable single linked list to avoid clone
-    let tail = if lst.len() > 1 {
-        lst.clone().split_off(1)
-    } else {
-        LinkedList::new()
-    };
-
-    // how do we call the head of the code list?
-    match scm_obj {
-        SchemeObject::Symbol(cmd) => {
-            // is this a normal function call or a special form?
-            match cmd.as_str() {
-                "define" => define(&tail, env),
-                "let" => scm_let(&tail, env),
-                "lambda" => lambda(&tail, env),
-                "if" => scm_if(&tail, env),
-                _ => function_call(scm_obj, &tail, env),
-            }
-        }
-        // We need to evaluate the code list and then exec whatever it returns
-        SchemeObject::List(_) => scm_obj.exec(env)?.exec_args(&tail, env),
-        // We can't call that type
-        _ => Err(RuntimeError::from(format!(
-            "{:?} found; function name expected",
-            scm_obj
-        ))),
-    }
-}
-
-/// Reads in a scheme function's arguments and constructs the `SchemeObject`
-fn read_scm_fn(
-    name_iter: &mut Iterator<Item = &SchemeObject>,
-    body: SchemeObject,
-    env: &PackedEnv,
-) -> Result<SchemeObject, RuntimeError> {
-    // read in argument names
-    let mut arg_names = Vec::new();
-    for scm_obj in name_iter {
-        if let SchemeObject::Symbol(arg_name) = scm_obj {
-            arg_names.push(arg_name.clone());
-        } else {
-            return Err(RuntimeError::from("You can't call a variable that"));
-        }
-    }
-
-    Ok(SchemeObject::SFunc(Box::new(body), arg_names, env.clone()))
-}
-
-/// Helper function for `exec_codelist`
-/// Handles executing lambda expressions
-fn lambda(
-    tail: &LinkedList<SchemeObject>,
-    env: &PackedEnv,
-) -> Result<Rc<SchemeObject>, RuntimeError> {
-    // two arguments: argument names and the function body
-    // TODO additional arguments are more code statements for the fn body like in a let?
-    if tail.len() != 2 {
-        return Err(RuntimeError::from("Expected 2 arguments to lambda"));
-    }
-
-    let mut tail_iter = tail.iter();
-
-    // first argument is the argument names
-    let mut arg_names = {
-        if let SchemeObject::List(lst) = tail_iter.next().unwrap() {
-            lst.iter()
-        } else {
-            return Err(RuntimeError::from(
-                "Expected the first argument to lambda to be a list",
-            ));
-        }
-    };
-
-    // second argument is the function body
-    let body = tail_iter.next().unwrap().clone();
-
-    // construct the SchemeObject
-    Ok(Rc::new(read_scm_fn(&mut arg_names, body, env)?))
-}
-
-/// prepares to apply bindings (symbol val) for define and let
-/// returns (name, value)
-fn apply_biding(
-    tail: &LinkedList<SchemeObject>,
-    env: &PackedEnv,
-) -> Result<(String, Rc<SchemeObject>), RuntimeError> {
-    // check the number of arguments
-    if tail.len() == 2 {
-        let mut tail_iter = tail.iter(); // todo: should this be a list of Rc<>
-
-        // first item in the binding list
-        match tail_iter.next().unwrap() {
-            // ordinary variable binding
-            SchemeObject::Symbol(name) => {
-                // return the name and the evaluated value
-                let scm_obj = tail_iter.next().unwrap().clone();
-                Ok((name.clone(), scm_obj.exec(env)?))
-            }
-            // function binding
-            SchemeObject::List(lst) => {
-                let mut lst_iter = lst.iter();
-
-                // first list item is the function name
-                let name = match lst_iter.next() {
-                    Some(SchemeObject::Symbol(name)) => name,
-                    Some(_) => return Err(RuntimeError::from("You can't name a function that")),
-                    None => panic!("Empty assignment list"),
-                };
-
-                // get the function body
-                let body = tail_iter.next().unwrap().clone();
-
-                // get the function argument names and construct the `SchemeObject`
-                let rt_obj = read_scm_fn(&mut lst_iter, body, env)?;
-                Ok((name.clone(), Rc::new(rt_obj)))
-            }
-            // neither a function binding nor a symbol
-            _ => Err(RuntimeError::from("You can't name a variable that")),
-        }
-    } else {
-        Err(RuntimeError::from("Expected 2 arguments"))
-    }
-}
-
-/// helper function for `exec_codelist`
-/// Executes a define statement
-fn define(
-    tail: &LinkedList<SchemeObject>,
-    env: &PackedEnv,
-) -> Result<Rc<SchemeObject>, RuntimeError> {
-    let (name, val) = apply_biding(tail, env)?;
-    env.borrow_mut().set_global(name, val);
-
-    Ok(get_none())
-}
-
-/// helper function for `exec_codelist`
-/// Executes a let statement
-fn scm_let(
-    tail: &LinkedList<SchemeObject>,
-    env: &PackedEnv,
-) -> Result<Rc<SchemeObject>, RuntimeError> {
-    let mut tail_iter = tail.iter();
-
-    // check the number of arguments
-    if tail.len() >= 2 {
-        // this should be the list of lists of variables and mappings
-        if let SchemeObject::List(lst) = tail_iter.next().unwrap() {
-            let mut local_env = Environment::new(Some(env.clone()));
-
-            // set all bindings in local_env
-            for binding in lst {
-                if let SchemeObject::List(lst) = binding {
-                    let (name, val) = apply_biding(lst, env)?;
-                    env.borrow_mut().set(name, val);
-                } else {
-                    // binding wasn't a list
-                    return Err(RuntimeError::from("Let bindings should be 2 element lists"));
-                }
-            }
-
-            // shrink local_env now we have finished putting it together
-            local_env.borrow_mut().shrink();
-
-            // execute the code arguments
-            let mut last_ret = Err(RuntimeError::from("No let result"));
-
-            for code in tail_iter {
-                last_ret = code.exec(&local_env);
-
-                // return early if we encounter an error
-                if last_ret.is_err() {
-                    return last_ret;
-                }
-            }
-
-            // return the result of the last operation
-            last_ret
-        } else {
-            // the first argument didn't look right
-            Err(RuntimeError::from("You incorrect let form"))
-        }
-    } else {
-        // incorrect number of arguments
-        Err(RuntimeError::from("let should have at least 2 arguments"))
-    }
-}
-
-/// helper function for `exec_codelist`
-fn function_call(
-    scm_obj: &SchemeObject,
-    tail: &LinkedList<SchemeObject>,
-    env: &PackedEnv,
-) -> Result<Rc<SchemeObject>, RuntimeError> {
-    // look up the function
-    let runtime_obj = scm_obj.exec(env)?;
-
-    // execute the function
-    runtime_obj.exec_args(&tail, env)
-}
-
-fn scm_if(
-    tail: &LinkedList<SchemeObject>,
-    env: &PackedEnv,
-) -> Result<Rc<SchemeObject>, RuntimeError> {
-    if tail.len() < 2 {
-        return Err(RuntimeError::from(
-            "If statement needs to at least specify a condition and something to do on true",
-        ));
-    }
-
-    let mut iter = tail.iter();
-
-    // evaluate condition
-    let cond = iter.next().unwrap().exec(env)?;
-
-    let true_branch = iter.next().unwrap();
-
-    if cond == get_true() {
-        true_branch.exec(env)
-    } else {
-        iter.next()
-            .map_or_else(|| Ok(get_none()), |so| so.exec(env))
+        vm::run(&Rc::new(compile(self)), env)
     }
 }
 
@@ -270,7 +53,10 @@ fn scm_if(
 mod test {
     use ast;
     use data::env::*;
+    use data::limits::{Limit, Limits};
     use data::*;
+    use span::{Position, Span};
+    use stdlib::get_std_env;
 
     use std::collections::LinkedList;
     use std::ops::Deref;
@@ -301,7 +87,10 @@ mod test {
     }
 
     /// implementation of string concatenation for use in tests
-    fn cat(args: &LinkedList<Rc<SchemeObject>>, _env: &PackedEnv) -> Rc<SchemeObject> {
+    fn cat(
+        args: &LinkedList<Rc<SchemeObject>>,
+        _env: &PackedEnv,
+    ) -> Result<Rc<SchemeObject>, RuntimeError> {
         let mut out = String::new();
 
         for arg in args {
@@ -312,14 +101,19 @@ mod test {
             }
         }
 
-        Rc::new(SchemeObject::from(out))
+        Ok(Rc::new(SchemeObject::from(out)))
     }
 
     fn get_test_env() -> PackedEnv {
         let env = Environment::new(None);
         env.borrow_mut().set(
             String::from("cat"),
-            Rc::new(SchemeObject::RFunc(String::from("cat"), cat)),
+            Rc::new(SchemeObject::RFunc(
+                String::from("cat"),
+                cat,
+                Arity::any(),
+                None,
+            )),
         );
         env.borrow_mut()
             .set(String::from("space"), Rc::new(SchemeObject::from(" ")));
@@ -509,4 +303,132 @@ mod test {
         let expected = SchemeObject::from("hi");
         exec_program(program, vec![expected])
     }
+
+    #[test]
+    fn error_location() {
+        let env = get_test_env();
+        let program = "(cat \"a\"\n     (cat nope))";
+        let code = ast::parse_tokens(program.chars()).unwrap();
+
+        let err = code[0].exec(&env).unwrap_err();
+        let start = Position { line: 2, col: 6 };
+        let end = Position { line: 2, col: 15 };
+
+        assert_eq!(err.span(), Some(Span::new(start, end)));
+        assert_eq!(err.root(), &RuntimeError::NameLookup(String::from("nope")));
+    }
+
+    #[test]
+    fn guard() {
+        let program = "(guard (e (#f \"no\") (else e)) (cat \"a\") (cat nope))
+                       (guard (e (#t \"caught\")) \"fine\")
+                       (guard (e (#t \"caught\")) (cat nope))";
+        let env = get_test_env();
+        let code = ast::parse_tokens(program.chars()).unwrap();
+
+        match *code[0].exec(&env).unwrap() {
+            SchemeObject::Condition(ref e) => {
+                assert_eq!(e.root(), &RuntimeError::NameLookup(String::from("nope")))
+            }
+            ref o => panic!("Expected a condition, got {:?}", o),
+        }
+        assert_eq!(*code[1].exec(&env).unwrap(), SchemeObject::from("fine"));
+        assert_eq!(*code[2].exec(&env).unwrap(), SchemeObject::from("caught"));
+    }
+
+    #[test]
+    fn guard_reraise() {
+        let env = get_test_env();
+        let code = ast::parse_tokens("(guard (e (#f 1)) (cat nope))".chars()).unwrap();
+        let err = code[0].exec(&env).unwrap_err();
+
+        assert_eq!(err.root(), &RuntimeError::NameLookup(String::from("nope")));
+    }
+
+    fn eval_std(env: &PackedEnv, program: &str) -> Result<Rc<SchemeObject>, RuntimeError> {
+        let mut res = Ok(Rc::new(SchemeObject::None));
+        for code in ast::ObjectIterator::from(program.chars()) {
+            res = code.unwrap().exec(env);
+        }
+        res
+    }
+
+    #[test]
+    fn multiple_values() {
+        let env = get_std_env();
+        let check = |program: &str, expected: &str| {
+            let res = eval_std(&env, program).unwrap();
+            assert_eq!(format!("{:?}", res), expected, "{}", program);
+        };
+
+        check("(call-with-values (lambda () (values 1 2)) list)", "(1 2)");
+        check("(call-with-values (lambda () 1) list)", "(1)");
+        check(
+            "(apply call-with-values (list (lambda () (values)) list))",
+            "()",
+        );
+        check("(receive (a b) (values 1 2) (list b a))", "(2 1)");
+        check("(receive all (values 1 2) all)", "(1 2)");
+        check(
+            "(let ((a 1)) (let-values (((a b) (values 2 a)) ((c) (values a))) (list a b c)))",
+            "(2 1 1)",
+        );
+        check(
+            "(let*-values (((a b) (values 1 2)) ((c) (values a))) (list a b c))",
+            "(1 2 1)",
+        );
+        check("(define-values (x y) (values 1 2)) (list x y)", "(1 2)");
+        // not a list
+        check("(values 1 \"a\")", "1 \"a\"");
+        check("(values 1)", "1");
+
+        let err = eval_std(&env, "(receive (a b) (values 1) a)").unwrap_err();
+        assert_eq!(err.root(), &RuntimeError::from("Expected 2 values, got 1"));
+    }
+
+    #[test]
+    fn values_where_one_value_is_expected() {
+        let env = get_std_env();
+        let check = |program: &str, expected: &str| {
+            let err = eval_std(&env, program).unwrap_err();
+            assert_eq!(err.root(), &RuntimeError::from(expected), "{}", program);
+        };
+
+        check(
+            "(equal? (values 1 2) (list 1 2))",
+            "Expected one value as an argument, got 2",
+        );
+        check(
+            "(define (f x) x) (f (values))",
+            "Expected one value as an argument, got 0",
+        );
+        check(
+            "(define x (values 1 2))",
+            "Expected one value to define, got 2",
+        );
+        check(
+            "(if (values #t #t) 1 2)",
+            "Expected one value as the test of if, got 2",
+        );
+        check(
+            "(list 1 (values 2 3))",
+            "Expected one value as an argument, got 2",
+        );
+    }
+
+    #[test]
+    fn consumer_called_by_vm() {
+        let env = get_std_env();
+        env.borrow_mut().set_limits(Limits {
+            steps: Some(200_000),
+            ..Limits::default()
+        });
+
+        // each call of the consumer is a call by the virtual machine rather than a rust call
+        let program = "(define (loop l) (call-with-values (lambda () (values l l)) loop2))
+                       (define (loop2 a b) (loop a))
+                       (loop 1)";
+        let err = eval_std(&env, program).unwrap_err();
+        assert_eq!(err.root(), &RuntimeError::Limit(Limit::Steps));
+    }
 }
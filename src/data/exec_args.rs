@@ -1,8 +1,9 @@
-//! Implements `exec_args` for `SchemeObject`
+//! Implements `exec_args` and `apply` for `SchemeObject`
 
 use data::env::*;
 use data::scm_obj::SchemeObject;
-use data::RuntimeError;
+use data::vm;
+use data::{Frame, RuntimeError};
 
 use std::collections::LinkedList;
 use std::rc::Rc;
@@ -17,17 +18,48 @@ impl SchemeObject {
         use self::SchemeObject::*;
 
         match self {
-            RFunc(_, ref f) => {
+            RFunc(_, _, _, _) | SFunc(_, _, _) => {
                 let evaled_args = eval_args(args, env)?;
-                // call the function
-                Ok(f(&evaled_args, env))
-            }
-            SFunc(code_lst, arg_names, local_env) => {
-                exec_sfunc(code_lst, arg_names, args, local_env)
+                self.apply_list(&evaled_args, env)
             }
             o => o.exec(env),
         }
     }
+
+    /// Calls a function with arguments which have already been evaluated
+    /// This is how built-in functions call scheme functions they were given as arguments.
+    /// `env` is only passed on to built-in functions: scheme functions run in their closure
+    /// environment, on a virtual machine of their own, so no extra frames are added between the
+    /// caller and the callee. Errors are returned to the caller with a backtrace
+    /// frame for this call added.
+    pub fn apply(&self, args: &[Rc<Self>], env: &PackedEnv) -> Result<Rc<Self>, RuntimeError> {
+        self.apply_list(&args.iter().cloned().collect(), env)
+    }
+
+    /// `apply` for arguments which are already in a `LinkedList`
+    pub(crate) fn apply_list(
+        &self,
+        args: &LinkedList<Rc<Self>>,
+        env: &PackedEnv,
+    ) -> Result<Rc<Self>, RuntimeError> {
+        use self::SchemeObject::*;
+
+        match self {
+            RFunc(name, ref f, arity, _) => if !arity.accepts(args.len()) {
+                Err(RuntimeError::from(format!(
+                    "{} expects {} arguments, got {}",
+                    name,
+                    arity,
+                    args.len()
+                )))
+            } else {
+                f(args, env)
+            }
+            .map_err(|e| e.in_frame(Frame::new(name, args))),
+            SFunc(_, _, _) => vm::call(&Rc::new(self.clone()), args),
+            o => Err(RuntimeError::from(format!("{:?} is not a function", o))),
+        }
+    }
 }
 
 /// evaluates function arguments
@@ -45,33 +77,114 @@ fn eval_args(
     Ok(ret)
 }
 
-/// Helper function for `SchemeObject::exec_args`
-/// Evaluates scheme functions (`SchemeObject::SFunc`)
-fn exec_sfunc(
-    code_list: &SchemeObject,
-    arg_names: &[String],
-    func_args: &LinkedList<SchemeObject>,
-    g_env: &PackedEnv,
-) -> Result<Rc<SchemeObject>, RuntimeError> {
-    // did we get the correct number of arguments
-    // TODO variable number of arguments
-    if func_args.len() != arg_names.len() {
-        return Err(RuntimeError::from(format!(
-            "Expected {} arguments, got {}",
-            arg_names.len(),
-            func_args.len()
-        )));
+#[cfg(test)]
+mod tests {
+    use ast;
+    use data::env::*;
+    use data::*;
+    use stdlib::get_std_env;
+
+    use std::collections::LinkedList;
+    use std::rc::Rc;
+
+    fn eval(program: &str, env: &PackedEnv) -> Result<Rc<SchemeObject>, RuntimeError> {
+        let mut res = Ok(Rc::new(SchemeObject::None));
+        for code in ast::ObjectIterator::from(program.chars()) {
+            res = code.unwrap().exec(env);
+        }
+        res
+    }
+
+    fn fail(
+        _args: &LinkedList<Rc<SchemeObject>>,
+        _env: &PackedEnv,
+    ) -> Result<Rc<SchemeObject>, RuntimeError> {
+        Err(RuntimeError::from("failed"))
     }
 
-    // evaluate arguments
-    let evaled_args = eval_args(func_args, g_env)?;
+    #[test]
+    fn apply_sfunc() {
+        let env = get_std_env();
+        let f = eval("(lambda (a b) (list b a))", &env).unwrap();
+        let args = [
+            Rc::new(SchemeObject::from("a")),
+            Rc::new(SchemeObject::from("b")),
+        ];
 
-    // add arguments to local environment
-    let local_env = Environment::new(Some(g_env.clone()));
-    for (name, arg) in arg_names.iter().zip(evaled_args) {
-        local_env.borrow_mut().set(name.clone(), arg);
+        let res = f.apply(&args, &env).unwrap();
+        assert_eq!(format!("{:?}", res), "(\"b\" \"a\")");
+        assert!(f.apply(&args[..1], &env).is_err());
     }
-    local_env.borrow_mut().shrink();
 
-    code_list.exec(&local_env)
+    #[test]
+    fn apply_rfunc() {
+        let env = get_std_env();
+        let list = env.borrow().lookup("list").unwrap();
+        let args = [Rc::new(SchemeObject::from(true))];
+
+        assert_eq!(list.apply(&args, &env).unwrap().to_string(), "(#t)");
+    }
+
+    #[test]
+    fn apply_not_a_function() {
+        let env = get_std_env();
+        assert!(SchemeObject::from("f").apply(&[], &env).is_err());
+    }
+
+    #[test]
+    fn map() {
+        let env = get_std_env();
+        let res = eval("(map (lambda (x) (list x x)) (list \"a\" \"b\"))", &env);
+        assert_eq!(
+            format!("{:?}", res.unwrap()),
+            "((\"a\" \"a\") (\"b\" \"b\"))"
+        );
+    }
+
+    #[test]
+    fn scheme_apply() {
+        let env = get_std_env();
+        let res = eval("(apply list \"a\" (list \"b\" \"c\"))", &env);
+        assert_eq!(format!("{:?}", res.unwrap()), "(\"a\" \"b\" \"c\")");
+    }
+
+    #[test]
+    fn errors_propagate() {
+        let env = get_std_env();
+        env.borrow_mut().set(
+            String::from("fail"),
+            Rc::new(SchemeObject::RFunc(
+                String::from("fail"),
+                fail,
+                Arity::any(),
+                None,
+            )),
+        );
+
+        let err = eval("(map (lambda (x) (fail)) (list #t))", &env).unwrap_err();
+        assert_eq!(err.root(), &RuntimeError::from("failed"));
+    }
+
+    #[test]
+    fn backtrace() {
+        let env = get_std_env();
+        // the calls aren't tail calls, which would replace the caller's frame
+        let program = "(define (inner x) (list nope))
+                       (define outer (lambda () (list (inner \"a\"))))
+                       (map (lambda (x) (list (outer))) (list #t))";
+
+        let err = eval(program, &env).unwrap_err();
+        let frames: Vec<&Frame> = err.backtrace().unwrap().frames().collect();
+        let names: Vec<&str> = frames.iter().map(|f| f.name.as_str()).collect();
+
+        assert_eq!(names, vec!["inner", "outer", "lambda", "map"]);
+        assert_eq!(frames[0].args, vec![Rc::new(SchemeObject::from("a"))]);
+
+        // inner and outer were called from scheme code, lambda from map
+        let call_line = |f: &Frame| f.call_site.map(|s| s.start.line);
+        assert_eq!(call_line(frames[0]), Some(2));
+        assert_eq!(call_line(frames[1]), Some(3));
+        assert_eq!(call_line(frames[2]), None);
+        assert_eq!(call_line(frames[3]), Some(3));
+    }
 }
@@ -0,0 +1,74 @@
+//! Rewrites derived forms as the core forms they stand for (e.g. for the REPL's `,expand`)
+
+use super::scm_obj::{SchemeObject, SymFrom};
+use std::collections::LinkedList;
+
+/// Expand the derived forms in `obj` and everything inside it
+/// There are no macros yet so only the shorthands built into the evaluator are rewritten
+pub fn expand(obj: &SchemeObject) -> SchemeObject {
+    match obj {
+        SchemeObject::List(lst, span) => {
+            let lst = lst.iter().map(expand).collect();
+            SchemeObject::List(expand_form(lst), *span)
+        }
+        // including quoted data, which isn't code
+        obj => obj.clone(),
+    }
+}
+
+/// Rewrite `form` (the contents of which are already expanded) if it is a shorthand:
+/// (define (f arg...) body) is (define f (lambda (arg...) body)). The same goes when there is a
+/// docstring before the body.
+fn expand_form(form: LinkedList<SchemeObject>) -> LinkedList<SchemeObject> {
+    let mut items = form.iter();
+
+    match (items.next(), items.next()) {
+        (Some(SchemeObject::Symbol(define)), Some(SchemeObject::List(signature, _)))
+            if define == "define" && !signature.is_empty() && items.len() > 0 =>
+        {
+            let mut signature = signature.iter().cloned();
+            let name = signature.next().unwrap();
+
+            let mut lambda = LinkedList::new();
+            lambda.push_back(SchemeObject::sym_from("lambda"));
+            lambda.push_back(SchemeObject::List(signature.collect(), None));
+            lambda.extend(items.cloned());
+
+            let mut out = LinkedList::new();
+            out.push_back(SchemeObject::sym_from("define"));
+            out.push_back(name);
+            out.push_back(SchemeObject::List(lambda, None));
+            out
+        }
+        _ => form,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast;
+
+    fn expand_str(source: &str) -> String {
+        let code = ast::parse_tokens(source.chars()).unwrap();
+        format!("{:?}", expand(&code[0]))
+    }
+
+    #[test]
+    fn define_shorthand() {
+        assert_eq!(
+            expand_str("(define (f x y) (list y x))"),
+            "(define f (lambda (x y) (list y x)))"
+        );
+        assert_eq!(
+            expand_str("(let ((g 1)) (define (h) g))"),
+            "(let ((g 1)) (define h (lambda () g)))"
+        );
+        assert_eq!(
+            expand_str("(define (f) \"doc\" 1)"),
+            "(define f (lambda () \"doc\" 1))"
+        );
+        assert_eq!(expand_str("(define x 1)"), "(define x 1)");
+        assert_eq!(expand_str("'(define (f) 1)"), "'(define (f) 1)");
+    }
+}
@@ -0,0 +1,172 @@
+//! Opaque host data which can be passed through scheme code
+
+use super::scm_obj::SchemeObject;
+
+use std::any::Any;
+use std::cmp::PartialEq;
+use std::fmt;
+use std::rc::Rc;
+
+/// Custom printing for a `ForeignObject`. The hook is given the wrapped value.
+pub type DisplayHook = fn(&dyn Any, &mut fmt::Formatter) -> fmt::Result;
+
+/// A rust value owned by the embedding application
+/// Scheme code can store and pass these around but can't look inside them.
+#[derive(Clone)]
+pub struct ForeignObject {
+    /// Name used when printing and comparing
+    type_name: String,
+    /// The host value
+    value: Rc<dyn Any>,
+    /// Optional replacement for the default `#<type_name>` output
+    display: Option<DisplayHook>,
+}
+
+impl ForeignObject {
+    /// Wrap a rust value
+    pub fn new<T: Any>(type_name: &str, value: T) -> Self {
+        Self::from_rc(type_name, Rc::new(value))
+    }
+
+    /// Wrap a value which is already shared with the host
+    pub fn from_rc(type_name: &str, value: Rc<dyn Any>) -> Self {
+        Self {
+            type_name: String::from(type_name),
+            value,
+            display: None,
+        }
+    }
+
+    /// Use `hook` to print this object
+    pub fn with_display(mut self, hook: DisplayHook) -> Self {
+        self.display = Some(hook);
+        self
+    }
+
+    /// The name given when the object was created
+    pub fn type_name(&self) -> &str {
+        &self.type_name
+    }
+
+    /// Borrow the wrapped value if it is a `T`
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.value.downcast_ref()
+    }
+
+    /// Get a new reference to the wrapped value if it is a `T`
+    pub fn downcast_rc<T: Any>(&self) -> Option<Rc<T>> {
+        self.value.clone().downcast().ok()
+    }
+
+    /// Address of the wrapped value. Clones of the object have the same address.
+    pub(crate) fn address(&self) -> usize {
+        Rc::as_ptr(&self.value) as *const () as usize
+    }
+}
+
+/// Foreign objects are only equal to themselves
+impl PartialEq for ForeignObject {
+    fn eq(&self, other: &Self) -> bool {
+        self.type_name == other.type_name && Rc::ptr_eq(&self.value, &other.value)
+    }
+}
+
+impl fmt::Display for ForeignObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.display {
+            Some(hook) => hook(self.value.as_ref(), f),
+            None => write!(f, "#<{}>", self.type_name),
+        }
+    }
+}
+
+impl fmt::Debug for ForeignObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// Downcasting helpers so that built-in functions can get at their arguments
+impl SchemeObject {
+    /// Create a `SchemeObject::Foreign`
+    pub fn foreign<T: Any>(type_name: &str, value: T) -> Self {
+        SchemeObject::Foreign(ForeignObject::new(type_name, value))
+    }
+
+    /// Borrow the host value if this is a `SchemeObject::Foreign` wrapping a `T`
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        match self {
+            SchemeObject::Foreign(obj) => obj.downcast_ref(),
+            _ => None,
+        }
+    }
+
+    /// Get a new reference to the host value if this is a `SchemeObject::Foreign` wrapping a `T`
+    pub fn downcast_rc<T: Any>(&self) -> Option<Rc<T>> {
+        match self {
+            SchemeObject::Foreign(obj) => obj.downcast_rc(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use data::*;
+
+    use std::any::Any;
+    use std::cell::RefCell;
+    use std::fmt;
+    use std::rc::Rc;
+
+    struct Point {
+        x: i32,
+        y: i32,
+    }
+
+    fn print_point(p: &dyn Any, f: &mut fmt::Formatter) -> fmt::Result {
+        let p = p.downcast_ref::<Point>().unwrap();
+        write!(f, "#<point {} {}>", p.x, p.y)
+    }
+
+    #[test]
+    fn downcast() {
+        let obj = SchemeObject::foreign("point", Point { x: 1, y: 2 });
+
+        assert_eq!(obj.downcast_ref::<Point>().map(|p| p.x), Some(1));
+        assert!(obj.downcast_ref::<String>().is_none());
+        assert!(SchemeObject::from("point")
+            .downcast_ref::<Point>()
+            .is_none());
+        assert_eq!(obj.downcast_rc::<Point>().map(|p| p.y), Some(2));
+    }
+
+    #[test]
+    fn shared_with_host() {
+        let cell = Rc::new(RefCell::new(0));
+        let obj = SchemeObject::Foreign(ForeignObject::from_rc("counter", cell.clone()));
+
+        *obj.downcast_ref::<RefCell<i32>>().unwrap().borrow_mut() += 1;
+        assert_eq!(*cell.borrow(), 1);
+    }
+
+    #[test]
+    fn equality() {
+        let obj = SchemeObject::foreign("point", Point { x: 1, y: 2 });
+        let other = SchemeObject::foreign("point", Point { x: 1, y: 2 });
+
+        assert_eq!(obj, obj.clone());
+        assert_ne!(obj, other);
+    }
+
+    #[test]
+    fn display() {
+        let plain = SchemeObject::foreign("point", Point { x: 1, y: 2 });
+        let hooked = SchemeObject::Foreign(
+            ForeignObject::new("point", Point { x: 1, y: 2 }).with_display(print_point),
+        );
+
+        assert_eq!(plain.to_string(), "#<point>");
+        assert_eq!(hooked.to_string(), "#<point 1 2>");
+    }
+}
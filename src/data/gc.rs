@@ -0,0 +1,507 @@
+//! Collecting reference cycles
+//!
+//! Objects are reference counted, which frees most of them as soon as they are no longer used, but
+//! a function defined in an environment holds that environment through its closure so the two
+//! keep each other alive. Every environment is tracked here and `collect` finds the ones which can
+//! only be reached from such cycles and clears them, breaking the cycles.
+//!
+//! There are no pointers into the heap from outside which the collector can see, so it works the
+//! other way around: it counts the references to each object which come from inside the graph
+//! reachable from the tracked environments. An object with more references than that is in use
+//! from outside (e.g. by the embedder or the virtual machine's stack), so it and everything it
+//! refers to are marked live. Whatever is left over is garbage.
+//! The collector follows references through lists, vectors, multiple values, records, promises
+//! and caught errors (the arguments in their backtraces). It can't look inside foreign objects, so
+//! a cycle which passes through the host's data (e.g. a foreign object holding a scheme function
+//! whose environment holds the foreign object) is never collected. Such references are never
+//! counted, which only ever keeps objects alive.
+
+use super::env::*;
+use super::library::{Exports, Libraries};
+use super::promise::{Promise, PromiseState};
+use super::record::Record;
+use super::scm_obj::SchemeObject;
+use super::vm::Closure;
+use super::RuntimeError;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem;
+use std::rc::{Rc, Weak};
+
+/// Allocations between automatic collections unless the embedder chooses otherwise
+pub const DEFAULT_THRESHOLD: usize = 10_000;
+
+/// What the collector has done so far on this thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GcStats {
+    /// How many collections have run
+    pub collections: usize,
+    /// How many objects collections have reclaimed in total
+    pub freed: usize,
+    /// How many environments are alive
+    pub environments: usize,
+    /// Environments and closures allocated since the last collection
+    pub allocated: usize,
+    /// Allocations which trigger an automatic collection (`None` if they are disabled)
+    pub threshold: Option<usize>,
+}
+
+/// The tracked environments and the collector's counters
+struct Heap {
+    envs: Vec<Weak<RefCell<Environment>>>,
+    /// The number of tracked environments after the last time dead ones were removed
+    pruned_len: usize,
+    allocated: usize,
+    threshold: Option<usize>,
+    collections: usize,
+    freed: usize,
+}
+
+thread_local! {
+    static HEAP: RefCell<Heap> = const { RefCell::new(Heap {
+        envs: Vec::new(),
+        pruned_len: 0,
+        allocated: 0,
+        threshold: Some(DEFAULT_THRESHOLD),
+        collections: 0,
+        freed: 0,
+    }) };
+}
+
+impl Heap {
+    /// Forget environments which have already been freed
+    fn prune(&mut self) {
+        self.envs.retain(|env| env.strong_count() > 0);
+        self.pruned_len = self.envs.len();
+    }
+}
+
+/// Track a new environment so cycles through it can be collected
+pub(crate) fn track(env: &PackedEnv) {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.envs.push(Rc::downgrade(env));
+        heap.allocated += 1;
+        // don't let environments which were freed normally build up
+        if heap.envs.len() > 2 * heap.pruned_len.max(64) {
+            heap.prune();
+        }
+    })
+}
+
+/// Record that something which could be part of a cycle was allocated
+pub(crate) fn allocated() {
+    HEAP.with(|heap| heap.borrow_mut().allocated += 1)
+}
+
+/// Collect if enough has been allocated since the last collection
+/// Only called where the interpreter isn't holding any environment borrowed.
+pub(crate) fn safe_point() {
+    let due = HEAP.with(|heap| {
+        let heap = heap.borrow();
+        heap.threshold.is_some_and(|t| heap.allocated >= t)
+    });
+    if due {
+        collect();
+    }
+}
+
+/// Set how many environments and closures can be allocated before the collector runs
+/// automatically. `None` means it only runs when `collect` (or `(gc)`) is called.
+pub fn set_threshold(threshold: Option<usize>) {
+    HEAP.with(|heap| heap.borrow_mut().threshold = threshold)
+}
+
+/// What the collector has done so far on this thread
+pub fn stats() -> GcStats {
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.prune();
+        GcStats {
+            collections: heap.collections,
+            freed: heap.freed,
+            environments: heap.envs.len(),
+            allocated: heap.allocated,
+            threshold: heap.threshold,
+        }
+    })
+}
+
+/// Something which can be part of a cycle. Holds a reference so it can't be freed mid-collection.
+enum Node {
+    Env(PackedEnv),
+    Libraries(Rc<RefCell<Libraries>>),
+    Exports(Rc<Exports>),
+    Closure(Rc<Closure>),
+    Promise(Rc<Promise>),
+    PromiseState(Rc<RefCell<PromiseState>>),
+    Record(Rc<Record>),
+    Object(Rc<SchemeObject>),
+}
+
+impl Node {
+    fn id(&self) -> usize {
+        match self {
+            Node::Env(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Libraries(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Exports(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Closure(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Promise(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::PromiseState(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Record(rc) => Rc::as_ptr(rc) as *const () as usize,
+            Node::Object(rc) => Rc::as_ptr(rc) as *const () as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Env(rc) => Rc::strong_count(rc),
+            Node::Libraries(rc) => Rc::strong_count(rc),
+            Node::Exports(rc) => Rc::strong_count(rc),
+            Node::Closure(rc) => Rc::strong_count(rc),
+            Node::Promise(rc) => Rc::strong_count(rc),
+            Node::PromiseState(rc) => Rc::strong_count(rc),
+            Node::Record(rc) => Rc::strong_count(rc),
+            Node::Object(rc) => Rc::strong_count(rc),
+        }
+    }
+
+    /// The nodes this one refers to. Anything which is borrowed right now is skipped.
+    fn children(&self, out: &mut Vec<Node>) {
+        match self {
+            Node::Env(env) => {
+                if let Ok(env) = env.try_borrow() {
+                    out.extend(env.get_parent().map(Node::Env));
+                    out.extend(env.own_libraries().map(Node::Libraries));
+                    out.extend(
+                        env.local_bindings()
+                            .map(|(_, val)| Node::Object(val.clone())),
+                    );
+                }
+            }
+            Node::Libraries(libs) => {
+                if let Ok(libs) = libs.try_borrow() {
+                    out.extend(libs.exports().map(|e| Node::Exports(e.clone())));
+                }
+            }
+            Node::Exports(exports) => {
+                out.extend(exports.values().map(|val| Node::Object(val.clone())));
+            }
+            Node::Closure(closure) => {
+                out.push(Node::Env(closure.env().clone()));
+                out.extend(closure.free().iter().map(|val| Node::Object(val.clone())));
+            }
+            Node::Promise(promise) => {
+                if let Some(state) = promise.try_shared() {
+                    out.push(Node::PromiseState(state));
+                }
+            }
+            Node::PromiseState(state) => {
+                if let Ok(state) = state.try_borrow() {
+                    match *state {
+                        PromiseState::Done(ref val) => out.push(Node::Object(val.clone())),
+                        PromiseState::Delayed {
+                            ref func, ref args, ..
+                        } => {
+                            out.push(Node::Object(func.clone()));
+                            out.extend(args.iter().map(|a| Node::Object(a.clone())));
+                        }
+                    }
+                }
+            }
+            Node::Record(record) => {
+                if let Some(values) = record.try_values() {
+                    out.extend(values.iter().map(|val| Node::Object(val.clone())));
+                }
+            }
+            Node::Object(obj) => object_children(obj, out),
+        }
+    }
+}
+
+/// The closures, promises and records inside an object
+fn object_children(obj: &SchemeObject, out: &mut Vec<Node>) {
+    match obj {
+        SchemeObject::List(l, _) => l.iter().for_each(|o| object_children(o, out)),
+        SchemeObject::Vector(v) => v.iter().for_each(|o| object_children(o, out)),
+        SchemeObject::Quoted(o) => object_children(o, out),
+        SchemeObject::Values(v) => out.extend(v.iter().map(|val| Node::Object(val.clone()))),
+        SchemeObject::SFunc(_, closure, _) => out.push(Node::Closure(closure.clone())),
+        SchemeObject::Promise(promise) => out.push(Node::Promise(promise.clone())),
+        SchemeObject::Record(record) => out.push(Node::Record(record.clone())),
+        SchemeObject::Condition(err) => error_children(err, out),
+        _ => (),
+    }
+}
+
+/// The objects kept by an error caught by guard
+fn error_children(err: &RuntimeError, out: &mut Vec<Node>) {
+    match err {
+        RuntimeError::Raised(obj) => out.push(Node::Object(obj.clone())),
+        RuntimeError::Traced(backtrace, err) => {
+            for frame in backtrace.frames() {
+                out.extend(frame.args.iter().map(|arg| Node::Object(arg.clone())));
+            }
+            error_children(err, out);
+        }
+        RuntimeError::Located(_, err) | RuntimeError::Loaded(_, err) => error_children(err, out),
+        _ => (),
+    }
+}
+
+/// Everything reachable from the tracked environments
+struct Graph {
+    nodes: Vec<Node>,
+    index: HashMap<usize, usize>,
+    /// The edges out of each node, as indices into `nodes`
+    edges: Vec<Vec<usize>>,
+}
+
+impl Graph {
+    fn add(&mut self, node: Node) -> (usize, bool) {
+        let id = node.id();
+        if let Some(&i) = self.index.get(&id) {
+            return (i, false);
+        }
+
+        self.nodes.push(node);
+        self.edges.push(Vec::new());
+        self.index.insert(id, self.nodes.len() - 1);
+        (self.nodes.len() - 1, true)
+    }
+
+    fn build(envs: Vec<PackedEnv>) -> Self {
+        let mut graph = Graph {
+            nodes: Vec::new(),
+            index: HashMap::new(),
+            edges: Vec::new(),
+        };
+
+        let mut todo: Vec<usize> = envs
+            .into_iter()
+            .map(|env| graph.add(Node::Env(env)))
+            .filter(|&(_, new)| new)
+            .map(|(i, _)| i)
+            .collect();
+
+        let mut children = Vec::new();
+        while let Some(i) = todo.pop() {
+            graph.nodes[i].children(&mut children);
+            for child in children.drain(..) {
+                let (c, new) = graph.add(child);
+                graph.edges[i].push(c);
+                if new {
+                    todo.push(c);
+                }
+            }
+        }
+
+        graph
+    }
+
+    /// Which nodes are reachable from outside the graph
+    fn mark(&self) -> Vec<bool> {
+        let mut internal = vec![0; self.nodes.len()];
+        for edges in &self.edges {
+            for &c in edges {
+                internal[c] += 1;
+            }
+        }
+
+        // the graph holds one reference to each node itself
+        let mut todo: Vec<usize> = (0..self.nodes.len())
+            .filter(|&i| self.nodes[i].strong_count() > internal[i] + 1)
+            .collect();
+        let mut live = vec![false; self.nodes.len()];
+        while let Some(i) = todo.pop() {
+            if !live[i] {
+                live[i] = true;
+                todo.extend(&self.edges[i]);
+            }
+        }
+
+        live
+    }
+}
+
+/// Reclaim the environments and closures which are only reachable from reference cycles.
+/// Returns how many objects were reclaimed.
+pub fn collect() -> usize {
+    let envs: Vec<PackedEnv> = HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.prune();
+        heap.allocated = 0;
+        heap.envs.iter().filter_map(Weak::upgrade).collect()
+    });
+
+    let graph = Graph::build(envs);
+    let live = graph.mark();
+
+    // clearing the garbage environments breaks the cycles so the objects are freed when the
+    // graph is dropped. What was in them is dropped after the borrows end.
+    let mut cleared = Vec::new();
+    let mut freed = 0;
+    for (node, _) in graph.nodes.iter().zip(live).filter(|&(_, live)| !live) {
+        freed += 1;
+        match node {
+            Node::Env(env) => {
+                if let Ok(mut env) = env.try_borrow_mut() {
+                    cleared.push(mem::replace(&mut *env, Environment::empty()));
+                }
+            }
+            Node::Libraries(libs) => {
+                if let Ok(mut libs) = libs.try_borrow_mut() {
+                    libs.clear_loaded();
+                }
+            }
+            _ => (),
+        }
+    }
+    drop(graph);
+    drop(cleared);
+
+    HEAP.with(|heap| {
+        let mut heap = heap.borrow_mut();
+        heap.collections += 1;
+        heap.freed += freed;
+        heap.prune();
+    });
+
+    freed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast;
+    use stdlib::get_std_env;
+
+    fn eval(env: &PackedEnv, program: &str) -> Rc<SchemeObject> {
+        let mut res = Rc::new(SchemeObject::None);
+        for code in ast::ObjectIterator::from(program.chars()) {
+            res = code.unwrap().exec(env).unwrap();
+        }
+        res
+    }
+
+    /// A new top level environment with just `names` from the standard library, so that
+    /// collecting it only counts the objects made by the test
+    fn env_with(names: &[&str]) -> PackedEnv {
+        let std = get_std_env();
+        let env = Environment::new(None);
+        for name in names {
+            let val = std.borrow().lookup(*name).unwrap();
+            env.borrow_mut().set(*name, val);
+        }
+        env
+    }
+
+    /// How many objects are collected after running `program` and dropping its environment
+    fn collected(names: &[&str], program: &str) -> usize {
+        set_threshold(None);
+        collect();
+        let env = env_with(names);
+        eval(&env, program);
+
+        let weak = Rc::downgrade(&env);
+        drop(env);
+        // the functions keep the environment alive
+        assert!(weak.upgrade().is_some(), "{}", program);
+
+        let freed = collect();
+        assert!(weak.upgrade().is_none(), "{}", program);
+        freed
+    }
+
+    #[test]
+    fn collects_cycles() {
+        // the environment, =, -, count and its closure
+        assert_eq!(
+            collected(
+                &["=", "-"],
+                "(define (count n) (if (= n 0) 0 (count (- n 1)))) (count 10)"
+            ),
+            5
+        );
+        // ... and the value of n kept by the closure
+        assert_eq!(
+            collected(
+                &["=", "-"],
+                "(define f (let ((n 3)) (lambda (x) (if (= x 0) n (f (- x 1)))))) (f 2)"
+            ),
+            6
+        );
+    }
+
+    #[test]
+    fn collects_cycles_through_data() {
+        // the environment, the type, the three procedures and their closures, b, the record and
+        // the function in it and its closure
+        assert_eq!(
+            collected(
+                &[],
+                "(define-record-type box (make-box v) box? (v unbox))
+                 (define b (make-box (lambda () b)))"
+            ),
+            12
+        );
+        // the environment, p, the promise and its state, and the delayed code and its closure
+        assert_eq!(collected(&[], "(define p (delay (lambda () p)))"), 6);
+        // the environment, force, values, p, the promise and its state, the values, 1, and the
+        // function and its closure
+        assert_eq!(
+            collected(
+                &["force", "values"],
+                "(define p (delay (values 1 (lambda () p)))) (force p)"
+            ),
+            10
+        );
+        // the environment, list, l, and the function in it and its closure
+        assert_eq!(collected(&["list"], "(define l (list 1 (lambda () l)))"), 4);
+        // the environment, f and its closure, c, and the function in c's backtrace and its
+        // closure
+        assert_eq!(
+            collected(
+                &[],
+                "(define (f x) (x 1)) (define c (guard (e (#t e)) (f (lambda () c))))"
+            ),
+            6
+        );
+    }
+
+    #[test]
+    fn keeps_live_objects() {
+        set_threshold(None);
+        let env = get_std_env();
+        let f = eval(&env, "(define (twice x) (list x x)) twice");
+
+        let other = get_std_env();
+        let weak = Rc::downgrade(&other);
+        drop(other);
+
+        collect();
+        assert!(weak.upgrade().is_none());
+        // the function is still used from rust, so its environment is still there
+        assert_eq!(format!("{:?}", eval(&env, "(twice 1)")), "(1 1)");
+        drop(env);
+        let res = f.apply_list(
+            &vec![Rc::new(SchemeObject::Integer(2))]
+                .into_iter()
+                .collect(),
+            &Environment::new(None),
+        );
+        assert_eq!(format!("{:?}", res.unwrap()), "(2 2)");
+    }
+
+    #[test]
+    fn threshold() {
+        set_threshold(Some(1));
+        let before = stats().collections;
+        let env = get_std_env();
+        eval(&env, "((lambda () 1))");
+        assert!(stats().collections > before);
+        set_threshold(None);
+        assert_eq!(stats().threshold, None);
+    }
+}
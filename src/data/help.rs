@@ -0,0 +1,101 @@
+//! Descriptions of names for the REPL (e.g. `,help` and `,env`)
+
+use super::env::PackedEnv;
+use super::exec::SPECIAL_FORMS;
+use super::scm_obj::{Arity, SchemeObject};
+use super::symbol::Sym;
+
+/// How to use the special form `name`, if it is one
+pub fn special_form_usage(name: &str) -> Option<&'static str> {
+    SPECIAL_FORMS
+        .iter()
+        .find(|(form, _)| *form == name)
+        .map(|(_, usage)| *usage)
+}
+
+/// A one line description of `val`, which is bound to `name`
+/// Procedures are described by how they are called. Other values are shown as they are.
+pub fn summary(name: &str, val: &SchemeObject) -> String {
+    match val {
+        SchemeObject::RFunc(_, _, arity, _) if *arity == Arity::any() => {
+            format!("({} ...) built-in procedure", name)
+        }
+        SchemeObject::RFunc(_, _, arity, _) => {
+            let plural = if *arity == Arity::from(1) { "" } else { "s" };
+            format!(
+                "({} ...) built-in procedure taking {} argument{}",
+                name, arity, plural
+            )
+        }
+        SchemeObject::SFunc(lambda, _, _) => {
+            let args: Vec<String> = lambda.args.iter().map(Sym::to_string).collect();
+            let mut call = vec![name];
+            call.extend(args.iter().map(String::as_str));
+            format!("({}) procedure", call.join(" "))
+        }
+        val => format!("{} = {:?}", name, val),
+    }
+}
+
+/// Everything we know about `name` in `env`, or None if it isn't a special form or bound
+pub fn describe(name: &str, env: &PackedEnv) -> Option<String> {
+    if let Some(usage) = special_form_usage(name) {
+        return Some(format!("{}: special form\n{}", name, usage));
+    }
+
+    let val = env.borrow().lookup(name)?;
+    let mut description = summary(name, &val);
+    if let Some(doc) = val.documentation() {
+        description += "\n";
+        description += &doc;
+    }
+    // show the code of procedures written in scheme
+    if let Some(source) = val.source() {
+        description += &format!("\n{:?}", source);
+    }
+
+    Some(description)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast;
+    use stdlib::get_std_env;
+
+    #[test]
+    fn describe_names() {
+        let env = get_std_env();
+        let code = ast::parse_tokens(
+            "(define (f x y) (list y x)) (define (g) \"Does nothing.\" 0) (define n 5)".chars(),
+        )
+        .unwrap();
+        for c in code {
+            c.exec(&env).unwrap();
+        }
+
+        assert_eq!(
+            describe("f", &env),
+            Some(String::from("(f x y) procedure\n(lambda (x y) (list y x))"))
+        );
+        assert_eq!(
+            describe("g", &env),
+            Some(String::from(
+                "(g) procedure\nDoes nothing.\n(lambda () \"Does nothing.\" 0)"
+            ))
+        );
+        assert_eq!(describe("n", &env), Some(String::from("n = 5")));
+        assert_eq!(
+            describe("map", &env),
+            Some(String::from(
+                "(map ...) built-in procedure taking 2 arguments\n\
+                 Call f on each item of lst, returning a list of the results"
+            ))
+        );
+        assert_eq!(
+            describe("if", &env),
+            Some(String::from("if: special form\n(if condition then [else])"))
+        );
+        assert_eq!(describe("nothing", &env), None);
+    }
+}
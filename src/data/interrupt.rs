@@ -0,0 +1,90 @@
+//! Stopping evaluation from outside the interpreter
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// A handle which stops the code being run in an interpreter, e.g. from a signal handler or a
+/// timer thread. Clones share the same flag.
+///
+/// Evaluation checks the handle before each instruction. Once triggered, the code being run stops
+/// with `RuntimeError::Interrupted`, which scheme code can catch with guard. Triggering it while
+/// nothing is being run has no effect: the flag is cleared when evaluation starts.
+#[derive(Debug, Clone, Default)]
+pub struct Interrupt(Arc<AtomicBool>);
+
+impl Interrupt {
+    /// A new handle which hasn't been triggered
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Stop the code being run
+    pub fn trigger(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    /// Has it been triggered since evaluation last noticed?
+    pub fn is_triggered(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    /// Clear the flag, returning whether it was set
+    pub(crate) fn take(&self) -> bool {
+        self.0.load(Ordering::Relaxed) && self.0.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// Handles are equal if they share a flag
+impl PartialEq for Interrupt {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast;
+    use data::{RuntimeError, SchemeObject};
+    use stdlib::get_std_env;
+
+    use std::thread;
+    use std::time::Duration;
+
+    /// Trigger `interrupt` from another thread in a little while
+    fn trigger_soon(interrupt: &Interrupt) {
+        let interrupt = interrupt.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            interrupt.trigger();
+        });
+    }
+
+    #[test]
+    fn interrupted() {
+        let env = get_std_env();
+        let interrupt = Interrupt::new();
+        env.borrow_mut().set_interrupt(interrupt.clone());
+
+        let code = ast::parse_tokens(
+            "(define (loop) (loop))
+             (guard (e (#t \"caught\")) (loop))
+             (loop)
+             \"fine\""
+                .chars(),
+        )
+        .unwrap();
+        code[0].exec(&env).unwrap();
+
+        trigger_soon(&interrupt);
+        assert_eq!(*code[1].exec(&env).unwrap(), SchemeObject::from("caught"));
+
+        trigger_soon(&interrupt);
+        let err = code[2].exec(&env).unwrap_err();
+        assert_eq!(*err.root(), RuntimeError::Interrupted);
+
+        // only evaluation which is already running is stopped
+        interrupt.trigger();
+        assert_eq!(*code[3].exec(&env).unwrap(), SchemeObject::from("fine"));
+    }
+}
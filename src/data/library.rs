@@ -0,0 +1,596 @@
+//! Libraries: `define-library` and `import`
+
+use super::env::*;
+use super::scm_obj::SchemeObject;
+use super::RuntimeError;
+use ast;
+use stdlib::get_none;
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet, LinkedList};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::rc::Rc;
+
+/// Fills an environment with the bindings of a built-in library
+pub type LibraryBuilder = fn(&PackedEnv);
+
+/// The bindings exported by a library, by their exported names
+pub type Exports = HashMap<String, Rc<SchemeObject>>;
+
+/// The libraries known to an interpreter
+/// Each library is only loaded once: everything which imports it shares the same bindings.
+pub struct Libraries {
+    /// Libraries which have been loaded, by name e.g. "(scheme base)"
+    loaded: HashMap<String, Rc<Exports>>,
+    /// Libraries provided by rust code which haven't been loaded yet
+    builtin: HashMap<String, LibraryBuilder>,
+    /// Directories searched for library files. (a b) is found in a/b.sld
+    search_path: Vec<PathBuf>,
+    /// Libraries part way through loading (to catch libraries which import themselves)
+    loading: Vec<String>,
+    /// Libraries which code isn't allowed to import
+    forbidden: HashSet<String>,
+}
+
+impl Libraries {
+    /// Create a new `Libraries` which searches the current directory for library files
+    pub fn new() -> Self {
+        Self {
+            loaded: HashMap::new(),
+            builtin: HashMap::new(),
+            search_path: vec![PathBuf::from(".")],
+            loading: Vec::new(),
+            forbidden: HashSet::new(),
+        }
+    }
+
+    /// Provide a library from rust. `name` is written like it would be in scheme e.g.
+    /// "(scheme base)". `build` is called the first time the library is imported.
+    pub fn add_builtin(&mut self, name: &str, build: LibraryBuilder) {
+        self.builtin.insert(String::from(name), build);
+    }
+
+    /// Don't allow the library called `name` to be imported
+    pub fn forbid(&mut self, name: &str) {
+        self.forbidden.insert(String::from(name));
+    }
+
+    /// Only search `dirs` for library files. An empty search path stops libraries being loaded
+    /// from files.
+    pub fn set_search_path(&mut self, dirs: Vec<PathBuf>) {
+        self.search_path = dirs;
+    }
+
+    /// Search `dir` for library files (after the directories already on the search path)
+    pub fn add_search_path<P: Into<PathBuf>>(&mut self, dir: P) {
+        self.search_path.push(dir.into());
+    }
+
+    /// The bindings of every library which has been loaded
+    pub(crate) fn exports(&self) -> impl Iterator<Item = &Rc<Exports>> {
+        self.loaded.values()
+    }
+
+    /// Forget the libraries which have been loaded (used by the garbage collector)
+    pub(crate) fn clear_loaded(&mut self) {
+        self.loaded.clear()
+    }
+
+    /// Has the library called `name` (e.g. "(scheme base)") been loaded?
+    pub fn is_loaded(&self, name: &str) -> bool {
+        self.loaded.contains_key(name)
+    }
+
+    /// The file which should define the library with the name `parts` e.g. mylib/util.sld
+    fn find_file(&self, parts: &[String]) -> Option<PathBuf> {
+        let mut relative = parts.iter().collect::<PathBuf>();
+        relative.set_extension("sld");
+
+        self.search_path
+            .iter()
+            .map(|dir| dir.join(&relative))
+            .find(|path| path.is_file())
+    }
+}
+
+impl Default for Libraries {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `Libraries` are only equal to themselves
+impl PartialEq for Libraries {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Libraries {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut loaded: Vec<&String> = self.loaded.keys().collect();
+        loaded.sort();
+        write!(f, "Libraries {{ loaded: {:?} }}", loaded)
+    }
+}
+
+/// The libraries which can be imported into `env`
+fn libraries(env: &PackedEnv) -> Result<Rc<RefCell<Libraries>>, RuntimeError> {
+    env.borrow()
+        .libraries()
+        .ok_or_else(|| RuntimeError::from("No libraries can be imported here"))
+}
+
+/// Read a library name like (mylib util)
+/// Returns the name as it is written in scheme and its parts
+fn library_name(obj: &SchemeObject) -> Result<(String, Vec<String>), RuntimeError> {
+    let lst = match obj {
+        SchemeObject::List(lst, _) if !lst.is_empty() => lst,
+        o => {
+            return Err(RuntimeError::from(format!(
+                "Expected a library name, got {:?}",
+                o
+            )))
+        }
+    };
+
+    let mut parts = Vec::with_capacity(lst.len());
+    for part in lst {
+        match part {
+            SchemeObject::Symbol(s) => parts.push(s.to_string()),
+            SchemeObject::Integer(i) => parts.push(i.to_string()),
+            o => {
+                return Err(RuntimeError::from(format!(
+                    "Library names are made of symbols and integers, not {:?}",
+                    o
+                )))
+            }
+        }
+    }
+
+    Ok((format!("({})", parts.join(" ")), parts))
+}
+
+/// Get the exports of the library called `name_obj`, loading it if it hasn't been already
+fn find_library(name_obj: &SchemeObject, env: &PackedEnv) -> Result<Rc<Exports>, RuntimeError> {
+    let (name, parts) = library_name(name_obj)?;
+    let libs = libraries(env)?;
+
+    if let Some(exports) = libs.borrow().loaded.get(&name) {
+        return Ok(exports.clone());
+    }
+
+    if libs.borrow().forbidden.contains(&name) {
+        return Err(RuntimeError::Forbidden(name));
+    }
+
+    if libs.borrow().loading.contains(&name) {
+        return Err(RuntimeError::from(format!(
+            "Library {} imports itself",
+            name
+        )));
+    }
+
+    // libraries provided by rust
+    let builtin = libs.borrow().builtin.get(&name).cloned();
+    if let Some(build) = builtin {
+        let lib_env = Environment::new(None);
+        build(&lib_env);
+
+        let exports: Exports = lib_env
+            .borrow()
+            .local_bindings()
+            .map(|(k, v)| (k.to_string(), v.clone()))
+            .collect();
+        let exports = Rc::new(exports);
+
+        libs.borrow_mut().loaded.insert(name, exports.clone());
+        return Ok(exports);
+    }
+
+    // libraries defined in files
+    let path = libs.borrow().find_file(&parts);
+    let path = path.ok_or_else(|| RuntimeError::from(format!("Library {} not found", name)))?;
+
+    libs.borrow_mut().loading.push(name.clone());
+    let res = load_library_file(&path, &libs);
+    libs.borrow_mut().loading.retain(|n| *n != name);
+    res?;
+
+    let exports = libs.borrow().loaded.get(&name).cloned();
+    exports.ok_or_else(|| {
+        RuntimeError::from(format!(
+            "{} doesn't define library {}",
+            path.display(),
+            name
+        ))
+    })
+}
+
+/// Evaluate a library file. It should contain a define-library form for the library we want.
+fn load_library_file(path: &Path, libs: &Rc<RefCell<Libraries>>) -> Result<(), RuntimeError> {
+    let source = fs::read_to_string(path)
+        .map_err(|e| RuntimeError::File(format!("{}: {}", path.display(), e)))?;
+
+    let env = Environment::new(None);
+    env.borrow_mut().set_libraries(libs.clone());
+
+    for code in ast::parse_source(&source) {
+        let code = code.map_err(|e| {
+            RuntimeError::from(format!("{}: Parse Error: {:?}", path.display(), e.root()))
+        })?;
+        code.exec(&env)?;
+    }
+
+    Ok(())
+}
+
+/// Read a list of symbols e.g. the names in (only (lib) name...)
+fn symbols<'a, I>(iter: I) -> Result<Vec<String>, RuntimeError>
+where
+    I: Iterator<Item = &'a SchemeObject>,
+{
+    iter.map(|obj| match obj {
+        SchemeObject::Symbol(s) => Ok(s.to_string()),
+        o => Err(RuntimeError::from(format!(
+            "Expected an identifier, got {:?}",
+            o
+        ))),
+    })
+    .collect()
+}
+
+/// Take `name` out of `bindings`, complaining if it isn't there
+fn take_binding(
+    bindings: &mut Exports,
+    name: &str,
+    set: &SchemeObject,
+) -> Result<Rc<SchemeObject>, RuntimeError> {
+    bindings
+        .remove(name)
+        .ok_or_else(|| RuntimeError::from(format!("{} isn't in the import set {:?}", name, set)))
+}
+
+/// Work out the bindings given by an import set:
+/// a library name, (only set name...), (except set name...), (prefix set prefix) or
+/// (rename set (from to)...)
+fn import_set(set: &SchemeObject, env: &PackedEnv) -> Result<Exports, RuntimeError> {
+    let lst = match set {
+        SchemeObject::List(lst, _) => lst,
+        o => {
+            return Err(RuntimeError::from(format!(
+                "Expected an import set, got {:?}",
+                o
+            )))
+        }
+    };
+
+    let mut iter = lst.iter();
+    let modifier = match (iter.next(), iter.next()) {
+        (Some(SchemeObject::Symbol(m)), Some(inner)) => Some((m.name(), inner)),
+        _ => None,
+    };
+
+    match modifier.as_ref().map(|(m, inner)| (&**m, *inner)) {
+        Some(("only", inner)) => {
+            let mut bindings = import_set(inner, env)?;
+            let mut out = Exports::new();
+            for name in symbols(iter)? {
+                let val = take_binding(&mut bindings, &name, inner)?;
+                out.insert(name, val);
+            }
+            Ok(out)
+        }
+        Some(("except", inner)) => {
+            let mut bindings = import_set(inner, env)?;
+            for name in symbols(iter)? {
+                take_binding(&mut bindings, &name, inner)?;
+            }
+            Ok(bindings)
+        }
+        Some(("prefix", inner)) => {
+            let prefix = match symbols(iter)?.as_slice() {
+                [prefix] => prefix.clone(),
+                _ => return Err(RuntimeError::from("Expected (prefix import-set prefix)")),
+            };
+            let bindings = import_set(inner, env)?;
+            Ok(bindings
+                .into_iter()
+                .map(|(name, val)| (format!("{}{}", prefix, name), val))
+                .collect())
+        }
+        Some(("rename", inner)) => {
+            let mut bindings = import_set(inner, env)?;
+            let mut renamed = Vec::new();
+            for pair in iter {
+                let names = match pair {
+                    SchemeObject::List(p, _) => symbols(p.iter())?,
+                    _ => Vec::new(),
+                };
+                match names.as_slice() {
+                    [from, to] => {
+                        renamed.push((to.clone(), take_binding(&mut bindings, from, inner)?))
+                    }
+                    _ => {
+                        return Err(RuntimeError::from(format!(
+                            "Expected (from to) in rename, got {:?}",
+                            pair
+                        )))
+                    }
+                }
+            }
+            bindings.extend(renamed);
+            Ok(bindings)
+        }
+        _ => Ok((*find_library(set, env)?).clone()),
+    }
+}
+
+/// Import all of the library called `name` (e.g. "(scheme base)") into `env`
+pub fn import_library(env: &PackedEnv, name: &str) -> Result<(), RuntimeError> {
+    let name_obj = match ast::parse_tokens(name.chars()) {
+        Ok(ref objs) if objs.len() == 1 => objs[0].clone(),
+        _ => {
+            return Err(RuntimeError::from(format!(
+                "{:?} isn't a library name",
+                name
+            )))
+        }
+    };
+
+    for (name, val) in find_library(&name_obj, env)?.iter() {
+        env.borrow_mut().set_global(name.clone(), val.clone());
+    }
+
+    Ok(())
+}
+
+/// helper function for `exec_codelist`
+/// (import import-set...)
+pub fn import(
+    tail: &LinkedList<SchemeObject>,
+    env: &PackedEnv,
+) -> Result<Rc<SchemeObject>, RuntimeError> {
+    for set in tail {
+        for (name, val) in import_set(set, env)? {
+            env.borrow_mut().set_global(name, val);
+        }
+    }
+
+    Ok(get_none())
+}
+
+/// helper function for `exec_codelist`
+/// (define-library name declaration...)
+/// Declarations are (export spec...), (import import-set...) and (begin form...). Export specs
+/// are names or (rename internal external).
+pub fn define_library(
+    tail: &LinkedList<SchemeObject>,
+    env: &PackedEnv,
+) -> Result<Rc<SchemeObject>, RuntimeError> {
+    let mut tail_iter = tail.iter();
+    let name_obj = tail_iter
+        .next()
+        .ok_or_else(|| RuntimeError::from("define-library expects a library name"))?;
+    let (name, _) = library_name(name_obj)?;
+    let libs = libraries(env)?;
+
+    // standard libraries can't be replaced, whether or not the environment is allowed them
+    if libs.borrow().forbidden.contains(&name) {
+        return Err(RuntimeError::Forbidden(name));
+    }
+    if libs.borrow().builtin.contains_key(&name) {
+        return Err(RuntimeError::from(format!(
+            "Library {} is built in and can't be redefined",
+            name
+        )));
+    }
+
+    // the library only sees what it imports
+    let lib_env = Environment::new(None);
+    lib_env.borrow_mut().set_libraries(libs.clone());
+
+    // (internal name, exported name)
+    let mut export_names = Vec::new();
+
+    for decl in tail_iter {
+        let unknown = || RuntimeError::from(format!("Unknown library declaration {:?}", decl));
+        let mut forms = match decl {
+            SchemeObject::List(lst, _) => lst.iter(),
+            _ => return Err(unknown()),
+        };
+
+        match forms.next() {
+            Some(SchemeObject::Symbol(s)) if s == "export" => {
+                for spec in forms {
+                    let names = match spec {
+                        SchemeObject::Symbol(s) => vec![s.to_string(), s.to_string()],
+                        SchemeObject::List(lst, _) => symbols(lst.iter())?,
+                        _ => Vec::new(),
+                    };
+                    match names.as_slice() {
+                        [internal, external] => {
+                            export_names.push((internal.clone(), external.clone()))
+                        }
+                        [rename, internal, external] if rename == "rename" => {
+                            export_names.push((internal.clone(), external.clone()))
+                        }
+                        _ => {
+                            return Err(RuntimeError::from(format!(
+                                "Expected a name or (rename internal external) in export, got {:?}",
+                                spec
+                            )))
+                        }
+                    }
+                }
+            }
+            Some(SchemeObject::Symbol(s)) if s == "import" => {
+                for set in forms {
+                    for (name, val) in import_set(set, &lib_env)? {
+                        lib_env.borrow_mut().set(name, val);
+                    }
+                }
+            }
+            Some(SchemeObject::Symbol(s)) if s == "begin" => {
+                for form in forms {
+                    form.exec(&lib_env)?;
+                }
+            }
+            _ => return Err(unknown()),
+        }
+    }
+
+    let mut exports = Exports::new();
+    for (internal, external) in export_names {
+        let val = lib_env.borrow().lookup(&internal).ok_or_else(|| {
+            RuntimeError::from(format!(
+                "Library {} exports {} but doesn't define it",
+                name, internal
+            ))
+        })?;
+        exports.insert(external, val);
+    }
+
+    libs.borrow_mut().loaded.insert(name, Rc::new(exports));
+    Ok(get_none())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use stdlib::{get_std_env, get_std_env_with, Capability, EnvOptions};
+
+    use std::env;
+    use std::process;
+
+    fn eval_in(env: &PackedEnv, program: &str) -> Result<Rc<SchemeObject>, RuntimeError> {
+        let mut res = Ok(Rc::new(SchemeObject::None));
+        for code in ast::ObjectIterator::from(program.chars()) {
+            res = code.unwrap().exec(env);
+        }
+        res
+    }
+
+    const POINTS: &str = "(define-library (points)
+           (export make-point (rename point-x x) point-y)
+           (import (scheme base))
+           (begin
+             (define make-point (lambda (x y) (list x y)))
+             (define point-x (lambda (p) (apply (lambda (x y) x) p)))
+             (define point-y (lambda (p) (apply (lambda (x y) y) p)))))";
+
+    #[test]
+    fn import_sets() {
+        let env = get_std_env();
+        eval_in(&env, POINTS).unwrap();
+
+        let res = eval_in(&env, "(import (points)) (x (make-point 1 2))").unwrap();
+        assert_eq!(*res, SchemeObject::Integer(1));
+        assert!(env.borrow().lookup("point-x").is_none());
+
+        let env = get_std_env();
+        eval_in(&env, POINTS).unwrap();
+        eval_in(&env, "(import (only (points) point-y))").unwrap();
+        assert!(env.borrow().lookup("point-y").is_some());
+        assert!(env.borrow().lookup("make-point").is_none());
+
+        let env = get_std_env();
+        eval_in(&env, POINTS).unwrap();
+        eval_in(&env, "(import (except (points) point-y))").unwrap();
+        assert!(env.borrow().lookup("point-y").is_none());
+        assert!(env.borrow().lookup("make-point").is_some());
+
+        let env = get_std_env();
+        eval_in(&env, POINTS).unwrap();
+        let program = "(import (rename (prefix (points) p:) (p:make-point point)))
+                       (p:point-y (point 1 2))";
+        let res = eval_in(&env, program).unwrap();
+        assert_eq!(*res, SchemeObject::Integer(2));
+        assert!(env.borrow().lookup("p:make-point").is_none());
+
+        let res = eval_in(&env, "(import (only (points) nothing))");
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn library_files() {
+        let dir = env::temp_dir().join(format!("rustyscheme-{}-libraries", process::id()));
+        fs::create_dir_all(dir.join("mylib")).unwrap();
+        fs::write(
+            dir.join("mylib").join("util.sld"),
+            "(define-library (mylib util)
+               (export double)
+               (import (scheme base))
+               (begin (define double (lambda (x) (list x x)))))",
+        )
+        .unwrap();
+
+        let env = get_std_env();
+        let libs = env.borrow().libraries().unwrap();
+        libs.borrow_mut().add_search_path(&dir);
+        assert!(!libs.borrow().is_loaded("(mylib util)"));
+
+        let program = "(import (prefix (mylib util) a:))
+                       (import (prefix (mylib util) b:))
+                       (a:double 1)";
+        let res = eval_in(&env, program);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(format!("{:?}", res.unwrap()), "(1 1)");
+
+        // the library was only loaded once
+        assert!(libs.borrow().is_loaded("(mylib util)"));
+        let a = env.borrow().lookup("a:double").unwrap();
+        let b = env.borrow().lookup("b:double").unwrap();
+        assert!(Rc::ptr_eq(&a, &b));
+    }
+
+    #[test]
+    fn missing() {
+        let res = eval_in(&get_std_env(), "(import (no such library))").unwrap_err();
+        assert_eq!(
+            *res.root(),
+            RuntimeError::from("Library (no such library) not found")
+        );
+
+        let env = get_std_env_with(&EnvOptions::sandbox(&[
+            Capability::Pure,
+            Capability::Io,
+            Capability::Process,
+        ]));
+        assert!(eval_in(&env, "(import (scheme write))").is_ok());
+        assert!(eval_in(&env, "(import (scheme file))").is_err());
+    }
+
+    #[test]
+    fn redefining_builtins() {
+        let lib = |name: &str| {
+            format!(
+                "(define-library {} (export delete-file) (begin (define (delete-file f) f)))",
+                name
+            )
+        };
+
+        let res = eval_in(&get_std_env(), &lib("(scheme write)")).unwrap_err();
+        assert_eq!(
+            *res.root(),
+            RuntimeError::from("Library (scheme write) is built in and can't be redefined")
+        );
+
+        let env = get_std_env_with(&EnvOptions::sandbox(&[Capability::Pure]));
+        let res = eval_in(&env, &lib("(scheme file)")).unwrap_err();
+        assert_eq!(
+            *res.root(),
+            RuntimeError::Forbidden(String::from("(scheme file)"))
+        );
+        // the forbidden library still can't be imported
+        let res = eval_in(&env, "(import (scheme file))").unwrap_err();
+        assert_eq!(
+            *res.root(),
+            RuntimeError::Forbidden(String::from("(scheme file)"))
+        );
+        assert!(eval_in(&env, &lib("(mylib file)")).is_ok());
+    }
+}
@@ -0,0 +1,356 @@
+//! Limits on how much work evaluating code can do
+//!
+//! An interpreter's limits are set on its global environment. They apply to each piece of code the
+//! embedder runs (e.g. each call to `SchemeObject::exec`), counting everything it does including
+//! the scheme code run by built-in procedures it calls. Once the outermost run finishes the counts
+//! start again, so hitting a limit doesn't stop the interpreter from being used afterwards.
+//! Runs also check the interpreter's `Interrupt` handle.
+
+use super::env::PackedEnv;
+use super::interrupt::Interrupt;
+use super::scm_obj::SchemeObject;
+use super::RuntimeError;
+
+use std::cell::RefCell;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// The most a run of code is allowed to do. `None` means there is no limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Limits {
+    /// Virtual machine instructions executed
+    pub steps: Option<u64>,
+    /// Bytes allocated for new objects over the whole run (estimated from the size of the objects
+    /// made). Memory freed during the run isn't given back, so this is a budget for allocation
+    /// rather than a limit on how much data is alive at once.
+    pub allocation: Option<usize>,
+    /// How deeply calls to scheme functions can nest: the number of frames waiting for a call to
+    /// return. A tail call replaces its caller's frame, so loops written as tail calls don't count
+    /// against this however long they run.
+    pub call_depth: Option<usize>,
+    /// Wall-clock time
+    pub time: Option<Duration>,
+}
+
+/// Which limit was hit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    /// `Limits::steps`
+    Steps,
+    /// `Limits::allocation`
+    Allocation,
+    /// `Limits::call_depth`
+    CallDepth,
+    /// `Limits::time`
+    Time,
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Limit::Steps => "step",
+            Limit::Allocation => "allocation",
+            Limit::CallDepth => "call depth",
+            Limit::Time => "time",
+        })
+    }
+}
+
+impl Limits {
+    /// Are there any limits to check?
+    fn any(&self) -> bool {
+        *self != Self::default()
+    }
+}
+
+/// How often (in steps) the clock is checked
+const CLOCK_INTERVAL: u64 = 1024;
+
+/// What the current run has used so far
+struct Budget {
+    limits: Limits,
+    deadline: Option<Instant>,
+    steps: u64,
+    allocated: usize,
+    depth: usize,
+    interrupt: Option<Interrupt>,
+    /// Runs in progress (code run by built-in procedures starts a run inside the outer one)
+    runs: usize,
+}
+
+thread_local! {
+    static BUDGET: RefCell<Option<Budget>> = const { RefCell::new(None) };
+}
+
+/// Counts what a run uses while it is alive. The budget is shared with any runs started inside it.
+pub(crate) struct Run {
+    /// Are there any limits to check? Saves looking at the budget when there aren't.
+    checked: bool,
+    interrupt: Option<Interrupt>,
+}
+
+impl Run {
+    /// Start running code in `env`, with the limits of its interpreter unless this is inside
+    /// another run.
+    pub(crate) fn start(env: &PackedEnv) -> Self {
+        BUDGET.with(|budget| {
+            let mut budget = budget.borrow_mut();
+            let budget = budget.get_or_insert_with(|| {
+                let (limits, interrupt) = match env.try_borrow() {
+                    Ok(env) => (env.limits(), env.interrupt()),
+                    Err(_) => (Limits::default(), None),
+                };
+                // interrupts from before the run started are for code which has finished
+                if let Some(ref interrupt) = interrupt {
+                    interrupt.take();
+                }
+                Budget {
+                    limits,
+                    deadline: limits.time.map(|t| Instant::now() + t),
+                    steps: 0,
+                    allocated: 0,
+                    depth: 0,
+                    interrupt,
+                    runs: 0,
+                }
+            });
+            budget.runs += 1;
+            Self {
+                checked: budget.limits.any(),
+                interrupt: budget.interrupt.clone(),
+            }
+        })
+    }
+
+    /// Count an instruction, stopping if the run was interrupted
+    pub(crate) fn step(&self) -> Result<(), RuntimeError> {
+        if let Some(ref interrupt) = self.interrupt {
+            if interrupt.take() {
+                return Err(RuntimeError::Interrupted);
+            }
+        }
+        if !self.checked {
+            return Ok(());
+        }
+
+        with_budget(|budget| {
+            budget.steps += 1;
+            if budget.limits.steps.is_some_and(|max| budget.steps > max) {
+                return Err(Limit::Steps.into());
+            }
+            if budget.steps % CLOCK_INTERVAL == 0
+                && budget.deadline.is_some_and(|d| Instant::now() >= d)
+            {
+                return Err(Limit::Time.into());
+            }
+            Ok(())
+        })
+    }
+
+    /// Count a new object against the allocation budget
+    pub(crate) fn allocated(&self, obj: &SchemeObject) -> Result<(), Limit> {
+        if !self.checked {
+            return Ok(());
+        }
+
+        with_budget(|budget| {
+            let max = match budget.limits.allocation {
+                Some(max) => max,
+                None => return Ok(()),
+            };
+            budget.allocated += size_within(obj, max.saturating_sub(budget.allocated));
+            if budget.allocated > max {
+                Err(Limit::Allocation)
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    /// Count frames of calls being entered (`calls` > 0) or returned from (`calls` < 0). Tail calls
+    /// reuse a frame so aren't counted.
+    pub(crate) fn calls(&self, calls: isize) -> Result<(), Limit> {
+        if !self.checked {
+            return Ok(());
+        }
+
+        with_budget(|budget| {
+            budget.depth = (budget.depth as isize + calls) as usize;
+            match budget.limits.call_depth {
+                Some(max) if budget.depth > max => Err(Limit::CallDepth),
+                _ => Ok(()),
+            }
+        })
+    }
+}
+
+impl Drop for Run {
+    fn drop(&mut self) {
+        BUDGET.with(|budget| {
+            let mut budget = budget.borrow_mut();
+            let finished = match *budget {
+                Some(ref mut b) => {
+                    b.runs -= 1;
+                    b.runs == 0
+                }
+                None => false,
+            };
+            if finished {
+                *budget = None;
+            }
+        })
+    }
+}
+
+fn with_budget<T>(f: impl FnOnce(&mut Budget) -> T) -> T {
+    BUDGET.with(|budget| f(budget.borrow_mut().as_mut().expect("no run in progress")))
+}
+
+/// Estimate how many bytes `obj` takes up, giving up once the estimate passes `max`
+/// Lists are values, so a new list holds its own copy of every list inside it and the estimate has
+/// to look at all of them. Stopping at `max` keeps one huge object from being walked in full just
+/// to find that it is over the budget.
+fn size_within(obj: &SchemeObject, max: usize) -> usize {
+    let mut total = 0;
+    add_size(obj, &mut total, max);
+    total
+}
+
+/// Helper for `size_within`
+fn add_size(obj: &SchemeObject, total: &mut usize, max: usize) {
+    *total += size_of::<SchemeObject>();
+    match obj {
+        SchemeObject::String(s) => *total += s.len(),
+        SchemeObject::Bytevector(b) => *total += b.len(),
+        SchemeObject::Record(r) => *total += r.fields().len() * size_of::<SchemeObject>(),
+        SchemeObject::List(l, _) => add_sizes(l.iter(), total, max),
+        SchemeObject::Vector(v) => add_sizes(v.iter(), total, max),
+        SchemeObject::Quoted(o) => add_size(o, total, max),
+        SchemeObject::Values(v) => add_sizes(v.iter().map(|val| &**val), total, max),
+        _ => (),
+    }
+}
+
+/// Helper for `add_size`: the children of an object, until the estimate passes `max`
+fn add_sizes<'a>(objs: impl Iterator<Item = &'a SchemeObject>, total: &mut usize, max: usize) {
+    for obj in objs {
+        if *total > max {
+            return;
+        }
+        add_size(obj, total, max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast;
+    use data::env::PackedEnv;
+    use stdlib::get_std_env;
+
+    use std::collections::LinkedList;
+    use std::rc::Rc;
+
+    fn eval(env: &PackedEnv, program: &str) -> Result<Rc<SchemeObject>, RuntimeError> {
+        let mut res = Ok(Rc::new(SchemeObject::None));
+        for code in ast::ObjectIterator::from(program.chars()) {
+            res = code.unwrap().exec(env);
+        }
+        res
+    }
+
+    fn limited(limits: Limits) -> PackedEnv {
+        let env = get_std_env();
+        env.borrow_mut().set_limits(limits);
+        env
+    }
+
+    #[test]
+    fn steps() {
+        let env = limited(Limits {
+            steps: Some(10_000),
+            ..Limits::default()
+        });
+
+        let err = eval(&env, "(define (loop) (loop)) (loop)").unwrap_err();
+        assert_eq!(err.root(), &RuntimeError::Limit(Limit::Steps));
+        // the interpreter can still be used
+        assert_eq!(*eval(&env, "\"ok\"").unwrap(), SchemeObject::from("ok"));
+    }
+
+    #[test]
+    fn call_depth() {
+        let env = limited(Limits {
+            call_depth: Some(100),
+            ..Limits::default()
+        });
+
+        let err = eval(&env, "(define (deep n) (list (deep n))) (deep 1)").unwrap_err();
+        assert_eq!(err.root(), &RuntimeError::Limit(Limit::CallDepth));
+        eval(&env, "(define (f n) n) (f 1)").unwrap();
+    }
+
+    #[test]
+    fn tail_calls_within_call_depth() {
+        let env = limited(Limits {
+            call_depth: Some(1000),
+            ..Limits::default()
+        });
+
+        let program = "(define (count n) (if (= n 0) \"done\" (count (- n 1))))
+                       (count 5000)";
+        assert_eq!(*eval(&env, program).unwrap(), SchemeObject::from("done"));
+
+        // a call which isn't the last thing the function does still needs a frame
+        let program = "(define (count n) (if (= n 0) \"done\" (list (count (- n 1)))))
+                       (count 5000)";
+        let err = eval(&env, program).unwrap_err();
+        assert_eq!(err.root(), &RuntimeError::Limit(Limit::CallDepth));
+    }
+
+    #[test]
+    fn allocation() {
+        let env = limited(Limits {
+            allocation: Some(100_000),
+            ..Limits::default()
+        });
+
+        let program = "(define (grow l) (grow (list l l))) (grow \"a\")";
+        let err = eval(&env, program).unwrap_err();
+        assert_eq!(err.root(), &RuntimeError::Limit(Limit::Allocation));
+    }
+
+    #[test]
+    fn size_stops_at_budget() {
+        let item = SchemeObject::from("an item");
+        let lst = SchemeObject::from((0..1000).map(|_| item.clone()).collect::<LinkedList<_>>());
+
+        let full = size_within(&lst, usize::MAX);
+        assert!(full > 1000 * size_of::<SchemeObject>());
+        let partial = size_within(&lst, 1000);
+        assert!(partial > 1000 && partial < 1000 + 2 * size_within(&item, usize::MAX));
+    }
+
+    #[test]
+    fn time() {
+        let env = limited(Limits {
+            time: Some(Duration::from_millis(50)),
+            ..Limits::default()
+        });
+
+        let err = eval(&env, "(define (loop) (loop)) (loop)").unwrap_err();
+        assert_eq!(err.root(), &RuntimeError::Limit(Limit::Time));
+    }
+
+    #[test]
+    fn not_caught_by_guard() {
+        let env = limited(Limits {
+            steps: Some(10_000),
+            ..Limits::default()
+        });
+
+        let program = "(define (loop) (loop)) (guard (e (#t \"caught\")) (loop))";
+        let err = eval(&env, program).unwrap_err();
+        assert_eq!(err.root(), &RuntimeError::Limit(Limit::Steps));
+    }
+}
@@ -9,20 +9,227 @@ mod exec;
 // evaluate a scheme object with arguments (used in ::exec)
 mod exec_args;
 
+// compile scheme code to bytecode
+pub mod compile;
+
+// run bytecode
+mod vm;
+
+// opaque host data
+mod foreign;
+
+// backtraces for runtime errors
+mod backtrace;
+
 // environment variable storage and lookup
 pub mod env;
+
+// define-library and import
+pub mod library;
+
+// derived forms
+pub mod expand;
+
+// descriptions of names for the REPL
+pub mod help;
+
+// arity, name, documentation and source of functions
+mod procedure;
+
+// input and output ports
+pub mod port;
+
+// delay, delay-force and force
+mod promise;
+
+// define-record-type
+mod record;
+
+// display, write and friends
+pub mod print;
+
+// collecting reference cycles
+pub mod gc;
+
+// stopping evaluation from outside
+pub mod interrupt;
+
+// limits on the work code can do
+pub mod limits;
+
+// interned symbols
+mod symbol;
+
 // re-export
-pub use self::scm_obj::{SchemeObject, SymFrom};
+pub use self::backtrace::{Backtrace, Frame};
+pub use self::exec::SPECIAL_FORMS;
+pub use self::foreign::{DisplayHook, ForeignObject};
+pub use self::port::Port;
+pub use self::promise::Promise;
+pub use self::record::{Record, RecordType};
+pub use self::scm_obj::{Arity, BuiltinDoc, BuiltinFn, SchemeObject, SymFrom};
+pub use self::symbol::Sym;
+pub use self::vm::Closure;
 
+use span::{self, Span};
+use std::rc::Rc;
 use std::string::ToString;
 
-/// Possible parse errors
-#[derive(Debug, PartialEq)]
+/// Possible runtime errors
+#[derive(Debug, PartialEq, Clone)]
 pub enum RuntimeError {
     /// Misc. runtime error
     Msg(String),
     /// Name lookup error (reporting the name of the unbound variable)
     NameLookup(String),
+    /// Failure to open, create or delete a file
+    File(String),
+    /// An object raised by scheme code which isn't an error (see `raise`)
+    Raised(Rc<SchemeObject>),
+    /// Evaluation was stopped because it went over one of the interpreter's limits
+    /// These can't be caught by guard.
+    Limit(limits::Limit),
+    /// Evaluation was stopped by an `Interrupt` (e.g. Ctrl-C in the REPL)
+    Interrupted,
+    /// Something the environment isn't allowed to do (e.g. import a library it wasn't given)
+    Forbidden(String),
+    /// The program called `exit` with this status. Embedders decide what to do about it: it can't
+    /// be caught by guard.
+    Exit(i32),
+    /// Another error along with the location of the innermost form which raised it
+    Located(Span, Box<RuntimeError>),
+    /// Another error along with the function calls it passed through
+    Traced(Backtrace, Box<RuntimeError>),
+    /// An error raised by code in a file run by `load`. The locations in the error it wraps are in
+    /// that file, rather than the code which called load.
+    Loaded(Rc<LoadedFile>, Box<RuntimeError>),
+}
+
+/// The name and source of a file run by `load`, for reporting errors in it
+#[derive(Debug, PartialEq)]
+pub struct LoadedFile {
+    /// The name load was given
+    pub name: String,
+    /// The contents of the file
+    pub source: String,
+}
+
+impl RuntimeError {
+    /// Attach a location to the error unless it already has one
+    pub fn at(self, span: Span) -> Self {
+        use self::RuntimeError::*;
+
+        match self {
+            Located(_, _) => self,
+            Traced(bt, e) => Traced(bt, Box::new(e.at(span))),
+            e => Located(span, Box::new(e)),
+        }
+    }
+
+    /// Record that the error passed out of a function call
+    pub fn in_frame(self, frame: Frame) -> Self {
+        match self {
+            RuntimeError::Traced(mut bt, e) => {
+                bt.push(frame);
+                RuntimeError::Traced(bt, e)
+            }
+            e => {
+                let mut bt = Backtrace::new();
+                bt.push(frame);
+                RuntimeError::Traced(bt, Box::new(e))
+            }
+        }
+    }
+
+    /// Record where the outermost function call so far was made from
+    /// Called by each form the error passes through: only the form which made the call will find
+    /// a frame with no call site.
+    pub fn called_from(mut self, span: Span) -> Self {
+        if let RuntimeError::Traced(ref mut bt, _) = self {
+            if let Some(frame) = bt.outermost_mut() {
+                if frame.call_site.is_none() {
+                    frame.call_site = Some(span);
+                }
+            }
+        }
+
+        self
+    }
+
+    /// Where the error happened, if known
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            RuntimeError::Located(span, _) => Some(*span),
+            RuntimeError::Traced(_, e) => e.span(),
+            _ => None,
+        }
+    }
+
+    /// The function calls the error passed through, if any
+    pub fn backtrace(&self) -> Option<&Backtrace> {
+        match self {
+            RuntimeError::Traced(bt, _) => Some(bt),
+            _ => None,
+        }
+    }
+
+    /// The file the error came from and the error as it was raised there, if it came from a file
+    /// run by `load`. For nested loads this is the outermost loaded file.
+    pub fn loaded(&self) -> Option<(&LoadedFile, &Self)> {
+        match self {
+            RuntimeError::Loaded(file, e) => Some((file, e)),
+            RuntimeError::Located(_, e) | RuntimeError::Traced(_, e) => e.loaded(),
+            _ => None,
+        }
+    }
+
+    /// Format the error for the user along with its backtrace. Locations are shown in `source`,
+    /// the code of `file`, except for those in files the code loaded, which are shown in those
+    /// files (followed by where they were loaded from).
+    pub fn report(&self, file: &str, source: &str) -> String {
+        let mut out = match self.loaded() {
+            Some((loaded, e)) => {
+                let mut out = e.report(&loaded.name, &loaded.source);
+                if let Some(span) = self.span() {
+                    out += &format!("\nLoaded from {}:{}", file, span);
+                }
+                out
+            }
+            None => match self.span() {
+                Some(span) => span::report(file, source, span, &self.to_string()),
+                None => self.to_string(),
+            },
+        };
+
+        if let Some(bt) = self.backtrace() {
+            out += "\n";
+            out += &bt.render(file);
+        }
+
+        out
+    }
+
+    /// Can guard catch the error? Not if it is meant to stop evaluation altogether.
+    pub fn catchable(&self) -> bool {
+        !matches!(self.root(), RuntimeError::Limit(_) | RuntimeError::Exit(_))
+    }
+
+    /// The error without any location or backtrace information
+    pub fn root(&self) -> &Self {
+        match self {
+            RuntimeError::Located(_, e)
+            | RuntimeError::Traced(_, e)
+            | RuntimeError::Loaded(_, e) => e.root(),
+            e => e,
+        }
+    }
+}
+
+/// Creates a `RuntimeError::Limit`
+impl From<limits::Limit> for RuntimeError {
+    fn from(limit: limits::Limit) -> Self {
+        RuntimeError::Limit(limit)
+    }
 }
 
 /// Creates a `RuntimeError::Msg`
@@ -45,23 +252,18 @@ impl ToString for RuntimeError {
         use self::RuntimeError::*;
 
         match self {
-            Msg(s) => s.clone(),
-            NameLookup(s) => {
-                // catch special forms baked into exec and provide usage hints
-                match s.as_str() {
-                    "define" => String::from("Built-in: define: (define name value) | (define (function_name arg) (body arg)"),
-
-                    "let" => String::from(
-"Built-in: let: (let ((name1 value1)
-                     (name2 value2))
-                    (body name1 name2))"),
-
-                    "lambda" => String::from("Built-in: lambda: (lambda (arg1 arg2) (body arg1 arg2))"),
-                    "if" => String::from("Built-in: if: (if cond true_body false_body)"),
-
-                    _ => format!("Undefined binding: {}", s),
-                }
-            }
+            Msg(s) | File(s) => s.clone(),
+            Raised(obj) => format!("Uncaught exception: {:?}", obj),
+            Limit(limit) => format!("Evaluation stopped: {} limit exceeded", limit),
+            Forbidden(s) => format!("Not allowed in this environment: {}", s),
+            Interrupted => String::from("Interrupted"),
+            Exit(status) => format!("Exit with status {}", status),
+            Located(_, e) | Traced(_, e) | Loaded(_, e) => e.to_string(),
+            // catch special forms baked into exec and provide usage hints
+            NameLookup(s) => match help::special_form_usage(s) {
+                Some(usage) => format!("Built-in: {}: {}", s, usage),
+                None => format!("Undefined binding: {}", s),
+            },
         }
     }
 }
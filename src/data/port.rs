@@ -0,0 +1,601 @@
+//! Ports: the source and destination of scheme input and output
+
+use std::cell::RefCell;
+use std::cmp::PartialEq;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::rc::Rc;
+
+/// Where an input port gets its characters from
+enum Source {
+    /// Characters which have already been decoded (e.g. from a string)
+    Chars(VecDeque<char>),
+    /// UTF-8 encoded bytes
+    Reader {
+        reader: Box<dyn BufRead>,
+        /// characters which have been peeked but not read
+        peeked: VecDeque<char>,
+        /// can we block waiting for input? (see `char_ready`)
+        interactive: bool,
+    },
+    /// Raw bytes for a binary port
+    Bytes {
+        reader: Box<dyn BufRead>,
+        /// a byte which has been peeked but not read
+        peeked: Option<u8>,
+    },
+}
+
+/// Where an output port sends its characters
+enum Sink {
+    /// Collect the output in memory (for `get-output-string`)
+    String(String),
+    /// Collect binary output in memory (for `get-output-bytevector`)
+    Bytes(Vec<u8>),
+    /// Any rust writer
+    Writer(Box<dyn Write>),
+}
+
+/// The state behind a `Port`
+enum PortState {
+    Input(Source),
+    Output(Sink),
+    Closed,
+}
+
+/// A scheme port. Clones refer to the same port.
+/// Ports are either textual (reading and writing characters) or binary (reading and writing bytes).
+#[derive(Clone)]
+pub struct Port {
+    state: Rc<RefCell<PortState>>,
+    /// was this created as an input port (still known after the port is closed)
+    input: bool,
+    /// is this a binary port?
+    binary: bool,
+}
+
+impl Port {
+    fn new(state: PortState, binary: bool) -> Self {
+        let input = matches!(state, PortState::Input(_));
+
+        Self {
+            state: Rc::new(RefCell::new(state)),
+            input,
+            binary,
+        }
+    }
+
+    /// An input port which reads UTF-8 text from `reader`
+    pub fn input<R: Read + 'static>(reader: R) -> Self {
+        Self::input_buffered(BufReader::new(reader))
+    }
+
+    /// An input port which reads UTF-8 text from an already buffered `reader`
+    pub fn input_buffered<R: BufRead + 'static>(reader: R) -> Self {
+        Self::new(
+            PortState::Input(Source::Reader {
+                reader: Box::new(reader),
+                peeked: VecDeque::new(),
+                interactive: false,
+            }),
+            false,
+        )
+    }
+
+    /// An input port which reads from a terminal or other source which might not have input
+    /// ready: `char_ready` won't claim that input is available until it has been peeked
+    pub fn interactive_input<R: BufRead + 'static>(reader: R) -> Self {
+        Self::new(
+            PortState::Input(Source::Reader {
+                reader: Box::new(reader),
+                peeked: VecDeque::new(),
+                interactive: true,
+            }),
+            false,
+        )
+    }
+
+    /// An input port reading the characters of `s`
+    pub fn input_string(s: &str) -> Self {
+        Self::new(PortState::Input(Source::Chars(s.chars().collect())), false)
+    }
+
+    /// An output port which writes UTF-8 text to `writer`
+    pub fn output<W: Write + 'static>(writer: W) -> Self {
+        Self::new(PortState::Output(Sink::Writer(Box::new(writer))), false)
+    }
+
+    /// An output port which collects its output into a string (see `output_string`)
+    pub fn output_string() -> Self {
+        Self::new(PortState::Output(Sink::String(String::new())), false)
+    }
+
+    /// A binary input port reading bytes from `reader`
+    pub fn binary_input<R: Read + 'static>(reader: R) -> Self {
+        Self::new(
+            PortState::Input(Source::Bytes {
+                reader: Box::new(BufReader::new(reader)),
+                peeked: None,
+            }),
+            true,
+        )
+    }
+
+    /// A binary input port reading the bytes in `bytes`
+    pub fn input_bytevector(bytes: Vec<u8>) -> Self {
+        Self::binary_input(io::Cursor::new(bytes))
+    }
+
+    /// A binary output port writing bytes to `writer`
+    pub fn binary_output<W: Write + 'static>(writer: W) -> Self {
+        Self::new(PortState::Output(Sink::Writer(Box::new(writer))), true)
+    }
+
+    /// A binary output port which collects its output (see `output_bytevector_contents`)
+    pub fn output_bytevector() -> Self {
+        Self::new(PortState::Output(Sink::Bytes(Vec::new())), true)
+    }
+
+    /// Is this an input port?
+    pub fn is_input(&self) -> bool {
+        self.input
+    }
+
+    /// Is this an output port?
+    pub fn is_output(&self) -> bool {
+        !self.input
+    }
+
+    /// Is this a binary port?
+    pub fn is_binary(&self) -> bool {
+        self.binary
+    }
+
+    /// Is this a textual port?
+    pub fn is_textual(&self) -> bool {
+        !self.binary
+    }
+
+    /// Has the port been closed?
+    pub fn is_closed(&self) -> bool {
+        matches!(*self.state.borrow(), PortState::Closed)
+    }
+
+    /// Close the port. Output is flushed first.
+    pub fn close(&self) -> io::Result<()> {
+        let res = self.flush();
+        *self.state.borrow_mut() = PortState::Closed;
+        res
+    }
+
+    /// The output collected so far by a port created with `output_string`
+    pub fn output_string_contents(&self) -> Option<String> {
+        match *self.state.borrow() {
+            PortState::Output(Sink::String(ref s)) => Some(s.clone()),
+            _ => None,
+        }
+    }
+
+    /// The output collected so far by a port created with `output_bytevector`
+    pub fn output_bytevector_contents(&self) -> Option<Vec<u8>> {
+        match *self.state.borrow() {
+            PortState::Output(Sink::Bytes(ref b)) => Some(b.clone()),
+            _ => None,
+        }
+    }
+
+    /// Write a string to a textual output port
+    pub fn write_str(&self, s: &str) -> io::Result<()> {
+        if self.binary {
+            return Err(not_open("textual output"));
+        }
+
+        match *self.state.borrow_mut() {
+            PortState::Output(Sink::String(ref mut out)) => {
+                out.push_str(s);
+                Ok(())
+            }
+            PortState::Output(Sink::Writer(ref mut w)) => w.write_all(s.as_bytes()),
+            _ => Err(not_open("textual output")),
+        }
+    }
+
+    /// Write bytes to a binary output port
+    pub fn write_bytes(&self, bytes: &[u8]) -> io::Result<()> {
+        if !self.binary {
+            return Err(not_open("binary output"));
+        }
+
+        match *self.state.borrow_mut() {
+            PortState::Output(Sink::Bytes(ref mut out)) => {
+                out.extend_from_slice(bytes);
+                Ok(())
+            }
+            PortState::Output(Sink::Writer(ref mut w)) => w.write_all(bytes),
+            _ => Err(not_open("binary output")),
+        }
+    }
+
+    /// Read the next byte from a binary input port. `None` at the end of the input.
+    pub fn read_u8(&self) -> io::Result<Option<u8>> {
+        let b = self.peek_u8()?;
+        if let PortState::Input(Source::Bytes { ref mut peeked, .. }) = *self.state.borrow_mut() {
+            *peeked = None;
+        }
+        Ok(b)
+    }
+
+    /// Look at the next byte from a binary input port without consuming it
+    pub fn peek_u8(&self) -> io::Result<Option<u8>> {
+        match *self.state.borrow_mut() {
+            PortState::Input(Source::Bytes {
+                ref mut reader,
+                ref mut peeked,
+            }) => {
+                if peeked.is_none() {
+                    let mut buf = [0];
+                    if reader.read(&mut buf)? == 1 {
+                        *peeked = Some(buf[0]);
+                    }
+                }
+                Ok(*peeked)
+            }
+            _ => Err(not_open("binary input")),
+        }
+    }
+
+    /// Read up to `k` bytes from a binary input port. `None` at the end of the input.
+    pub fn read_bytes(&self, k: usize) -> io::Result<Option<Vec<u8>>> {
+        let mut out = Vec::with_capacity(k);
+        while out.len() < k {
+            match self.read_u8()? {
+                Some(b) => out.push(b),
+                None => break,
+            }
+        }
+
+        if out.is_empty() && k > 0 {
+            Ok(None)
+        } else {
+            Ok(Some(out))
+        }
+    }
+
+    /// Flush any buffered output
+    pub fn flush(&self) -> io::Result<()> {
+        match *self.state.borrow_mut() {
+            PortState::Output(Sink::Writer(ref mut w)) => w.flush(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Read the next character. `None` at the end of the input.
+    pub fn read_char(&self) -> io::Result<Option<char>> {
+        let c = self.peek_char()?;
+        if let PortState::Input(ref mut source) = *self.state.borrow_mut() {
+            match source {
+                Source::Chars(chars) => chars.pop_front(),
+                Source::Reader { peeked, .. } => peeked.pop_front(),
+                Source::Bytes { .. } => None,
+            };
+        }
+        Ok(c)
+    }
+
+    /// Look at the next character without consuming it. `None` at the end of the input.
+    pub fn peek_char(&self) -> io::Result<Option<char>> {
+        match *self.state.borrow_mut() {
+            PortState::Input(Source::Chars(ref chars)) => Ok(chars.front().cloned()),
+            PortState::Input(Source::Reader {
+                ref mut reader,
+                ref mut peeked,
+                ..
+            }) => {
+                if peeked.is_empty() {
+                    if let Some(c) = decode_char(reader.as_mut())? {
+                        peeked.push_back(c);
+                    }
+                }
+                Ok(peeked.front().cloned())
+            }
+            _ => Err(not_open("textual input")),
+        }
+    }
+
+    /// Put `c` back so that it is the next character read
+    pub fn unread_char(&self, c: char) -> io::Result<()> {
+        match *self.state.borrow_mut() {
+            PortState::Input(Source::Chars(ref mut chars)) => chars.push_front(c),
+            PortState::Input(Source::Reader { ref mut peeked, .. }) => peeked.push_front(c),
+            _ => return Err(not_open("textual input")),
+        }
+        Ok(())
+    }
+
+    /// Iterate over the characters of the port, reading them one at a time as they are needed
+    pub fn chars(&self) -> Chars {
+        Chars {
+            port: self.clone(),
+            error: None,
+        }
+    }
+
+    /// Read up to the end of the line. The newline is consumed but not returned.
+    /// `None` if we were already at the end of the input.
+    pub fn read_line(&self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+
+        loop {
+            match self.read_char()? {
+                Some('\n') => return Ok(Some(line)),
+                Some(c) => line.push(c),
+                None if line.is_empty() => return Ok(None),
+                None => return Ok(Some(line)),
+            }
+        }
+    }
+
+    /// Can a character be read without blocking?
+    pub fn char_ready(&self) -> io::Result<bool> {
+        match *self.state.borrow() {
+            PortState::Input(Source::Reader {
+                ref peeked,
+                interactive: true,
+                ..
+            }) => Ok(!peeked.is_empty()),
+            PortState::Input(Source::Bytes { .. }) => Err(not_open("textual input")),
+            PortState::Input(_) => Ok(true),
+            _ => Err(not_open("textual input")),
+        }
+    }
+
+    /// Can a byte be read without blocking?
+    pub fn u8_ready(&self) -> io::Result<bool> {
+        match *self.state.borrow() {
+            PortState::Input(Source::Bytes { .. }) => Ok(true),
+            _ => Err(not_open("binary input")),
+        }
+    }
+}
+
+/// Iterator over the characters read from a port (see `Port::chars`)
+/// Iteration stops at the end of the input or at the first error
+pub struct Chars {
+    port: Port,
+    error: Option<io::Error>,
+}
+
+impl Chars {
+    /// The error which stopped the iteration, if there was one
+    pub fn take_error(&mut self) -> Option<io::Error> {
+        self.error.take()
+    }
+}
+
+impl Iterator for Chars {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        match self.port.read_char() {
+            Ok(c) => c,
+            Err(e) => {
+                self.error = Some(e);
+                None
+            }
+        }
+    }
+}
+
+/// Error for using a port the wrong way
+fn not_open(direction: &str) -> io::Error {
+    io::Error::other(format!("Not an open {} port", direction))
+}
+
+/// Read one UTF-8 encoded character
+fn decode_char(reader: &mut dyn BufRead) -> io::Result<Option<char>> {
+    let mut buf = [0; 4];
+
+    if reader.read(&mut buf[..1])? == 0 {
+        return Ok(None);
+    }
+
+    // the number of leading ones in the first byte tells us how long the character is
+    let len = match buf[0].leading_ones() {
+        0 => 1,
+        n @ 2..=4 => n as usize,
+        _ => return Err(invalid_utf8()),
+    };
+    reader.read_exact(&mut buf[1..len])?;
+
+    match ::std::str::from_utf8(&buf[..len]) {
+        Ok(s) => Ok(s.chars().next()),
+        Err(_) => Err(invalid_utf8()),
+    }
+}
+
+fn invalid_utf8() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Invalid UTF-8 in input")
+}
+
+/// Ports are only equal to themselves
+impl PartialEq for Port {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.state, &other.state)
+    }
+}
+
+impl fmt::Display for Port {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = if self.binary { "binary" } else { "textual" };
+        let direction = if self.input { "input" } else { "output" };
+        write!(f, "#<{}-{}-port>", kind, direction)
+    }
+}
+
+impl fmt::Debug for Port {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+/// The ports used when a procedure isn't given one explicitly
+/// Each thread has its own, and an environment can have others instead (see
+/// `Environment::set_ports`).
+#[derive(Debug, Clone, PartialEq)]
+pub struct CurrentPorts {
+    /// Read from by read-char, read and the like
+    pub input: Port,
+    /// Written to by display, write and the like
+    pub output: Port,
+    /// Returned by current-error-port
+    pub error: Port,
+}
+
+impl CurrentPorts {
+    /// Ports which are always at the end of the input and throw away what is written to them
+    pub fn null() -> Self {
+        Self {
+            input: Port::input(io::empty()),
+            output: Port::output(io::sink()),
+            error: Port::output(io::sink()),
+        }
+    }
+}
+
+thread_local! {
+    static CURRENT: RefCell<CurrentPorts> = RefCell::new(CurrentPorts {
+        input: Port::interactive_input(BufReader::new(io::stdin())),
+        output: Port::output(io::stdout()),
+        error: Port::output(io::stderr()),
+    });
+}
+
+/// Generates a getter and setter for one of the current ports
+macro_rules! current_port {
+    ($get:ident, $set:ident, $field:ident, $desc:expr) => {
+        #[doc = "The current "]
+        #[doc = $desc]
+        #[doc = " port for this thread"]
+        pub fn $get() -> Port {
+            CURRENT.with(|c| c.borrow().$field.clone())
+        }
+
+        #[doc = "Replace the current "]
+        #[doc = $desc]
+        #[doc = " port for this thread, returning the old one"]
+        pub fn $set(port: Port) -> Port {
+            CURRENT.with(|c| ::std::mem::replace(&mut c.borrow_mut().$field, port))
+        }
+    };
+}
+
+current_port!(current_input, set_current_input, input, "input");
+current_port!(current_output, set_current_output, output, "output");
+current_port!(current_error, set_current_error, error, "error");
+
+/// All of the current ports for this thread
+pub fn current_ports() -> CurrentPorts {
+    CURRENT.with(|c| c.borrow().clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn string_input() {
+        let port = Port::input_string("ab\ncd");
+
+        assert_eq!(port.peek_char().unwrap(), Some('a'));
+        assert_eq!(port.read_char().unwrap(), Some('a'));
+        assert_eq!(port.read_line().unwrap(), Some(String::from("b")));
+        assert_eq!(port.read_line().unwrap(), Some(String::from("cd")));
+        assert_eq!(port.read_line().unwrap(), None);
+        assert_eq!(port.read_char().unwrap(), None);
+    }
+
+    #[test]
+    fn chars() {
+        let port = Port::input_string("abc");
+
+        let first: String = port.chars().take(2).collect();
+        port.unread_char('b').unwrap();
+        let rest: String = port.chars().collect();
+        assert_eq!((first.as_str(), rest.as_str()), ("ab", "bc"));
+
+        let mut chars = Port::output_string().chars();
+        assert_eq!(chars.next(), None);
+        assert!(chars.take_error().is_some());
+    }
+
+    #[test]
+    fn reader_input() {
+        let port = Port::input(Cursor::new("λx".as_bytes().to_vec()));
+
+        assert!(port.char_ready().unwrap());
+        assert_eq!(port.peek_char().unwrap(), Some('λ'));
+        assert_eq!(port.read_char().unwrap(), Some('λ'));
+        assert_eq!(port.read_char().unwrap(), Some('x'));
+        assert_eq!(port.peek_char().unwrap(), None);
+    }
+
+    #[test]
+    fn interactive_input() {
+        let port = Port::interactive_input(Cursor::new(b"x".to_vec()));
+
+        assert!(!port.char_ready().unwrap());
+        port.peek_char().unwrap();
+        assert!(port.char_ready().unwrap());
+    }
+
+    #[test]
+    fn string_output() {
+        let port = Port::output_string();
+        port.write_str("hello ").unwrap();
+        port.write_str("world").unwrap();
+
+        assert_eq!(
+            port.output_string_contents(),
+            Some(String::from("hello world"))
+        );
+        assert!(port.read_char().is_err());
+    }
+
+    #[test]
+    fn binary() {
+        let input = Port::input_bytevector(vec![1, 2, 3]);
+        assert_eq!(input.peek_u8().unwrap(), Some(1));
+        assert_eq!(input.read_u8().unwrap(), Some(1));
+        assert_eq!(input.read_bytes(5).unwrap(), Some(vec![2, 3]));
+        assert_eq!(input.read_bytes(5).unwrap(), None);
+        assert!(input.read_char().is_err());
+
+        let output = Port::output_bytevector();
+        output.write_bytes(&[4, 5]).unwrap();
+        assert_eq!(output.output_bytevector_contents(), Some(vec![4, 5]));
+        assert!(output.write_str("x").is_err());
+    }
+
+    #[test]
+    fn closed() {
+        let port = Port::output_string();
+        port.close().unwrap();
+
+        assert!(port.is_closed());
+        assert!(port.is_output());
+        assert!(port.write_str("x").is_err());
+    }
+
+    #[test]
+    fn current() {
+        let port = Port::output_string();
+        let old = set_current_output(port.clone());
+
+        assert_eq!(current_output(), port);
+        set_current_output(old);
+        assert_ne!(current_output(), port);
+    }
+}
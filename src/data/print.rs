@@ -0,0 +1,338 @@
+//! Printing scheme objects for `display`, `write`, `write-shared` and `write-simple`
+
+use super::scm_obj::SchemeObject;
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// How objects are printed
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Style {
+    /// For people: strings and characters are printed as their contents
+    Display,
+    /// Can be read back by `read`. Cycles are marked with datum labels e.g.
+    /// #0=#<point x: #0# y: 2>
+    Write,
+    /// Like `Write` but every object printed more than once gets a datum label, not just cycles
+    /// Only records and foreign objects have an identity to share. Lists, vectors and strings are
+    /// values, so a list used twice is printed twice.
+    WriteShared,
+    /// Like `Write` without datum labels. Only use this for data known not to be cyclic.
+    WriteSimple,
+}
+
+/// Print `obj` in the chosen style
+pub fn print(obj: &SchemeObject, style: Style) -> String {
+    let mut printer = Printer {
+        style,
+        labels: HashMap::new(),
+        next_label: 0,
+        out: String::new(),
+    };
+
+    if style != Style::WriteSimple {
+        let mut visits = Visits::default();
+        visits.count(obj);
+        printer.labels = visits.labelled(style == Style::WriteShared);
+    }
+
+    printer.object(obj);
+    printer.out
+}
+
+/// Objects which can be shared or can contain themselves have an identity: the address of their
+/// shared contents. Lists and vectors own their elements, so they can't.
+fn identity(obj: &SchemeObject) -> Option<usize> {
+    match obj {
+        SchemeObject::Foreign(ref f) => Some(f.address()),
+        SchemeObject::Record(ref r) => Some(Rc::as_ptr(r) as usize),
+        _ => None,
+    }
+}
+
+/// Call `f` on the objects contained by `obj` which are printed as part of it
+fn each_child<F: FnMut(&SchemeObject)>(obj: &SchemeObject, mut f: F) {
+    use self::SchemeObject::*;
+
+    match obj {
+        List(ref lst, _) => lst.iter().for_each(f),
+        Vector(ref v) => v.iter().for_each(f),
+        Quoted(ref inner) => f(inner),
+        Record(ref r) => r.fields().iter().for_each(|(_, val)| f(val)),
+        Values(ref vals) => vals.iter().for_each(|val| f(val)),
+        _ => (),
+    }
+}
+
+/// First pass over the object: find objects with an identity which are printed more than once
+/// (shared) or which contain themselves (cycles)
+#[derive(Default)]
+struct Visits {
+    /// how many times each object has been reached
+    seen: HashMap<usize, usize>,
+    /// objects we are part way through
+    in_progress: Vec<usize>,
+    /// objects which contain themselves
+    cycles: Vec<usize>,
+    /// identities in the order they were first reached (so labels count up through the output)
+    order: Vec<usize>,
+}
+
+impl Visits {
+    fn count(&mut self, obj: &SchemeObject) {
+        let id = identity(obj);
+
+        if let Some(id) = id {
+            if self.in_progress.contains(&id) {
+                self.cycles.push(id);
+            }
+
+            let seen = self.seen.entry(id).or_insert(0);
+            *seen += 1;
+            if *seen > 1 {
+                // don't walk into objects twice: this is what stops cycles looping forever
+                return;
+            }
+
+            self.order.push(id);
+            self.in_progress.push(id);
+        }
+
+        each_child(obj, |child| self.count(child));
+
+        if id.is_some() {
+            self.in_progress.pop();
+        }
+    }
+
+    /// The objects which need a datum label
+    fn labelled(self, shared: bool) -> HashMap<usize, Option<usize>> {
+        let seen = self.seen;
+        let cycles = self.cycles;
+
+        self.order
+            .into_iter()
+            .filter(|id| cycles.contains(id) || (shared && seen[id] > 1))
+            .map(|id| (id, None))
+            .collect()
+    }
+}
+
+/// Second pass: write out the object
+struct Printer {
+    style: Style,
+    /// objects needing a label and the label once it has been printed
+    labels: HashMap<usize, Option<usize>>,
+    next_label: usize,
+    out: String,
+}
+
+impl Printer {
+    /// Print the datum label for `obj` if it needs one
+    /// Returns true if the object has already been printed, so only its label was needed
+    fn label(&mut self, obj: &SchemeObject) -> bool {
+        let id = match identity(obj) {
+            Some(id) => id,
+            None => return false,
+        };
+
+        match self.labels.get(&id) {
+            Some(Some(label)) => {
+                self.out += &format!("#{}#", label);
+                true
+            }
+            Some(None) => {
+                self.labels.insert(id, Some(self.next_label));
+                self.out += &format!("#{}=", self.next_label);
+                self.next_label += 1;
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn object(&mut self, obj: &SchemeObject) {
+        use self::SchemeObject::*;
+
+        if self.label(obj) {
+            return;
+        }
+
+        match obj {
+            Bool(true) => self.out += "#t",
+            Bool(false) => self.out += "#f",
+            Symbol(s) => self.symbol(&s.name()),
+            String(ref s) => self.string(s),
+            Char(c) => self.character(*c),
+            Integer(i) => self.out += &i.to_string(),
+            List(ref lst, _) => self.sequence("(", lst.iter()),
+            Vector(ref v) => self.sequence("#(", v.iter()),
+            Quoted(ref inner) => {
+                self.out.push('\'');
+                self.object(inner);
+            }
+            Bytevector(ref bytes) => {
+                let bytes: Vec<std::string::String> = bytes.iter().map(|b| b.to_string()).collect();
+                self.out += &format!("#u8({})", bytes.join(" "));
+            }
+            RFunc(ref name, _, _, _) => match self.style {
+                Style::Display => self.out += &format!("Built-in function: {}", name),
+                _ => self.out += name,
+            },
+            SFunc(ref lambda, _, _) => {
+                let args: Vec<std::string::String> =
+                    lambda.args.iter().map(|a| a.to_string()).collect();
+                self.out += &format!("(lambda ({}) ", args.join(" "));
+                self.object(&lambda.body);
+                self.out.push(')');
+            }
+            Port(ref port) => self.out += &port.to_string(),
+            Eof => self.out += "#<eof>",
+            Condition(ref e) => self.out += &format!("#<error: {}>", e.to_string()),
+            Foreign(ref f) => self.out += &f.to_string(),
+            Promise(ref p) => self.out += &format!("{:?}", p),
+            Record(ref r) => {
+                self.out += &format!("#<{}", r.type_name());
+                for (name, val) in r.fields() {
+                    self.out += &format!(" {}: ", name);
+                    self.object(&val);
+                }
+                self.out.push('>');
+            }
+            RecordType(ref t) => self.out += &format!("{:?}", t),
+            // the result of some code: (values 1 2) prints as 1 2. Multiple values can't be put
+            // inside other objects (see `vm::single_value`).
+            Values(ref vals) => {
+                for (i, val) in vals.iter().enumerate() {
+                    if i > 0 {
+                        self.out.push(' ');
+                    }
+                    self.object(val);
+                }
+            }
+            None => (),
+        }
+    }
+
+    /// Print a list or vector
+    fn sequence<'a, I>(&mut self, open: &str, items: I)
+    where
+        I: Iterator<Item = &'a SchemeObject>,
+    {
+        self.out += open;
+        for (i, item) in items.enumerate() {
+            if i > 0 {
+                self.out.push(' ');
+            }
+            self.object(item);
+        }
+        self.out.push(')');
+    }
+
+    fn string(&mut self, s: &str) {
+        if self.style == Style::Display {
+            self.out += s;
+            return;
+        }
+
+        self.out.push('"');
+        for c in s.chars() {
+            match c {
+                '"' => self.out += "\\\"",
+                '\\' => self.out += "\\\\",
+                '\n' => self.out += "\\n",
+                '\t' => self.out += "\\t",
+                '\r' => self.out += "\\r",
+                c => self.out.push(c),
+            }
+        }
+        self.out.push('"');
+    }
+
+    /// Names which the reader would split into several tokens or read as something other than
+    /// a symbol are written between bars e.g. |a b|, |12|, ||
+    fn symbol(&mut self, name: &str) {
+        let plain = |c: char| !c.is_whitespace() && !"()'\";#|\\".contains(c);
+        let reads_back = !name.is_empty()
+            && name != "."
+            && name.parse::<i64>().is_err()
+            && name.chars().all(plain);
+        if self.style == Style::Display || reads_back {
+            self.out += name;
+            return;
+        }
+
+        self.out.push('|');
+        for c in name.chars() {
+            if c == '|' || c == '\\' {
+                self.out.push('\\');
+            }
+            self.out.push(c);
+        }
+        self.out.push('|');
+    }
+
+    fn character(&mut self, c: char) {
+        if self.style == Style::Display {
+            self.out.push(c);
+            return;
+        }
+
+        match char_name(c) {
+            Some(name) => self.out += &format!("#\\{}", name),
+            None => self.out += &format!("#\\{}", c),
+        }
+    }
+}
+
+/// Names for characters which would be hard to read after #\
+pub fn char_name(c: char) -> Option<&'static str> {
+    match c {
+        ' ' => Some("space"),
+        '\n' => Some("newline"),
+        '\t' => Some("tab"),
+        '\r' => Some("return"),
+        '\0' => Some("null"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data::SymFrom;
+    use std::collections::LinkedList;
+
+    fn list(items: Vec<SchemeObject>) -> SchemeObject {
+        SchemeObject::from(items.into_iter().collect::<LinkedList<_>>())
+    }
+
+    #[test]
+    fn display_and_write() {
+        let obj = list(vec![
+            SchemeObject::from("a \"b\"\n"),
+            SchemeObject::Char('c'),
+            SchemeObject::Char(' '),
+            SchemeObject::Vector(vec![SchemeObject::sym_from("d")]),
+        ]);
+
+        assert_eq!(print(&obj, Style::Display), "(a \"b\"\n c   #(d))");
+        assert_eq!(
+            print(&obj, Style::Write),
+            "(\"a \\\"b\\\"\\n\" #\\c #\\space #(d))"
+        );
+        assert_eq!(print(&obj, Style::Write), print(&obj, Style::WriteSimple));
+    }
+
+    #[test]
+    fn shared() {
+        use data::ForeignObject;
+
+        let shared = SchemeObject::Foreign(ForeignObject::new("thing", 1));
+        let obj = list(vec![shared.clone(), shared.clone()]);
+
+        // a foreign object can't contain itself so only write-shared labels it
+        assert_eq!(print(&obj, Style::Write), "(#<thing> #<thing>)");
+        assert_eq!(print(&obj, Style::WriteShared), "(#0=#<thing> #0#)");
+    }
+}
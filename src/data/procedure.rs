@@ -0,0 +1,61 @@
+//! What can be found out about functions (e.g. for `procedure-arity` and the REPL's `,help`)
+
+use super::scm_obj::{Arity, SchemeObject, SymFrom};
+use std::collections::LinkedList;
+
+impl SchemeObject {
+    /// How many arguments the function accepts (None if this isn't a function)
+    pub fn arity(&self) -> Option<Arity> {
+        match self {
+            SchemeObject::RFunc(_, _, arity, _) => Some(*arity),
+            SchemeObject::SFunc(lambda, _, _) => Some(Arity::from(lambda.args.len())),
+            _ => None,
+        }
+    }
+
+    /// The name the function was defined with (None for anonymous functions and non-functions)
+    pub fn procedure_name(&self) -> Option<&str> {
+        match self {
+            SchemeObject::RFunc(name, _, _, _) => Some(name),
+            SchemeObject::SFunc(_, _, Some(name)) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// The documentation of the function: a scheme function's docstring or a built-in function's
+    /// doc comment
+    pub fn documentation(&self) -> Option<String> {
+        match self {
+            SchemeObject::RFunc(_, _, _, Some(doc)) => {
+                let lines: Vec<&str> = doc.lines().map(str::trim).collect();
+                Some(lines.join("\n").trim().to_string())
+            }
+            SchemeObject::SFunc(lambda, _, _) => lambda.doc.clone(),
+            _ => None,
+        }
+    }
+
+    /// The lambda expression which made a scheme function (None for other objects)
+    pub fn source(&self) -> Option<SchemeObject> {
+        match self {
+            SchemeObject::SFunc(lambda, _, _) => {
+                let args: LinkedList<SchemeObject> = lambda
+                    .args
+                    .iter()
+                    .map(|&a| SchemeObject::Symbol(a))
+                    .collect();
+
+                let mut form = LinkedList::new();
+                form.push_back(SchemeObject::sym_from("lambda"));
+                form.push_back(SchemeObject::List(args, None));
+                if let Some(ref doc) = lambda.doc {
+                    form.push_back(SchemeObject::from(doc.as_str()));
+                }
+                form.push_back(lambda.body.clone());
+
+                Some(SchemeObject::List(form, None))
+            }
+            _ => None,
+        }
+    }
+}
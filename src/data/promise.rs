@@ -0,0 +1,165 @@
+//! Promises made by delay, delay-force and make-promise
+//!
+//! Forcing works as R7RS describes: the result is remembered, and a promise made by delay-force
+//! takes over the state of the promise its expression gives instead of forcing it recursively, so
+//! long chains of delay-force (e.g. a lazy loop) are forced in constant space.
+
+use super::env::PackedEnv;
+use super::scm_obj::SchemeObject;
+use super::RuntimeError;
+
+use std::cell::RefCell;
+use std::fmt;
+use std::mem;
+use std::ptr;
+use std::rc::Rc;
+
+/// A promise. Promises are compared by identity.
+pub struct Promise {
+    /// Promises made by delay-force share their state with the promise they were forced into
+    state: RefCell<Rc<RefCell<PromiseState>>>,
+}
+
+/// What a promise holds
+#[derive(Clone)]
+pub(crate) enum PromiseState {
+    /// The promise has been forced (or was made by make-promise)
+    Done(Rc<SchemeObject>),
+    /// The value is found by calling `func` with `args`
+    Delayed {
+        func: Rc<SchemeObject>,
+        args: Vec<Rc<SchemeObject>>,
+        /// Does the call give another promise to force (delay-force) rather than the value?
+        forces: bool,
+    },
+}
+
+impl Promise {
+    /// A promise which has already been forced to `value`
+    pub fn done(value: Rc<SchemeObject>) -> Self {
+        Self::new(PromiseState::Done(value))
+    }
+
+    /// A promise of the result of calling `func` with `args`. When `forces` is true the call gives
+    /// another promise, whose value becomes the value of this one (like delay-force).
+    pub fn delayed(func: Rc<SchemeObject>, args: Vec<Rc<SchemeObject>>, forces: bool) -> Self {
+        Self::new(PromiseState::Delayed { func, args, forces })
+    }
+
+    fn new(state: PromiseState) -> Self {
+        Self {
+            state: RefCell::new(Rc::new(RefCell::new(state))),
+        }
+    }
+
+    /// Has the promise been forced?
+    pub fn is_done(&self) -> bool {
+        matches!(*self.shared().borrow(), PromiseState::Done(_))
+    }
+
+    /// The state shared with other promises
+    pub(crate) fn shared(&self) -> Rc<RefCell<PromiseState>> {
+        self.state.borrow().clone()
+    }
+
+    /// `shared` unless the promise is being changed
+    pub(crate) fn try_shared(&self) -> Option<Rc<RefCell<PromiseState>>> {
+        self.state.try_borrow().ok().map(|s| s.clone())
+    }
+
+    /// The value of the promise, working it out if it hasn't been already
+    /// `env` is passed on to built-in functions which are called.
+    pub fn force(&self, env: &PackedEnv) -> Result<Rc<SchemeObject>, RuntimeError> {
+        loop {
+            let (func, args, forces) = match *self.shared().borrow() {
+                PromiseState::Done(ref value) => return Ok(value.clone()),
+                PromiseState::Delayed {
+                    ref func,
+                    ref args,
+                    forces,
+                } => (func.clone(), args.clone(), forces),
+            };
+
+            let result = func.apply(&args, env)?;
+
+            // forcing the promise again while it was being worked out may have finished it
+            if self.is_done() {
+                continue;
+            }
+
+            if !forces {
+                *self.shared().borrow_mut() = PromiseState::Done(result.clone());
+                return Ok(result);
+            }
+
+            // take over the state of the promise we were given, and share it from now on
+            let other = match *result {
+                SchemeObject::Promise(ref other) => other.clone(),
+                ref o => {
+                    return Err(RuntimeError::from(format!(
+                        "delay-force expects its expression to give a promise, got {:?}",
+                        o
+                    )))
+                }
+            };
+            let state = other.shared().borrow().clone();
+            *self.shared().borrow_mut() = state;
+            *other.state.borrow_mut() = self.shared();
+        }
+    }
+}
+
+impl fmt::Debug for Promise {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_done() {
+            f.write_str("#<promise (forced)>")
+        } else {
+            f.write_str("#<promise>")
+        }
+    }
+}
+
+/// Forced streams are chains of promises of lists holding the next promise. Dropping them
+/// recursively would overflow the stack for long streams, so the values of promises (and of the
+/// promises inside those values) are taken out and dropped one at a time.
+impl Drop for Promise {
+    fn drop(&mut self) {
+        let mut objs = Vec::new();
+        take_value(self.state.get_mut(), &mut objs);
+
+        while let Some(obj) = objs.pop() {
+            match obj {
+                SchemeObject::Promise(promise) => {
+                    if let Ok(mut promise) = Rc::try_unwrap(promise) {
+                        take_value(promise.state.get_mut(), &mut objs);
+                    }
+                }
+                SchemeObject::List(items, _) => objs.extend(items),
+                SchemeObject::Vector(items) => objs.extend(items),
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Move the value of a forced promise to `objs` if nothing else holds it
+fn take_value(state: &mut Rc<RefCell<PromiseState>>, objs: &mut Vec<SchemeObject>) {
+    let state = match Rc::get_mut(state) {
+        Some(state) => state.get_mut(),
+        None => return,
+    };
+
+    if let PromiseState::Done(ref mut value) = *state {
+        let value = mem::replace(value, Rc::new(SchemeObject::None));
+        if let Ok(obj) = Rc::try_unwrap(value) {
+            objs.push(obj);
+        }
+    }
+}
+
+/// Promises are equal if they are the same promise
+impl PartialEq for Promise {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other)
+    }
+}
@@ -0,0 +1,353 @@
+//! Records made by define-record-type
+//!
+//! The procedures define-record-type defines are scheme functions which call the built-in
+//! functions here, passing the record type (and the field they work on) as constants. See
+//! `Compiler::define_record_type`.
+
+use super::env::PackedEnv;
+use super::scm_obj::{Arity, BuiltinFn, SchemeObject};
+use super::symbol::Sym;
+use super::RuntimeError;
+use stdlib::get_none;
+
+use std::cell::{Ref, RefCell};
+use std::collections::LinkedList;
+use std::fmt;
+use std::ptr;
+use std::rc::Rc;
+
+/// A record type. Each define-record-type makes a new one. Types are compared by identity.
+pub struct RecordType {
+    /// The name given to define-record-type, without any angle brackets e.g. point for <point>
+    name: String,
+    fields: Vec<Sym>,
+    /// The fields set from the constructor's arguments, in order
+    constructor: Vec<usize>,
+}
+
+impl RecordType {
+    /// A type with `fields`, whose constructor takes the values of the fields in `constructor`
+    pub(crate) fn new(name: &str, fields: Vec<Sym>, constructor: Vec<usize>) -> Self {
+        let name = name.trim_start_matches('<').trim_end_matches('>');
+        Self {
+            name: String::from(name),
+            fields,
+            constructor,
+        }
+    }
+
+    /// The name of the type e.g. point
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The names of the fields, in the order they were declared
+    pub fn field_names(&self) -> &[Sym] {
+        &self.fields
+    }
+
+    /// The position of the field called `name`
+    fn field_index(&self, name: &str) -> Option<usize> {
+        self.fields.iter().position(|f| *f == name)
+    }
+}
+
+impl fmt::Debug for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#<record-type {}>", self.name)
+    }
+}
+
+/// Record types are equal if they are the same type
+impl PartialEq for RecordType {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+/// An instance of a record type
+pub struct Record {
+    record_type: Rc<RecordType>,
+    /// Values in the order the type declares the fields
+    fields: RefCell<Vec<Rc<SchemeObject>>>,
+}
+
+impl Record {
+    /// The type of the record
+    pub fn record_type(&self) -> &Rc<RecordType> {
+        &self.record_type
+    }
+
+    /// The name of the record's type e.g. point
+    pub fn type_name(&self) -> &str {
+        self.record_type.name()
+    }
+
+    /// The value of the field called `name`, or None if the type has no such field
+    pub fn get(&self, name: &str) -> Option<Rc<SchemeObject>> {
+        let i = self.record_type.field_index(name)?;
+        Some(self.fields.borrow()[i].clone())
+    }
+
+    /// Change the field called `name`. Returns false if the type has no such field.
+    pub fn set(&self, name: &str, value: Rc<SchemeObject>) -> bool {
+        match self.record_type.field_index(name) {
+            Some(i) => {
+                self.fields.borrow_mut()[i] = value;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The names and values of the fields, in the order they were declared
+    pub fn fields(&self) -> Vec<(Sym, Rc<SchemeObject>)> {
+        let values = self.fields.borrow();
+        self.record_type
+            .fields
+            .iter()
+            .cloned()
+            .zip(values.iter().cloned())
+            .collect()
+    }
+
+    /// The values of the fields, in the order they were declared
+    pub(crate) fn values(&self) -> Vec<Rc<SchemeObject>> {
+        self.fields.borrow().clone()
+    }
+
+    /// The values of the fields unless they are being changed
+    pub(crate) fn try_values(&self) -> Option<Ref<'_, Vec<Rc<SchemeObject>>>> {
+        self.fields.try_borrow().ok()
+    }
+}
+
+/// Records are equal if they are the same record. Records can contain themselves, so comparing
+/// their fields is left to equal?, which can tell when it goes round a cycle.
+impl PartialEq for Record {
+    fn eq(&self, other: &Self) -> bool {
+        ptr::eq(self, other)
+    }
+}
+
+/// The built-in functions the procedures of a record type call, with the arguments they are given
+/// after the type (and field position for accessors and modifiers)
+pub(crate) fn constructor() -> SchemeObject {
+    builtin("record-constructor", construct, Arity::from(1..))
+}
+
+/// See `constructor`
+pub(crate) fn predicate() -> SchemeObject {
+    builtin("record-predicate", is_instance, Arity::from(2))
+}
+
+/// See `constructor`
+pub(crate) fn accessor() -> SchemeObject {
+    builtin("record-accessor", get_field, Arity::from(3))
+}
+
+/// See `constructor`
+pub(crate) fn modifier() -> SchemeObject {
+    builtin("record-modifier", set_field, Arity::from(4))
+}
+
+fn builtin(name: &str, f: BuiltinFn, arity: Arity) -> SchemeObject {
+    SchemeObject::RFunc(String::from(name), f, arity, None)
+}
+
+type Ret = Result<Rc<SchemeObject>, RuntimeError>;
+
+/// The record type passed as the first argument
+fn record_type(args: &[Rc<SchemeObject>]) -> Result<Rc<RecordType>, RuntimeError> {
+    match *args[0] {
+        SchemeObject::RecordType(ref t) => Ok(t.clone()),
+        ref o => Err(RuntimeError::from(format!(
+            "Expected a record type, got {:?}",
+            o
+        ))),
+    }
+}
+
+/// (record-constructor type value...)
+fn construct(args: &LinkedList<Rc<SchemeObject>>, _env: &PackedEnv) -> Ret {
+    let args: Vec<Rc<SchemeObject>> = args.iter().cloned().collect();
+    let record_type = record_type(&args)?;
+
+    let mut fields = vec![get_none(); record_type.fields.len()];
+    for (&i, value) in record_type.constructor.iter().zip(&args[1..]) {
+        fields[i] = value.clone();
+    }
+
+    Ok(Rc::new(SchemeObject::Record(Rc::new(Record {
+        record_type,
+        fields: RefCell::new(fields),
+    }))))
+}
+
+/// The record passed as argument `i`, which must be of the type passed as the first argument
+fn instance(args: &[Rc<SchemeObject>], i: usize) -> Result<Rc<Record>, RuntimeError> {
+    let record_type = record_type(args)?;
+    match *args[i] {
+        SchemeObject::Record(ref r) if Rc::ptr_eq(&r.record_type, &record_type) => Ok(r.clone()),
+        ref o => Err(RuntimeError::from(format!(
+            "Expected a {} record, got {:?}",
+            record_type.name, o
+        ))),
+    }
+}
+
+/// The field position passed as the second argument
+fn field(args: &[Rc<SchemeObject>]) -> Result<usize, RuntimeError> {
+    match *args[1] {
+        SchemeObject::Integer(i) => Ok(i as usize),
+        ref o => Err(RuntimeError::from(format!(
+            "Expected a field position, got {:?}",
+            o
+        ))),
+    }
+}
+
+/// (record-predicate type obj)
+fn is_instance(args: &LinkedList<Rc<SchemeObject>>, _env: &PackedEnv) -> Ret {
+    let args: Vec<Rc<SchemeObject>> = args.iter().cloned().collect();
+    Ok(Rc::new(SchemeObject::from(instance(&args, 1).is_ok())))
+}
+
+/// (record-accessor type field record)
+fn get_field(args: &LinkedList<Rc<SchemeObject>>, _env: &PackedEnv) -> Ret {
+    let args: Vec<Rc<SchemeObject>> = args.iter().cloned().collect();
+    let record = instance(&args, 2)?;
+    let value = record.fields.borrow()[field(&args)?].clone();
+    Ok(value)
+}
+
+/// (record-modifier type field record value)
+fn set_field(args: &LinkedList<Rc<SchemeObject>>, _env: &PackedEnv) -> Ret {
+    let args: Vec<Rc<SchemeObject>> = args.iter().cloned().collect();
+    let record = instance(&args, 2)?;
+    record.fields.borrow_mut()[field(&args)?] = args[3].clone();
+    Ok(get_none())
+}
+
+#[cfg(test)]
+mod tests {
+    use ast;
+    use data::env::PackedEnv;
+    use data::*;
+    use stdlib::get_std_env;
+
+    use std::rc::Rc;
+
+    fn eval(env: &PackedEnv, program: &str) -> Result<Rc<SchemeObject>, RuntimeError> {
+        let mut res = Ok(Rc::new(SchemeObject::None));
+        for code in ast::ObjectIterator::from(program.chars()) {
+            res = code.unwrap().exec(env);
+        }
+        res
+    }
+
+    const POINT: &str = "(define-record-type <point> (make-point x y) point?
+                           (x point-x set-point-x!)
+                           (y point-y))";
+
+    #[test]
+    fn procedures() {
+        let env = get_std_env();
+        eval(&env, POINT).unwrap();
+
+        let program = "(define p (make-point 1 2))
+                       (set-point-x! p 3)
+                       (list (point? p) (point? 1) (point-x p) (point-y p))";
+        assert_eq!(format!("{:?}", eval(&env, program).unwrap()), "(#t #f 3 2)");
+
+        let err = eval(&env, "(point-x \"p\")").unwrap_err();
+        assert_eq!(
+            err.root(),
+            &RuntimeError::from("Expected a point record, got \"p\"")
+        );
+        assert!(eval(&env, "(make-point 1)").is_err());
+    }
+
+    #[test]
+    fn printing_and_equality() {
+        let env = get_std_env();
+        eval(&env, POINT).unwrap();
+
+        let p = eval(&env, "(make-point \"a\" 2)").unwrap();
+        assert_eq!(p.to_string(), "#<point x: a y: 2>");
+        assert_eq!(format!("{:?}", p), "#<point x: \"a\" y: 2>");
+
+        let program = "(list (equal? (make-point 1 2) (make-point 1 2))
+                             (equal? (make-point 1 2) (make-point 1 3)))";
+        assert_eq!(format!("{:?}", eval(&env, program).unwrap()), "(#t #f)");
+
+        // a record containing itself gets a datum label
+        let p = eval(&env, "(define p (make-point 1 2)) (set-point-x! p p) p").unwrap();
+        assert_eq!(format!("{:?}", p), "#0=#<point x: #0# y: 2>");
+        eval(&env, "(set-point-x! p 1)").unwrap();
+    }
+
+    #[test]
+    fn cyclic_equality() {
+        let env = get_std_env();
+        eval(&env, POINT).unwrap();
+
+        // each record contains itself, or the two contain each other
+        let program = "(define a (make-point 1 2)) (set-point-x! a a)
+                       (define b (make-point 1 2)) (set-point-x! b b)
+                       (define c (make-point 1 2)) (define d (make-point 1 3))
+                       (set-point-x! c d) (set-point-x! d c)
+                       (list (equal? a b) (equal? a a) (equal? c d) (equal? (list a) (list b)))";
+        assert_eq!(
+            format!("{:?}", eval(&env, program).unwrap()),
+            "(#t #t #f #t)"
+        );
+        eval(
+            &env,
+            "(set-point-x! a 1) (set-point-x! b 1) (set-point-x! c 1)",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn from_rust() {
+        let env = get_std_env();
+        eval(&env, POINT).unwrap();
+
+        let p = eval(&env, "(make-point 1 2)").unwrap();
+        let record = match *p {
+            SchemeObject::Record(ref r) => r.clone(),
+            ref o => panic!("{:?} isn't a record", o),
+        };
+        assert_eq!(record.type_name(), "point");
+        assert_eq!(*record.get("y").unwrap(), SchemeObject::Integer(2));
+        assert!(record.get("z").is_none());
+
+        assert!(record.set("x", Rc::new(SchemeObject::Integer(5))));
+        env.borrow_mut().set(String::from("p"), p.clone());
+        assert_eq!(
+            *eval(&env, "(point-x p)").unwrap(),
+            SchemeObject::Integer(5)
+        );
+
+        let names: Vec<String> = record.fields().iter().map(|(n, _)| n.to_string()).collect();
+        assert_eq!(names, vec!["x", "y"]);
+    }
+
+    #[test]
+    fn duplicate_fields() {
+        let env = get_std_env();
+
+        let err = eval(&env, "(define-record-type p (mk x) p? (x gx sx) (x gx2))").unwrap_err();
+        assert_eq!(
+            err.root(),
+            &RuntimeError::from("Field x of p is declared more than once")
+        );
+
+        let err = eval(&env, "(define-record-type p (mk x x) p? (x gx))").unwrap_err();
+        assert_eq!(
+            err.root(),
+            &RuntimeError::from("Field x is an argument of mk more than once")
+        );
+    }
+}
@@ -1,43 +1,180 @@
 //! Defines `SchemeObject` type and implements basic traits
 
+use data::compile::Lambda;
 use data::env::*;
+use data::foreign::ForeignObject;
+use data::port::Port;
+use data::print::{print, Style};
+use data::promise::Promise;
+use data::record::{Record, RecordType};
+use data::symbol::Sym;
+use data::vm::Closure;
+use data::RuntimeError;
+use span::Span;
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
 use std::cmp::PartialEq;
 use std::collections::LinkedList;
 use std::fmt;
+use std::ops::{RangeFrom, RangeInclusive};
 use std::rc::Rc;
 
+/// Signature of built-in (rust) functions
+/// They are given their evaluated arguments and the environment of the caller
+pub type BuiltinFn =
+    fn(&LinkedList<Rc<SchemeObject>>, &PackedEnv) -> Result<Rc<SchemeObject>, RuntimeError>;
+
+/// The doc comment of a built-in function (see `lib_funcs!`)
+/// This is an alias so that serde doesn't think deserialised objects borrow from their input
+pub type BuiltinDoc = &'static str;
+
+/// How many arguments a function accepts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Arity {
+    /// The fewest arguments
+    pub min: usize,
+    /// The most arguments (if there is a limit)
+    pub max: Option<usize>,
+}
+
+impl Arity {
+    /// Any number of arguments (including built-in functions which check for themselves)
+    pub fn any() -> Self {
+        Self { min: 0, max: None }
+    }
+
+    /// Can the function be called with `n` arguments?
+    pub fn accepts(&self, n: usize) -> bool {
+        n >= self.min && self.max.is_none_or(|max| n <= max)
+    }
+}
+
+/// Exactly `n` arguments
+impl From<usize> for Arity {
+    fn from(n: usize) -> Self {
+        Self {
+            min: n,
+            max: Some(n),
+        }
+    }
+}
+
+/// `min..` arguments
+impl From<RangeFrom<usize>> for Arity {
+    fn from(r: RangeFrom<usize>) -> Self {
+        Self {
+            min: r.start,
+            max: None,
+        }
+    }
+}
+
+/// `min..=max` arguments
+impl From<RangeInclusive<usize>> for Arity {
+    fn from(r: RangeInclusive<usize>) -> Self {
+        Self {
+            min: *r.start(),
+            max: Some(*r.end()),
+        }
+    }
+}
+
+/// e.g. "2", "0 to 1" or "at least 1" (as in "expects 2 arguments")
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.max {
+            Some(max) if max == self.min => write!(f, "{}", max),
+            Some(max) => write!(f, "{} to {}", self.min, max),
+            None => write!(f, "at least {}", self.min),
+        }
+    }
+}
+
 /// Representation of a scheme object
+///
+/// With the `serde` feature enabled the data variants can be serialised to and from any serde
+/// format. Functions can't be serialised: attempting to do so returns an error from the serialiser.
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum SchemeObject {
     /// A boolean value
     Bool(bool),
     /// A symbol e.g. a variable name
-    Symbol(String),
+    Symbol(Sym),
     /// A string e.g. "HELLO"
     String(String),
+    /// A single character
+    Char(char),
+    /// An integer
+    Integer(i64),
     /// A linked list
-    List(LinkedList<SchemeObject>),
+    /// Lists read from source code remember where they came from so errors can be reported there
+    List(
+        LinkedList<SchemeObject>,
+        #[cfg_attr(feature = "serde", serde(skip))] Option<Span>,
+    ),
     /// A quoted object
     Quoted(Box<SchemeObject>),
     /// A vector #()
     Vector(Vec<SchemeObject>),
+    /// A bytevector #u8()
+    Bytevector(Vec<u8>),
     /// A built-in (rust) function
+    #[cfg_attr(feature = "serde", serde(skip))]
     RFunc(
-        String,                                                            // Name
-        fn(&LinkedList<Rc<SchemeObject>>, &PackedEnv) -> Rc<SchemeObject>, // Function pointer
+        String,             // Name
+        BuiltinFn,          // Function pointer
+        Arity,              // accepted number of arguments
+        Option<BuiltinDoc>, // documentation
     ),
     /// A scheme function
+    #[cfg_attr(feature = "serde", serde(skip))]
     SFunc(
-        Box<SchemeObject>, // Code list
-        Vec<String>,       // argument names
-        // closure environment (the environment in use when the function was defined
-        PackedEnv,
+        Rc<Lambda>,     // argument names, body, docstring and compiled code
+        Rc<Closure>,    // the variables it uses from where it was made
+        Option<String>, // name given by define (for backtraces)
     ),
+    /// An input or output port
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Port(Port),
+    /// The end of file object (returned when reading from an exhausted port)
+    Eof,
+    /// An error caught by guard
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Condition(Box<RuntimeError>),
+    /// Opaque data belonging to the embedding application
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Foreign(ForeignObject),
+    /// A promise made by delay, delay-force or make-promise (streams are promises too)
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Promise(Rc<Promise>),
+    /// A record made by a constructor from define-record-type
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Record(Rc<Record>),
+    /// The type define-record-type binds to the type name e.g. <point>
+    #[cfg_attr(feature = "serde", serde(skip))]
+    RecordType(Rc<RecordType>),
+    /// Multiple values returned by `values` (any number but one)
+    /// These are spread out by call-with-values, let-values and the like rather than being used as
+    /// a list.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    Values(Vec<Rc<SchemeObject>>),
     /// None (for use as a function return value)
     None,
 }
 
+impl SchemeObject {
+    /// The values `obj` stands for: the contents of `Values` or else just `obj`
+    pub fn values(obj: &Rc<Self>) -> Vec<Rc<Self>> {
+        match **obj {
+            SchemeObject::Values(ref vals) => vals.clone(),
+            _ => vec![obj.clone()],
+        }
+    }
+}
+
 /// Creates a `SchemeObject::String`
 impl<'a> From<&'a str> for SchemeObject {
     fn from(s: &str) -> Self {
@@ -59,6 +196,13 @@ impl From<String> for SchemeObject {
     }
 }
 
+/// Creates a `SchemeObject::List` with no source location
+impl From<LinkedList<SchemeObject>> for SchemeObject {
+    fn from(lst: LinkedList<SchemeObject>) -> Self {
+        SchemeObject::List(lst, None)
+    }
+}
+
 /// Creates a `SchemeObject::Bool`
 impl From<bool> for SchemeObject {
     fn from(b: bool) -> Self {
@@ -74,19 +218,19 @@ pub trait SymFrom<T> {
 
 impl<'a> SymFrom<&'a str> for SchemeObject {
     fn sym_from(s: &str) -> Self {
-        SchemeObject::Symbol(String::from(s))
+        SchemeObject::Symbol(Sym::intern(s))
     }
 }
 
 impl<'a> SymFrom<&'a String> for SchemeObject {
     fn sym_from(s: &String) -> Self {
-        SchemeObject::Symbol(s.clone())
+        SchemeObject::Symbol(Sym::intern(s))
     }
 }
 
 impl SymFrom<String> for SchemeObject {
     fn sym_from(s: String) -> Self {
-        SchemeObject::Symbol(s)
+        SchemeObject::Symbol(Sym::intern(&s))
     }
 }
 
@@ -98,79 +242,88 @@ impl PartialEq for SchemeObject {
 
         match (self, other) {
             (Bool(b1), Bool(b2)) => b1 == b2,
-            (Symbol(s1), Symbol(s2)) | (String(s1), String(s2)) => s1 == s2,
-            (List(l1), List(l2)) => l1 == l2,
+            (Symbol(s1), Symbol(s2)) => s1 == s2,
+            (String(s1), String(s2)) => s1 == s2,
+            (List(l1, _), List(l2, _)) => l1 == l2,
             (Quoted(o1), Quoted(o2)) => o1 == o2,
             (Vector(v1), Vector(v2)) => v1 == v2,
-            (RFunc(_, f1), RFunc(_, f2)) => *f1 as usize == *f2 as usize, // lifted from rust stdlib
-            (SFunc(b1, v1, e1), SFunc(b2, v2, e2)) => b1 == b2 && v1 == v2 && e1 == e2,
+            (RFunc(_, f1, _, _), RFunc(_, f2, _, _)) => *f1 as usize == *f2 as usize, // lifted from rust stdlib
+            (SFunc(l1, c1, _), SFunc(l2, c2, _)) => {
+                l1.args == l2.args && l1.body == l2.body && c1 == c2
+            }
+            (Char(c1), Char(c2)) => c1 == c2,
+            (Integer(i1), Integer(i2)) => i1 == i2,
+            (Bytevector(b1), Bytevector(b2)) => b1 == b2,
+            (Condition(e1), Condition(e2)) => e1 == e2,
+            (Port(p1), Port(p2)) => p1 == p2,
+            (Eof, Eof) => true,
+            (Foreign(o1), Foreign(o2)) => o1 == o2,
+            (Promise(p1), Promise(p2)) => Rc::ptr_eq(p1, p2),
+            (Record(r1), Record(r2)) => r1 == r2,
+            (RecordType(t1), RecordType(t2)) => t1 == t2,
+            (Values(v1), Values(v2)) => v1 == v2,
             (None, None) => true,
             _ => false,
         }
     }
 }
 
-/// Utility fn for impls of `fmt::{Debug, Display}`
-/// Prints an iterator of strings producing something like (one two three) or [one two]
-fn print_code_lst<I>(f: &mut fmt::Formatter, mut lst: I, s: [char; 2]) -> fmt::Result
-where
-    I: Iterator<Item = String>,
-{
-    // opening symbol
-    write!(f, "{}", s[0])?;
+/// For normal printing (like `display`)
+impl fmt::Display for SchemeObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", print(self, Style::Display))
+    }
+}
 
-    // no space on first iter
-    if let Some(so) = lst.next() {
-        write!(f, "{}", so)?;
+/// For printing source code and data so that it can be read back (like `write`)
+impl fmt::Debug for SchemeObject {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", print(self, Style::Write))
     }
-    // spaces on subsequent iters
-    for so in lst {
-        write!(f, " {}", so)?;
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod serde_tests {
+    use ast;
+    use data::*;
+    use serde_json;
+    use std::collections::LinkedList;
+    use stdlib::get_std_env;
+
+    fn round_trip(obj: &SchemeObject) {
+        let json = serde_json::to_string(obj).unwrap();
+        let back: SchemeObject = serde_json::from_str(&json).unwrap();
+        assert_eq!(&back, obj, "{}", json);
     }
 
-    // closing symbol
-    write!(f, "{}", s[1])
-}
+    #[test]
+    fn data() {
+        let mut lst = LinkedList::new();
+        lst.push_back(SchemeObject::sym_from("symbol"));
+        lst.push_back(SchemeObject::from("string"));
+        lst.push_back(SchemeObject::Vector(vec![SchemeObject::from(true)]));
+        lst.push_back(SchemeObject::None);
 
-/// For normal printing
-impl fmt::Display for SchemeObject {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use self::SchemeObject::*;
+        round_trip(&SchemeObject::from(lst.clone()));
+        round_trip(&SchemeObject::Quoted(Box::new(SchemeObject::from(lst))));
+    }
 
-        match self {
-            Bool(b) => {
-                if *b {
-                    write!(f, "#t")
-                } else {
-                    write!(f, "#f")
-                }
-            }
-            Symbol(ref s) | String(ref s) => write!(f, "{}", s),
-            List(ref lst) => print_code_lst(f, lst.iter().map(|x| format!("{:?}", x)), ['(', ')']),
-            Quoted(ref scm_obj) => write!(f, "'{}", scm_obj),
-            Vector(ref lst) => {
-                print_code_lst(f, lst.iter().map(|x| format!("{:?}", x)), ['[', ']'])
-            }
-            RFunc(_, _) => write!(f, "Built-in function: {:?}", &self),
-            SFunc(obj, names, _) => {
-                // "(lambda ({}) {})"
-                write!(f, "(lambda ")?;
-                print_code_lst(f, names.iter().cloned(), ['(', ')'])?;
-                write!(f, " {}", obj)
-            }
-            None => Ok(()),
-        }
+    #[test]
+    fn symbols_and_strings_differ() {
+        let sym = serde_json::to_string(&SchemeObject::sym_from("a")).unwrap();
+        let string = serde_json::to_string(&SchemeObject::from("a")).unwrap();
+        assert_ne!(sym, string);
     }
-}
 
-/// For printing source code (e.g. displaying a scheme function as a lambda expression)
-impl fmt::Debug for SchemeObject {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use self::SchemeObject::*;
-        match self {
-            String(s) => write!(f, "\"{}\"", s),
-            RFunc(name, _) => write!(f, "{}", name),
-            _ => fmt::Display::fmt(&self, f),
-        }
+    #[test]
+    fn functions() {
+        let env = get_std_env();
+        let display = env.borrow().lookup("display").unwrap();
+        assert!(serde_json::to_string(display.as_ref()).is_err());
+
+        let lambda = ast::parse_tokens("(lambda () 1)".chars()).unwrap()[0]
+            .exec(&env)
+            .unwrap();
+        assert!(serde_json::to_string(lambda.as_ref()).is_err());
     }
 }
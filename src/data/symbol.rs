@@ -0,0 +1,161 @@
+//! Interned symbols
+//!
+//! Each symbol name is stored once in a per-thread table and symbols are small handles into it, so
+//! comparing symbols and looking them up in environments doesn't compare strings.
+//! Uninterned symbols (from `gensym`) get a handle of their own which no name maps to.
+//!
+//! Names are never removed from the table, because a handle doesn't know whether it is still in
+//! use. Code which keeps making new names (e.g. calling `string->symbol` on input or `gensym` in a
+//! loop) uses more memory for as long as the thread runs.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+/// A symbol: a handle to a name in the symbol table
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Sym(u32);
+
+/// Every name which has been given a handle. Names are never freed (see the module docs).
+#[derive(Default)]
+struct SymbolTable {
+    /// Names by handle
+    names: Vec<Rc<str>>,
+    /// Handles of the interned names
+    interned: HashMap<Rc<str>, Sym>,
+    /// Used to make the names of generated symbols
+    generated: usize,
+}
+
+thread_local! {
+    static SYMBOLS: RefCell<SymbolTable> = RefCell::new(SymbolTable::default());
+}
+
+impl SymbolTable {
+    fn add(&mut self, name: Rc<str>) -> Sym {
+        self.names.push(name);
+        Sym(self.names.len() as u32 - 1)
+    }
+}
+
+impl Sym {
+    /// The symbol called `name`. Every call with the same name gives the same symbol.
+    pub fn intern(name: &str) -> Self {
+        SYMBOLS.with(|table| {
+            let mut table = table.borrow_mut();
+            if let Some(&sym) = table.interned.get(name) {
+                return sym;
+            }
+
+            let name: Rc<str> = Rc::from(name);
+            let sym = table.add(name.clone());
+            table.interned.insert(name, sym);
+            sym
+        })
+    }
+
+    /// A new symbol which isn't equal to any other, named `prefix` followed by a number
+    pub fn uninterned(prefix: &str) -> Self {
+        SYMBOLS.with(|table| {
+            let mut table = table.borrow_mut();
+            table.generated += 1;
+            let name = format!("{}{}", prefix, table.generated);
+            table.add(Rc::from(name))
+        })
+    }
+
+    /// The name of the symbol
+    pub fn name(self) -> Rc<str> {
+        SYMBOLS.with(|table| table.borrow().names[self.0 as usize].clone())
+    }
+
+    /// Is this the symbol for `name` (rather than an uninterned symbol with the same name)?
+    fn is(self, name: &str) -> bool {
+        SYMBOLS.with(|table| table.borrow().interned.get(name) == Some(&self))
+    }
+}
+
+impl From<&str> for Sym {
+    fn from(name: &str) -> Self {
+        Self::intern(name)
+    }
+}
+
+impl From<&String> for Sym {
+    fn from(name: &String) -> Self {
+        Self::intern(name)
+    }
+}
+
+impl From<String> for Sym {
+    fn from(name: String) -> Self {
+        Self::intern(&name)
+    }
+}
+
+impl PartialEq<str> for Sym {
+    fn eq(&self, other: &str) -> bool {
+        self.is(other)
+    }
+}
+
+impl PartialEq<&str> for Sym {
+    fn eq(&self, other: &&str) -> bool {
+        self.is(other)
+    }
+}
+
+impl fmt::Display for Sym {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.name())
+    }
+}
+
+impl fmt::Debug for Sym {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Sym({:?})", &*self.name())
+    }
+}
+
+/// Symbols are serialized by name (so uninterned symbols come back interned)
+#[cfg(feature = "serde")]
+impl Serialize for Sym {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Sym {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Sym::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interning() {
+        let a = Sym::intern("a");
+        assert_eq!(a, Sym::from("a"));
+        assert_ne!(a, Sym::from("b"));
+        assert_eq!(&*a.name(), "a");
+        assert!(a == "a");
+    }
+
+    #[test]
+    fn uninterned() {
+        let g = Sym::uninterned("g");
+        let name = g.name();
+
+        assert_ne!(g, Sym::uninterned("g"));
+        assert_ne!(g, Sym::intern(&name));
+        assert!(g != *name);
+    }
+}
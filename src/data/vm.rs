@@ -0,0 +1,609 @@
+//! Runs code compiled by compile.rs
+//!
+//! Calls to scheme functions don't recurse: each call pushes a frame onto the VM's own stack of
+//! frames. Values being worked on are kept on a single stack shared by all of the frames.
+//!
+//! A tail call from one scheme function to another replaces the caller's frame, so loops written
+//! as tail calls run in constant space. The callers replaced this way don't appear in backtraces,
+//! but the frame counts them and keeps where the latest tail call was made.
+
+use super::compile::{Capture, Code, Guard, Lambda, Op};
+use super::env::PackedEnv;
+use super::gc;
+use super::library;
+use super::limits::Run;
+use super::promise::Promise;
+use super::scm_obj::SchemeObject;
+use super::symbol::Sym;
+use super::{Frame, RuntimeError};
+use span::Span;
+use stdlib::{get_none, get_true};
+
+use std::collections::LinkedList;
+use std::rc::Rc;
+
+/// What a scheme function captured when it was made
+#[derive(Debug, PartialEq)]
+pub struct Closure {
+    /// Where global variables are looked up
+    env: PackedEnv,
+    /// The values of the variables it uses from enclosing functions
+    free: Vec<Rc<SchemeObject>>,
+}
+
+/// A function call (or top level code) being run
+struct Activation {
+    code: Rc<Code>,
+    /// The next instruction
+    pc: usize,
+    /// Where the frame's slots (arguments first) start on the stack
+    base: usize,
+    /// Where the stack goes back to when the frame returns
+    floor: usize,
+    closure: Rc<Closure>,
+    /// The function being run, for backtraces. It is on the stack under the arguments.
+    /// None for top level code and the parts of guard forms.
+    func: Option<Rc<SchemeObject>>,
+    /// How many tail calls have replaced this frame, for backtraces
+    tail_calls: usize,
+    /// Where the latest of those tail calls was made
+    tail_call_site: Option<Span>,
+}
+
+struct Vm {
+    stack: Vec<Rc<SchemeObject>>,
+    frames: Vec<Activation>,
+    /// Counts what is done against the interpreter's limits
+    run: Run,
+}
+
+/// Run `code` in `env`
+pub fn run(code: &Rc<Code>, env: &PackedEnv) -> Result<Rc<SchemeObject>, RuntimeError> {
+    Vm::top_level(code, env).execute()
+}
+
+/// Call the scheme function `func` with `args`
+pub fn call(
+    func: &Rc<SchemeObject>,
+    args: &LinkedList<Rc<SchemeObject>>,
+) -> Result<Rc<SchemeObject>, RuntimeError> {
+    let mut stack = Vec::with_capacity(args.len() + 1);
+    stack.push(func.clone());
+    stack.extend(args.iter().cloned());
+
+    let frame = enter(func, &stack, 1)?;
+    Vm::new(stack, frame).execute()
+}
+
+/// Make the frame for a call to the scheme function `func` with the arguments on `stack` from
+/// `base`. The error has the backtrace frame for the call if the arguments were wrong.
+fn enter(
+    func: &Rc<SchemeObject>,
+    stack: &[Rc<SchemeObject>],
+    base: usize,
+) -> Result<Activation, RuntimeError> {
+    let (lambda, closure) = match **func {
+        SchemeObject::SFunc(ref lambda, ref closure, _) => (lambda, closure),
+        _ => unreachable!("vm::enter called on {:?}", func),
+    };
+
+    // did we get the correct number of arguments
+    // TODO variable number of arguments
+    let args = &stack[base..];
+    if args.len() != lambda.args.len() {
+        let err = RuntimeError::from(format!(
+            "Expected {} arguments, got {}",
+            lambda.args.len(),
+            args.len()
+        ));
+        return Err(err.in_frame(backtrace_frame(func, args)));
+    }
+
+    Ok(Activation {
+        code: lambda.code.clone(),
+        pc: 0,
+        base,
+        floor: base - 1,
+        closure: closure.clone(),
+        func: Some(func.clone()),
+        tail_calls: 0,
+        tail_call_site: None,
+    })
+}
+
+fn backtrace_frame(func: &SchemeObject, args: &[Rc<SchemeObject>]) -> Frame {
+    let name = func.procedure_name().unwrap_or("lambda");
+    Frame::new(name, args)
+}
+
+/// Capture the variables `lambda` uses from the frame making it
+fn capture(lambda: &Lambda, frame: &Activation, stack: &[Rc<SchemeObject>]) -> Rc<Closure> {
+    let free = lambda
+        .captures
+        .iter()
+        .map(|&capture| match capture {
+            Capture::Local(slot) => stack[frame.base + slot as usize].clone(),
+            Capture::Free(i) => frame.closure.free[i as usize].clone(),
+        })
+        .collect();
+
+    Rc::new(Closure {
+        env: frame.closure.env.clone(),
+        free,
+    })
+}
+
+impl Closure {
+    /// Where global variables are looked up
+    pub(crate) fn env(&self) -> &PackedEnv {
+        &self.env
+    }
+
+    /// The values of the variables it uses from enclosing functions
+    pub(crate) fn free(&self) -> &[Rc<SchemeObject>] {
+        &self.free
+    }
+}
+
+impl Vm {
+    fn new(stack: Vec<Rc<SchemeObject>>, frame: Activation) -> Self {
+        Self {
+            stack,
+            run: Run::start(&frame.closure.env),
+            frames: vec![frame],
+        }
+    }
+
+    /// A VM to run top level `code` in `env`
+    fn top_level(code: &Rc<Code>, env: &PackedEnv) -> Self {
+        let closure = Closure {
+            env: env.clone(),
+            free: Vec::new(),
+        };
+        let top = Activation {
+            code: code.clone(),
+            pc: 0,
+            base: 0,
+            floor: 0,
+            closure: Rc::new(closure),
+            func: None,
+            tail_calls: 0,
+            tail_call_site: None,
+        };
+
+        Self::new(Vec::new(), top)
+    }
+
+    /// Run until the first frame returns
+    fn execute(&mut self) -> Result<Rc<SchemeObject>, RuntimeError> {
+        if self.frames[0].func.is_some() {
+            if let Err(limit) = self.run.calls(1) {
+                return Err(self.unwind(limit.into()));
+            }
+        }
+
+        loop {
+            match self.step() {
+                Ok(Some(result)) => return Ok(result),
+                Ok(None) => (),
+                Err(e) => return Err(self.unwind(e)),
+            }
+        }
+    }
+
+    /// Run one instruction. Returns the result once the first frame returns.
+    fn step(&mut self) -> Result<Option<Rc<SchemeObject>>, RuntimeError> {
+        let Vm { stack, frames, run } = self;
+        let frame = frames.last_mut().unwrap();
+        let op = frame.code.ops[frame.pc];
+        frame.pc += 1;
+        run.step()?;
+
+        match op {
+            Op::Const(i) => stack.push(frame.code.consts[i as usize].clone()),
+            Op::Local(slot) => {
+                let val = stack[frame.base + slot as usize].clone();
+                stack.push(val);
+            }
+            Op::Free(i) => stack.push(frame.closure.free[i as usize].clone()),
+            Op::Global(name) => match frame.closure.env.borrow().lookup(name) {
+                Some(val) => stack.push(val),
+                None => return Err(RuntimeError::NameLookup(name.to_string())),
+            },
+            Op::Define(name) => {
+                let val = stack.pop().unwrap();
+                single_value(&val, "to define")?;
+                frame.closure.env.borrow_mut().set_global(name, val);
+                stack.push(get_none());
+            }
+            Op::Name(name) => {
+                let val = stack.pop().unwrap();
+                stack.push(name_function(val, name));
+            }
+            Op::Closure(i) => {
+                gc::safe_point();
+                gc::allocated();
+                let lambda = &frame.code.lambdas[i as usize];
+                let closure = capture(lambda, frame, stack);
+                let func = SchemeObject::SFunc(lambda.clone(), closure, None);
+                run.allocated(&func)?;
+                stack.push(Rc::new(func));
+            }
+            Op::Delay(forces) => {
+                let func = stack.pop().unwrap();
+                let promise =
+                    SchemeObject::Promise(Rc::new(Promise::delayed(func, Vec::new(), forces)));
+                run.allocated(&promise)?;
+                stack.push(Rc::new(promise));
+            }
+            Op::List(n) => {
+                let start = stack.len() - n as usize;
+                let items = stack.drain(start..).map(|item| (*item).clone()).collect();
+                let lst = SchemeObject::List(items, None);
+                run.allocated(&lst)?;
+                stack.push(Rc::new(lst));
+            }
+            Op::Call(argc) => call_on_stack(argc as usize, stack, frames, run)?,
+            Op::TailCall(argc) => tail_call(argc as usize, stack, frames, run)?,
+            Op::CallValues => {
+                let vals = SchemeObject::values(&stack.pop().unwrap());
+                let argc = vals.len();
+                stack.extend(vals);
+                call_on_stack(argc, stack, frames, run)?;
+            }
+            Op::Unpack(n, rest) => {
+                let mut vals = SchemeObject::values(&stack.pop().unwrap());
+                let n = n as usize;
+                if vals.len() < n || (!rest && vals.len() > n) {
+                    let expected = if rest {
+                        format!("at least {}", n)
+                    } else {
+                        n.to_string()
+                    };
+                    return Err(RuntimeError::from(format!(
+                        "Expected {} values, got {}",
+                        expected,
+                        vals.len()
+                    )));
+                }
+
+                let extra = vals.split_off(n);
+                stack.extend(vals);
+                if rest {
+                    let lst = extra.iter().map(|val| (**val).clone()).collect();
+                    let lst = SchemeObject::List(lst, None);
+                    run.allocated(&lst)?;
+                    stack.push(Rc::new(lst));
+                }
+            }
+            Op::EndLet(n) => {
+                let result = stack.pop().unwrap();
+                let len = stack.len();
+                stack.truncate(len - n as usize);
+                stack.push(result);
+            }
+            Op::Jump(to) => frame.pc = to as usize,
+            Op::JumpUnlessTrue(to) => {
+                let test = stack.pop().unwrap();
+                single_value(&test, "as the test of if")?;
+                if test != get_true() {
+                    frame.pc = to as usize;
+                }
+            }
+            Op::Pop => {
+                stack.pop();
+            }
+            Op::Guard(i) => {
+                let res = guard(&frame.code.guards[i as usize], frame, stack)?;
+                stack.push(res);
+            }
+            Op::Import(i) => {
+                let tail = form_tail(&frame.code.consts[i as usize]);
+                stack.push(library::import(tail, &frame.closure.env)?);
+            }
+            Op::DefineLibrary(i) => {
+                let tail = form_tail(&frame.code.consts[i as usize]);
+                stack.push(library::define_library(tail, &frame.closure.env)?);
+            }
+            Op::Fail(i) => return Err(frame.code.errors[i as usize].clone()),
+            Op::Return => {
+                let result = stack.pop().unwrap();
+                let frame = frames.pop().unwrap();
+                stack.truncate(frame.floor);
+                if frame.func.is_some() {
+                    run.calls(-1)?;
+                }
+
+                if frames.is_empty() {
+                    return Ok(Some(result));
+                }
+                stack.push(result);
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Throw away every frame, adding where they were to the error
+    /// Each frame blames the form it was running for the error (if it doesn't have a location
+    /// yet) and as the call site of any call which failed. Function calls add a backtrace frame.
+    fn unwind(&mut self, mut err: RuntimeError) -> RuntimeError {
+        while let Some(frame) = self.frames.pop() {
+            // a frame which hasn't started yet (the call went over a limit) isn't running a form
+            let span = frame.pc.checked_sub(1).and_then(|pc| frame.code.spans[pc]);
+            if let Some(span) = span {
+                err = err.at(span).called_from(span);
+            }
+
+            if let Some(ref func) = frame.func {
+                // leaving calls can't go over the limit
+                let _ = self.run.calls(-1);
+                let nargs = func.arity().map_or(0, |a| a.min);
+                let args = &self.stack[frame.base..frame.base + nargs];
+                let mut bt_frame = backtrace_frame(func, args);
+                // the caller's form would be blamed for a call which was really a later tail call
+                bt_frame.tail_calls = frame.tail_calls;
+                bt_frame.call_site = frame.tail_call_site;
+                err = err.in_frame(bt_frame);
+            }
+        }
+
+        self.stack.clear();
+        err
+    }
+}
+
+/// Call the function under the `argc` arguments on top of the stack. Scheme functions get a new
+/// frame; anything else is applied straight away and its result pushed.
+fn call_on_stack(
+    argc: usize,
+    stack: &mut Vec<Rc<SchemeObject>>,
+    frames: &mut Vec<Activation>,
+    run: &Run,
+) -> Result<(), RuntimeError> {
+    let base = stack.len() - argc;
+    let func = stack[base - 1].clone();
+    for arg in &stack[base..] {
+        single_value(arg, "as an argument")?;
+    }
+
+    if let SchemeObject::SFunc(_, _, _) = *func {
+        let callee = enter(&func, stack, base)?;
+        frames.push(callee);
+        run.calls(1)?;
+    } else {
+        let args: LinkedList<Rc<SchemeObject>> = stack.drain(base..).collect();
+        stack.pop();
+        let env = &frames.last().unwrap().closure.env;
+        let result = func.apply_list(&args, env)?;
+        run.allocated(&result)?;
+        stack.push(result);
+    }
+    Ok(())
+}
+
+/// Make the call under the `argc` arguments on top of the stack in place of the current frame
+/// (see `Op::TailCall`). Frames which aren't function calls, like top level code, are kept and
+/// calls to anything but scheme functions are ordinary calls.
+fn tail_call(
+    argc: usize,
+    stack: &mut Vec<Rc<SchemeObject>>,
+    frames: &mut Vec<Activation>,
+    run: &Run,
+) -> Result<(), RuntimeError> {
+    let base = stack.len() - argc;
+    let func = stack[base - 1].clone();
+    let caller = frames.last_mut().unwrap();
+
+    match *func {
+        SchemeObject::SFunc(_, _, _) if caller.func.is_some() => {
+            for arg in &stack[base..] {
+                single_value(arg, "as an argument")?;
+            }
+
+            // checked before the caller's frame is thrown away, so that errors are reported in it
+            let mut callee = enter(&func, stack, base)?;
+
+            // the function and its arguments move down to where the caller's were
+            stack.drain(caller.floor..base - 1);
+            callee.floor = caller.floor;
+            callee.base = caller.floor + 1;
+            callee.tail_calls = caller.tail_calls + 1;
+            callee.tail_call_site = caller.code.spans[caller.pc - 1];
+            *caller = callee;
+            Ok(())
+        }
+        _ => call_on_stack(argc, stack, frames, run),
+    }
+}
+
+/// Multiple values can only be given to call-with-values and the forms which bind them. Anywhere
+/// else wanting a value (`used_as`) raises an error rather than seeing them as one object.
+fn single_value(val: &SchemeObject, used_as: &str) -> Result<(), RuntimeError> {
+    match *val {
+        SchemeObject::Values(ref vals) => Err(RuntimeError::from(format!(
+            "Expected one value {}, got {}",
+            used_as,
+            vals.len()
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// The rest of an import or define-library form saved by `Compiler::library_form`
+fn form_tail(obj: &SchemeObject) -> &LinkedList<SchemeObject> {
+    match obj {
+        SchemeObject::List(lst, _) => lst,
+        obj => unreachable!("{:?} isn't a list", obj),
+    }
+}
+
+/// Names anonymous functions after the variable they are bound to e.g. (define f (lambda ...))
+/// Other values are returned as-is
+fn name_function(val: Rc<SchemeObject>, name: Sym) -> Rc<SchemeObject> {
+    match *val {
+        SchemeObject::SFunc(ref lambda, ref closure, None) => Rc::new(SchemeObject::SFunc(
+            lambda.clone(),
+            closure.clone(),
+            Some(name.to_string()),
+        )),
+        _ => val,
+    }
+}
+
+/// The object a guard clause sees for an error
+/// Objects given to raise are passed on as they were. Other errors are wrapped up as conditions.
+fn error_object(err: &RuntimeError) -> Rc<SchemeObject> {
+    match err.root() {
+        RuntimeError::Raised(obj) => obj.clone(),
+        _ => Rc::new(SchemeObject::Condition(Box::new(err.clone()))),
+    }
+}
+
+/// Run a part of a guard form: `lambda` with `args` without a backtrace frame of its own
+fn run_part(
+    lambda: &Lambda,
+    closure: Rc<Closure>,
+    args: Vec<Rc<SchemeObject>>,
+) -> Result<Rc<SchemeObject>, RuntimeError> {
+    let frame = Activation {
+        code: lambda.code.clone(),
+        pc: 0,
+        base: 0,
+        floor: 0,
+        closure,
+        func: None,
+        tail_calls: 0,
+        tail_call_site: None,
+    };
+
+    Vm::new(args, frame).execute()
+}
+
+/// Run a guard form (see `Compiler::guard`) in `frame`
+fn guard(
+    guard: &Guard,
+    frame: &Activation,
+    stack: &[Rc<SchemeObject>],
+) -> Result<Rc<SchemeObject>, RuntimeError> {
+    let body = capture(&guard.body, frame, stack);
+    let err = match run_part(&guard.body, body, Vec::new()) {
+        Ok(res) => return Ok(res),
+        Err(e) => e,
+    };
+    if !err.catchable() {
+        return Err(err);
+    }
+
+    let obj = error_object(&err);
+
+    for clause in &guard.clauses {
+        let test = match clause.test {
+            Some(ref test) => run_part(test, capture(test, frame, stack), vec![obj.clone()])?,
+            None => get_true(),
+        };
+
+        if *test != SchemeObject::from(false) {
+            return match clause.body {
+                Some(ref body) => run_part(body, capture(body, frame, stack), vec![obj]),
+                None => Ok(test),
+            };
+        }
+    }
+
+    // no clause matched
+    Err(err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Vm;
+    use ast;
+    use data::compile::compile;
+    use data::*;
+    use stdlib::{get_none, get_std_env};
+
+    use std::rc::Rc;
+
+    fn run_program(program: &str) -> Result<Rc<SchemeObject>, RuntimeError> {
+        let env = get_std_env();
+        let mut res = Ok(get_none());
+        for obj in ast::parse_tokens(program.chars()).unwrap() {
+            res = obj.exec(&env);
+        }
+        res
+    }
+
+    #[test]
+    fn deep_recursion() {
+        // too deep for a recursive evaluator
+        let text = "a".repeat(100_000);
+        let program = format!(
+            "(define (count-down port)
+               (if (eof-object? (read-char port)) \"done\" (count-down port)))
+             (count-down (open-input-string \"{}\"))",
+            text
+        );
+
+        assert_eq!(*run_program(&program).unwrap(), SchemeObject::from("done"));
+    }
+
+    #[test]
+    fn tail_calls_reuse_frames() {
+        let env = get_std_env();
+        let program = "(define (loop n) (if (= n 0) \"done\" (let ((m (- n 1))) (loop m))))";
+        ast::parse_tokens(program.chars()).unwrap()[0]
+            .exec(&env)
+            .unwrap();
+
+        let call = &ast::parse_tokens("(loop 1000000)".chars()).unwrap()[0];
+        let mut vm = Vm::top_level(&Rc::new(compile(call)), &env);
+        let mut most_frames = 0;
+        let res = loop {
+            most_frames = most_frames.max(vm.frames.len());
+            if let Some(res) = vm.step().unwrap() {
+                break res;
+            }
+        };
+
+        assert_eq!(*res, SchemeObject::from("done"));
+        // the top level code and one call to loop
+        assert_eq!(most_frames, 2);
+    }
+
+    #[test]
+    fn tail_calls_in_backtrace() {
+        let program = "(define (loop n) (if (= n 3) (raise \"boom\") (loop (+ n 1))))
+(loop 0)";
+        let err = run_program(program).unwrap_err();
+        assert_eq!(
+            err.backtrace().unwrap().render("t.scm"),
+            "Backtrace (most recent call first):\n  0: (raise \"boom\") at t.scm:1:30\n  \
+             1: (loop 3) at t.scm:1:45\n     ... 3 tail calls elided"
+        );
+    }
+
+    #[test]
+    fn let_scope() {
+        let program = "(define x \"global\")
+                       (let ((x \"local\")) x)
+                       x";
+        assert_eq!(*run_program(program).unwrap(), SchemeObject::from("global"));
+    }
+
+    #[test]
+    fn not_a_function() {
+        let err = run_program("(define x \"a\") (x)").unwrap_err();
+        assert_eq!(err.root(), &RuntimeError::from("\"a\" is not a function"));
+    }
+
+    #[test]
+    fn guard_sees_locals() {
+        let program = "(define (f x)
+                         (let ((y \"y\"))
+                           (guard (e (#t (list x y e))) (raise \"boom\"))))
+                       (f 1)";
+        let res = run_program(program).unwrap();
+        assert_eq!(format!("{:?}", res), "(1 \"y\" \"boom\")");
+    }
+}
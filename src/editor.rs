@@ -0,0 +1,200 @@
+//! Line editing for the REPL using rustyline
+//! Adds completion of names, matching paren highlighting, a history file and cancelling input
+//! with Ctrl-C
+
+use rustyline::completion::Completer;
+use rustyline::config::Config;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::{CmdKind, Highlighter, MatchingBracketHighlighter};
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Helper};
+
+use std::borrow::Cow;
+use std::path::PathBuf;
+
+/// Characters which separate the names being completed
+const WORD_BREAKS: &[char] = &[' ', '\t', '\n', '(', ')', '\'', '"'];
+
+/// How many lines of history are kept in the history file
+const HISTORY_LINES: usize = 1000;
+
+/// Why `Editor::read_line` didn't return a line
+#[derive(Debug, PartialEq)]
+pub enum ReadError {
+    /// The end of the input (e.g. Ctrl-D)
+    Eof,
+    /// The line was cancelled with Ctrl-C
+    Interrupted,
+}
+
+/// Reads lines from the terminal
+pub struct Editor {
+    inner: rustyline::Editor<SchemeHelper, DefaultHistory>,
+    /// Where history is saved between sessions
+    history_file: Option<PathBuf>,
+}
+
+impl Editor {
+    /// Set up line editing. History is loaded from and saved to `history_file`
+    pub fn new(history_file: Option<PathBuf>) -> Self {
+        let config = Config::builder()
+            .max_history_size(HISTORY_LINES)
+            .expect("the history size is valid")
+            .build();
+        let mut inner = rustyline::Editor::with_config(config).expect("line editor set up");
+        inner.set_helper(Some(SchemeHelper::default()));
+
+        if let Some(ref file) = history_file {
+            // there is no history file the first time
+            let _ = inner.load_history(file);
+        }
+
+        Self {
+            inner,
+            history_file,
+        }
+    }
+
+    /// Set the names offered by tab completion
+    pub fn set_completions(&mut self, names: Vec<String>) {
+        if let Some(helper) = self.inner.helper_mut() {
+            helper.names = names;
+        }
+    }
+
+    /// Read a line, showing `prompt`
+    /// Ctrl-C cancels the line being typed
+    pub fn read_line(&mut self, prompt: &str) -> Result<String, ReadError> {
+        match self.inner.readline(prompt) {
+            Ok(line) => Ok(line),
+            Err(ReadlineError::Interrupted) => Err(ReadError::Interrupted),
+            // including errors reading the terminal: there is nothing more to read
+            Err(_) => Err(ReadError::Eof),
+        }
+    }
+
+    /// Remember `line` so that it can be found again with the up arrow (even after restarting)
+    pub fn add_history(&mut self, line: &str) {
+        let _ = self.inner.add_history_entry(line);
+        if let Some(ref file) = self.history_file {
+            // saving each line means it isn't lost however the REPL ends
+            let _ = self.inner.append_history(file);
+        }
+    }
+}
+
+/// Completion and highlighting for scheme code
+#[derive(Default)]
+struct SchemeHelper {
+    /// The names which can be completed
+    names: Vec<String>,
+    brackets: MatchingBracketHighlighter,
+}
+
+impl SchemeHelper {
+    /// Where the word ending at `pos` in `line` starts and the names it could be
+    fn candidates(&self, line: &str, pos: usize) -> (usize, Vec<String>) {
+        let start = line[..pos]
+            .rfind(WORD_BREAKS)
+            .map_or(0, |i| i + line[i..].chars().next().unwrap().len_utf8());
+        let word = &line[start..pos];
+
+        let names = self
+            .names
+            .iter()
+            .filter(|name| name.starts_with(word))
+            .cloned()
+            .collect();
+        (start, names)
+    }
+}
+
+impl Completer for SchemeHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(self.candidates(line, pos))
+    }
+}
+
+/// Highlight the paren matching the one at the cursor
+impl Highlighter for SchemeHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        self.brackets.highlight(line, pos)
+    }
+
+    fn highlight_char(&self, line: &str, pos: usize, kind: CmdKind) -> bool {
+        self.brackets.highlight_char(line, pos, kind)
+    }
+}
+
+impl Hinter for SchemeHelper {
+    type Hint = String;
+}
+
+impl Validator for SchemeHelper {}
+
+impl Helper for SchemeHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rustyline::history::{History, SearchDirection};
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    fn helper(names: &[&str]) -> SchemeHelper {
+        SchemeHelper {
+            names: names.iter().map(|n| String::from(*n)).collect(),
+            ..SchemeHelper::default()
+        }
+    }
+
+    #[test]
+    fn completion() {
+        let helper = helper(&["define", "display", "list"]);
+
+        assert_eq!(
+            helper.candidates("(d", 2),
+            (1, vec![String::from("define"), String::from("display")])
+        );
+        assert_eq!(
+            helper.candidates("(list 'a (li", 12),
+            (10, vec![String::from("list")])
+        );
+        // only the word before the cursor counts
+        assert_eq!(
+            helper.candidates("(li x", 3),
+            (1, vec![String::from("list")])
+        );
+        assert_eq!(helper.candidates("\"x", 2), (1, Vec::<String>::new()));
+    }
+
+    #[test]
+    fn history_file() {
+        let path = env::temp_dir().join(format!("rustyscheme-{}-history", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut editor = Editor::new(Some(path.clone()));
+        editor.add_history("(define x 1)");
+        editor.add_history("(list x)");
+
+        // a new session starts with the old history
+        let editor = Editor::new(Some(path.clone()));
+        let history = editor.inner.history();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(history.len(), 2);
+        let last = history.get(1, SearchDirection::Forward);
+        assert_eq!(last.unwrap().unwrap().entry, "(list x)");
+    }
+}
@@ -10,11 +10,19 @@
 #![warn(non_camel_case_types)]
 #![warn(unused_qualifications)]
 
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(all(test, feature = "serde"))]
+extern crate serde_json;
+
 pub mod ast;
 pub mod data;
+pub mod span;
 pub mod stdlib;
 pub mod tokenise;
 
+use span::Span;
+
 /// Possible parse errors
 #[derive(Debug, PartialEq)]
 pub enum ParseError {
@@ -28,6 +36,34 @@ pub enum ParseError {
     ClosingBracket,
     /// Syntax Error e.g. #a
     SyntaxError(String),
+    /// Another error along with where in the source it happened
+    Located(Span, Box<ParseError>),
+}
+
+impl ParseError {
+    /// Attach a location to the error unless it already has one
+    pub fn at(self, span: Span) -> Self {
+        match self {
+            ParseError::Located(_, _) => self,
+            e => ParseError::Located(span, Box::new(e)),
+        }
+    }
+
+    /// Where the error happened, if known
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            ParseError::Located(span, _) => Some(*span),
+            _ => None,
+        }
+    }
+
+    /// The error without any location information
+    pub fn root(&self) -> &Self {
+        match self {
+            ParseError::Located(_, e) => e.root(),
+            e => e,
+        }
+    }
 }
 
 /// Creates a `ParseError::SyntaxError`
@@ -1,46 +1,302 @@
 #![cfg_attr(feature = "cargo-clippy", deny(clippy::pedantic))]
 extern crate rustyscheme;
 
-extern crate readline;
+extern crate libc;
+extern crate rustyline;
 
-use readline::{add_history, readline};
+// REPL commands e.g. ,help
+mod commands;
+// line editing for the REPL
+mod editor;
+
+use editor::{Editor, ReadError};
 
 use rustyscheme::ast;
-use rustyscheme::data::SchemeObject;
-use rustyscheme::stdlib::get_std_env;
+use rustyscheme::data::env::PackedEnv;
+use rustyscheme::data::interrupt::Interrupt;
+use rustyscheme::data::RuntimeError;
+use rustyscheme::data::{SchemeObject, SPECIAL_FORMS};
+use rustyscheme::span::{self, Position, Span};
+use rustyscheme::stdlib::{get_std_env, set_command_line};
+use rustyscheme::tokenise::TokenIterator;
 
+use std::env;
+use std::fs;
 use std::io;
-use std::io::Write;
+use std::io::{IsTerminal, Write};
+use std::mem;
+use std::ops::ControlFlow;
+use std::path::Path;
+use std::process;
+use std::sync::OnceLock;
+
+/// Stops the REPL's evaluation. Triggered by SIGINT.
+static REPL_INTERRUPT: OnceLock<Interrupt> = OnceLock::new();
 
+/// rustyscheme [script.scm [args...]]
+/// With no arguments start the REPL, otherwise run the script
 fn main() {
-    let prompt = "demo> ";
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    // exiting only once everything has been dropped, so the REPL's history is saved
+    let status = if args.is_empty() {
+        repl()
+    } else {
+        run_script(args)
+    };
+    process::exit(status);
+}
+
+/// Run a source file non-interactively, returning the exit status
+/// `args` is the name of the file followed by the arguments for the script
+fn run_script(args: Vec<String>) -> i32 {
+    let file = args[0].clone();
+    set_command_line(args);
     let env = get_std_env();
 
-    'input: while let Ok(s) = readline(prompt) {
-        add_history(&s).unwrap();
+    // libraries can be kept next to the script
+    if let Some(dir) = Path::new(&file).parent() {
+        let libs = env.borrow().libraries().unwrap();
+        libs.borrow_mut().add_search_path(dir);
+    }
+
+    match load_file(&file, &env) {
+        Ok(()) => 0,
+        Err(Stop::Exit(status)) => status,
+        Err(Stop::Error(report)) => {
+            eprintln!("{}", report);
+            1
+        }
+    }
+}
+
+/// Why a file stopped being evaluated before its end
+enum Stop {
+    /// The code called `exit` with this status
+    Exit(i32),
+    /// An error, along with the message to show for it
+    Error(String),
+}
 
-        let code = ast::ObjectIterator::from(s.chars());
+/// Evaluate each form in a source file, stopping at the first error or call to `exit`
+fn load_file(file: &str, env: &PackedEnv) -> Result<(), Stop> {
+    let source = fs::read_to_string(file).map_err(|e| Stop::Error(format!("{}: {}", file, e)))?;
 
-        for scm_obj in code {
-            let res = match scm_obj.map(|obj| obj.exec(&env)) {
-                Ok(Ok(r)) => r,
-                Err(e) => {
-                    println!("Parse Error: {:?}", e);
-                    continue 'input;
-                }
-                Ok(Err(e)) => {
-                    println!("{}", e.to_string());
-                    continue 'input;
+    for scm_obj in ast::parse_source(&source) {
+        let res = scm_obj.map(|obj| obj.exec(env));
+        // keep the script's output in order with the error message
+        io::stdout().flush().unwrap();
+
+        match res {
+            Ok(Ok(_)) => (),
+            Err(e) => {
+                let msg = format!("Parse Error: {:?}", e.root());
+                return Err(Stop::Error(error_report(file, &source, e.span(), &msg)));
+            }
+            Ok(Err(e)) => {
+                if let Some(status) = exit_status(&e) {
+                    return Err(Stop::Exit(status));
                 }
-            };
+                return Err(Stop::Error(runtime_error_report(file, &source, &e)));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// ANSI colour codes for REPL output
+const VALUE_COLOUR: &str = "32";
+const ERROR_COLOUR: &str = "31";
+
+/// The interactive read-eval-print loop
+/// Returns the status the program should exit with
+fn repl() -> i32 {
+    let prompt = "demo> ";
+    // shown while a form is spread over several lines
+    let continuation_prompt = "...> ";
+    let env = get_std_env();
+    // Ctrl-C while code is running stops it
+    let interrupt = REPL_INTERRUPT.get_or_init(Interrupt::new);
+    env.borrow_mut().set_interrupt(interrupt.clone());
+    let history = env::var_os("HOME").map(|home| Path::new(&home).join(".rustyscheme_history"));
+    let mut editor = Editor::new(history);
+    let colour = io::stdout().is_terminal();
+    // everything entered so far. Source locations refer to this so that errors in functions
+    // defined by earlier input can be shown.
+    let mut transcript = String::new();
+    // lines of input which haven't been evaluated yet because they don't finish a form
+    let mut input = String::new();
+    let mut state = ast::InputState::new();
+    let mut start = Position::start();
 
-            if let SchemeObject::None = *res {
-                println!("None")
-            } else {
-                println!("{}", res)
+    loop {
+        editor.set_completions(completions(&env));
+
+        let prompt = if input.is_empty() {
+            prompt
+        } else {
+            continuation_prompt
+        };
+        let s = match editor.read_line(prompt) {
+            Ok(s) => s,
+            // Ctrl-C throws away everything typed since the last complete form
+            Err(ReadError::Interrupted) => {
+                input.clear();
+                state = ast::InputState::new();
+                continue;
+            }
+            Err(ReadError::Eof) => return 0,
+        };
+        if !s.trim().is_empty() {
+            editor.add_history(&s);
+        }
+
+        if input.is_empty() {
+            start = Position {
+                line: transcript.lines().count() + 1,
+                col: 1,
             };
+        }
+        let line = s + "\n";
+        transcript += &line;
 
-            io::stdout().flush().unwrap();
+        if input.is_empty() && commands::is_command(&line) {
+            let flow =
+                interruptible(|| commands::run(line.trim_end(), start, &env, &transcript, colour));
+            if let ControlFlow::Break(status) = flow {
+                return status;
+            }
+            continue;
         }
+        input += &line;
+
+        state.feed(&line);
+        if state.needs_more() {
+            continue;
+        }
+
+        let source = mem::take(&mut input);
+        state = ast::InputState::new();
+        let flow = interruptible(|| eval_input(&source, start, &env, &transcript, colour));
+        if let ControlFlow::Break(status) = flow {
+            return status;
+        }
+    }
+}
+
+extern "C" fn on_sigint(_: libc::c_int) {
+    if let Some(interrupt) = REPL_INTERRUPT.get() {
+        interrupt.trigger();
+    }
+}
+
+/// Run `f` with Ctrl-C stopping the code being evaluated rather than the whole REPL
+fn interruptible<T, F: FnOnce() -> T>(f: F) -> T {
+    unsafe {
+        let old_handler = libc::signal(
+            libc::SIGINT,
+            on_sigint as extern "C" fn(libc::c_int) as libc::sighandler_t,
+        );
+        let res = f();
+        libc::signal(libc::SIGINT, old_handler);
+        res
+    }
+}
+
+/// Names for tab completion: special forms and everything bound in `env`
+fn completions(env: &PackedEnv) -> Vec<String> {
+    let mut names: Vec<String> = SPECIAL_FORMS
+        .iter()
+        .map(|(s, _)| String::from(*s))
+        .collect();
+    names.extend(
+        env.borrow()
+            .all_bindings()
+            .into_iter()
+            .map(|(name, _)| name),
+    );
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Surround `text` with the ANSI escape codes for `colour` when `enabled`
+fn paint(text: &str, colour: &str, enabled: bool) -> String {
+    if enabled {
+        format!("\x1b[{}m{}\x1b[0m", colour, text)
+    } else {
+        String::from(text)
+    }
+}
+
+/// The status scheme code asked to exit with, if `e` came from calling `exit`
+fn exit_status(e: &RuntimeError) -> Option<i32> {
+    match *e.root() {
+        RuntimeError::Exit(status) => Some(status),
+        _ => None,
     }
 }
+
+/// Evaluate the complete forms in `source`, printing the results
+/// `start` is where `source` begins in the `transcript` of the whole session
+/// Breaks with the exit status if the code called `exit`
+fn eval_input(
+    source: &str,
+    start: Position,
+    env: &PackedEnv,
+    transcript: &str,
+    colour: bool,
+) -> ControlFlow<i32> {
+    let tokens = TokenIterator::new(source.chars()).starting_at(start);
+    let code = ast::ObjectIterator::new(tokens);
+
+    for scm_obj in code {
+        let res = match scm_obj.map(|obj| obj.exec(env)) {
+            Ok(Ok(r)) => r,
+            Err(e) => {
+                let msg = format!("Parse Error: {:?}", e.root());
+                let report = error_report("<repl>", transcript, e.span(), &msg);
+                println!("{}", paint(&report, ERROR_COLOUR, colour));
+                return ControlFlow::Continue(());
+            }
+            Ok(Err(e)) => {
+                if let Some(status) = exit_status(&e) {
+                    return ControlFlow::Break(status);
+                }
+                let report = runtime_error_report("<repl>", transcript, &e);
+                println!("{}", paint(&report, ERROR_COLOUR, colour));
+                return ControlFlow::Continue(());
+            }
+        };
+
+        // print results so that they could be typed back in
+        match *res {
+            SchemeObject::None => println!("None"),
+            // each value on its own line
+            SchemeObject::Values(ref vals) => {
+                for val in vals {
+                    println!("{}", paint(&format!("{:?}", val), VALUE_COLOUR, colour))
+                }
+            }
+            _ => println!("{}", paint(&format!("{:?}", res), VALUE_COLOUR, colour)),
+        }
+
+        io::stdout().flush().unwrap();
+    }
+
+    ControlFlow::Continue(())
+}
+
+/// Format an error message, pointing at the source if we know where the error came from
+fn error_report(file: &str, source: &str, span: Option<Span>, msg: &str) -> String {
+    match span {
+        Some(span) => span::report(file, source, span, msg),
+        None => String::from(msg),
+    }
+}
+
+/// Format a runtime error along with its backtrace
+fn runtime_error_report(file: &str, source: &str, e: &RuntimeError) -> String {
+    e.report(file, source)
+}
@@ -0,0 +1,142 @@
+//! Source locations for tokens, code and errors
+
+use std::fmt;
+
+/// A position in the source text. Lines and columns count from 1.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, PartialOrd, Ord)]
+pub struct Position {
+    /// Line number
+    pub line: usize,
+    /// Column number (in characters)
+    pub col: usize,
+}
+
+impl Position {
+    /// The first character of the source
+    pub fn start() -> Self {
+        Self { line: 1, col: 1 }
+    }
+
+    /// Move past the character `c`
+    pub fn advance(&mut self, c: char) {
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+    }
+}
+
+/// Prints as line:col
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.col)
+    }
+}
+
+/// A range of source text from the first character of `start` to the last character of `end`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Span {
+    /// The first character
+    pub start: Position,
+    /// The last character
+    pub end: Position,
+}
+
+impl Span {
+    /// Create a new `Span`
+    pub fn new(start: Position, end: Position) -> Self {
+        Self { start, end }
+    }
+
+    /// A span covering one character
+    pub fn at(pos: Position) -> Self {
+        Self::new(pos, pos)
+    }
+
+    /// A span from the start of `self` to the end of `other`
+    pub fn to(self, other: Self) -> Self {
+        Self::new(self.start, other.end)
+    }
+}
+
+/// Prints the start position as line:col
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.start)
+    }
+}
+
+/// Formats an error message for the user like
+///
+/// ```text
+/// file.scm:2:5: Undefined binding: foo
+///     (display foo)
+///     ^^^^^^^^^^^^^
+/// ```
+///
+/// `source` is the full text which `span` refers to. Spans covering more than one line are
+/// marked by a single caret at their start.
+pub fn report(file: &str, source: &str, span: Span, msg: &str) -> String {
+    let mut out = format!("{}:{}: {}", file, span, msg);
+
+    if let Some(line) = source.lines().nth(span.start.line - 1) {
+        let len = if span.end.line == span.start.line && span.end.col >= span.start.col {
+            span.end.col - span.start.col + 1
+        } else {
+            1
+        };
+
+        // keep tabs so that the carets line up with the source line
+        let indent: String = line
+            .chars()
+            .take(span.start.col - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+
+        out += &format!("\n{}\n{}{}", line, indent, "^".repeat(len));
+    }
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pos(line: usize, col: usize) -> Position {
+        Position { line, col }
+    }
+
+    #[test]
+    fn advance() {
+        let mut p = Position::start();
+        p.advance('a');
+        assert_eq!(p, pos(1, 2));
+        p.advance('\n');
+        assert_eq!(p, pos(2, 1));
+    }
+
+    #[test]
+    fn report_one_line() {
+        let source = "(define x 1)\n  (display foo)\n";
+        let span = Span::new(pos(2, 3), pos(2, 15));
+
+        assert_eq!(
+            report("test.scm", source, span, "Undefined binding: foo"),
+            "test.scm:2:3: Undefined binding: foo\n  (display foo)\n  ^^^^^^^^^^^^^"
+        );
+    }
+
+    #[test]
+    fn report_many_lines() {
+        let source = "(display\n  foo)";
+        let span = Span::new(pos(1, 1), pos(2, 6));
+
+        assert_eq!(
+            report("test.scm", source, span, "oops"),
+            "test.scm:1:1: oops\n(display\n^"
+        );
+    }
+}
@@ -0,0 +1,123 @@
+//! Raising errors and inspecting the error objects caught by guard
+
+use super::{scm_bool, Lst, Ret};
+use data::env::*;
+use data::{RuntimeError, SchemeObject};
+
+use std::rc::Rc;
+
+/// Adds the error procedures from (scheme base) to `env`
+pub fn add_error_funcs(env: &PackedEnv) {
+    lib_funcs!(env,
+        /// (raise obj) passes obj to the nearest guard. Error objects caught by guard are raised
+        /// again as they were.
+        "raise" => raise(1),
+        /// (error message irritant...) raises an error object whose message is message followed
+        /// by the irritants
+        "error" => error(1..),
+        /// Is obj an error object, as caught by guard?
+        "error-object?" => is_error_object(1),
+        /// (error-object-message error-object) is the message of an error object
+        "error-object-message" => error_object_message(1),
+        /// Is obj an error object for a file which couldn't be opened?
+        "file-error?" => is_file_error(1)
+    );
+}
+
+fn raise(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match **lst.front().unwrap() {
+        SchemeObject::Condition(ref e) => Err((**e).clone()),
+        _ => Err(RuntimeError::Raised(lst.front().unwrap().clone())),
+    }
+}
+
+fn error(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let mut iter = lst.iter();
+
+    let mut msg = match **iter.next().unwrap() {
+        SchemeObject::String(ref s) => s.clone(),
+        ref o => format!("{:?}", o),
+    };
+
+    for irritant in iter {
+        msg += &format!(" {:?}", irritant);
+    }
+
+    Err(RuntimeError::Msg(msg))
+}
+
+/// The error inside an error object
+fn condition(obj: &Rc<SchemeObject>) -> Option<&RuntimeError> {
+    match **obj {
+        SchemeObject::Condition(ref e) => Some(e.root()),
+        _ => None,
+    }
+}
+
+fn is_error_object(lst: &Lst, _env: &PackedEnv) -> Ret {
+    Ok(scm_bool(condition(lst.front().unwrap()).is_some()))
+}
+
+fn error_object_message(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match condition(lst.front().unwrap()) {
+        Some(e) => Ok(Rc::new(SchemeObject::from(e.to_string()))),
+        None => Err(RuntimeError::from(
+            "error-object-message expects an error object",
+        )),
+    }
+}
+
+fn is_file_error(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match condition(lst.front().unwrap()) {
+        Some(RuntimeError::File(_)) => Ok(scm_bool(true)),
+        _ => Ok(scm_bool(false)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ast;
+    use data::*;
+    use stdlib::get_std_env;
+
+    use std::rc::Rc;
+
+    fn eval(program: &str) -> Result<Rc<SchemeObject>, RuntimeError> {
+        let env = get_std_env();
+        let mut res = Ok(Rc::new(SchemeObject::None));
+        for code in ast::ObjectIterator::from(program.chars()) {
+            res = code.unwrap().exec(&env);
+        }
+        res
+    }
+
+    #[test]
+    fn raise_object() {
+        let res = eval("(guard (e (#t (list e))) (raise \"oops\"))").unwrap();
+        assert_eq!(format!("{:?}", res), "(\"oops\")");
+
+        let err = eval("(raise \"oops\")").unwrap_err();
+        assert_eq!(
+            err.root(),
+            &RuntimeError::Raised(Rc::new(SchemeObject::from("oops")))
+        );
+    }
+
+    #[test]
+    fn error_message() {
+        let program = "(guard (e ((error-object? e) (error-object-message e)))
+                         (error \"bad thing:\" 42))";
+        assert_eq!(*eval(program).unwrap(), SchemeObject::from("bad thing: 42"));
+    }
+
+    #[test]
+    fn reraise() {
+        let program = "(guard (e ((file-error? e) \"file\"))
+                         (guard (e ((error-object? e) (raise e)))
+                           (error \"inner\")))";
+        assert_eq!(
+            eval(program).unwrap_err().root(),
+            &RuntimeError::from("inner")
+        );
+    }
+}
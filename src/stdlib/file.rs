@@ -0,0 +1,343 @@
+//! File ports and file system procedures
+
+use super::io::{set_current_input, set_current_output};
+use super::{get_none, scm_bool, Lst, Ret};
+use ast;
+use data::env::*;
+use data::port::Port;
+use data::{LoadedFile, RuntimeError, SchemeObject};
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::Path;
+use std::rc::Rc;
+
+/// Fills (scheme file)
+pub fn add_file_funcs(env: &PackedEnv) {
+    lib_funcs!(env,
+        /// (open-input-file name) is a port which reads the characters of the file
+        "open-input-file" => open_input_file(1),
+        /// (open-output-file name) is a port which writes characters to the file, which is created
+        /// or emptied
+        "open-output-file" => open_output_file(1),
+        /// (open-binary-input-file name) is a port which reads the bytes of the file
+        "open-binary-input-file" => open_binary_input_file(1),
+        /// (open-binary-output-file name) is a port which writes bytes to the file, which is
+        /// created or emptied
+        "open-binary-output-file" => open_binary_output_file(1),
+        /// (call-with-input-file name proc) calls proc with a port reading the file. The port is
+        /// closed when proc returns.
+        "call-with-input-file" => call_with_input_file(2),
+        /// (call-with-output-file name proc) calls proc with a port writing to the file. The port
+        /// is closed when proc returns.
+        "call-with-output-file" => call_with_output_file(2),
+        /// (with-input-from-file name thunk) calls thunk with the file as the current input port
+        "with-input-from-file" => with_input_from_file(2),
+        /// (with-output-to-file name thunk) calls thunk with the file as the current output port
+        "with-output-to-file" => with_output_to_file(2),
+        /// Is there a file called name?
+        "file-exists?" => file_exists(1),
+        /// (delete-file name) removes the file
+        "delete-file" => delete_file(1)
+    );
+}
+
+/// Fills (scheme load)
+pub fn add_load_funcs(env: &PackedEnv) {
+    lib_funcs!(env,
+        /// (load name) evaluates the file in the current environment
+        "load" => load(1)
+    );
+}
+
+/// Report a failed file operation to scheme. These can be told apart from other errors with
+/// file-error?
+fn file_error(name: &str, e: &io::Error) -> RuntimeError {
+    RuntimeError::File(format!("{}: {}", name, e))
+}
+
+/// A file name argument
+fn file_name(obj: &SchemeObject) -> Result<&str, RuntimeError> {
+    match *obj {
+        SchemeObject::String(ref s) => Ok(s),
+        ref o => Err(RuntimeError::from(format!(
+            "Expected a file name, got {:?}",
+            o
+        ))),
+    }
+}
+
+/// Open `name` for reading
+fn open_input(name: &str, binary: bool) -> Result<Port, RuntimeError> {
+    let file = File::open(name).map_err(|e| file_error(name, &e))?;
+
+    if binary {
+        Ok(Port::binary_input(file))
+    } else {
+        Ok(Port::input_buffered(BufReader::new(file)))
+    }
+}
+
+/// Create (or truncate) `name` for writing
+fn open_output(name: &str, binary: bool) -> Result<Port, RuntimeError> {
+    let file = BufWriter::new(File::create(name).map_err(|e| file_error(name, &e))?);
+
+    if binary {
+        Ok(Port::binary_output(file))
+    } else {
+        Ok(Port::output(file))
+    }
+}
+
+fn open_input_file(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let port = open_input(file_name(lst.front().unwrap())?, false)?;
+    Ok(Rc::new(SchemeObject::Port(port)))
+}
+
+fn open_output_file(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let port = open_output(file_name(lst.front().unwrap())?, false)?;
+    Ok(Rc::new(SchemeObject::Port(port)))
+}
+
+fn open_binary_input_file(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let port = open_input(file_name(lst.front().unwrap())?, true)?;
+    Ok(Rc::new(SchemeObject::Port(port)))
+}
+
+fn open_binary_output_file(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let port = open_output(file_name(lst.front().unwrap())?, true)?;
+    Ok(Rc::new(SchemeObject::Port(port)))
+}
+
+/// Call `func` with `port` then close the port, even if `func` failed
+fn call_with_port(port: Port, func: &Rc<SchemeObject>, env: &PackedEnv) -> Ret {
+    let res = func.apply(&[Rc::new(SchemeObject::Port(port.clone()))], env);
+    let closed = port.close().map_err(|e| file_error("close", &e));
+
+    let val = res?;
+    closed?;
+    Ok(val)
+}
+
+/// (call-with-input-file name proc)
+fn call_with_input_file(lst: &Lst, env: &PackedEnv) -> Ret {
+    let port = open_input(file_name(lst.front().unwrap())?, false)?;
+    call_with_port(port, lst.back().unwrap(), env)
+}
+
+/// (call-with-output-file name proc)
+fn call_with_output_file(lst: &Lst, env: &PackedEnv) -> Ret {
+    let port = open_output(file_name(lst.front().unwrap())?, false)?;
+    call_with_port(port, lst.back().unwrap(), env)
+}
+
+/// (with-input-from-file name thunk)
+/// The current input port is restored and the file closed when thunk returns
+fn with_input_from_file(lst: &Lst, env: &PackedEnv) -> Ret {
+    let port = open_input(file_name(lst.front().unwrap())?, false)?;
+
+    let old = set_current_input(env, port.clone());
+    let res = lst.back().unwrap().apply(&[], env);
+    set_current_input(env, old);
+
+    port.close().map_err(|e| file_error("close", &e))?;
+    res
+}
+
+/// (with-output-to-file name thunk)
+/// The current output port is restored and the file closed when thunk returns
+fn with_output_to_file(lst: &Lst, env: &PackedEnv) -> Ret {
+    let port = open_output(file_name(lst.front().unwrap())?, false)?;
+
+    let old = set_current_output(env, port.clone());
+    let res = lst.back().unwrap().apply(&[], env);
+    set_current_output(env, old);
+
+    let closed = port.close().map_err(|e| file_error("close", &e));
+    let val = res?;
+    closed?;
+    Ok(val)
+}
+
+fn file_exists(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let name = file_name(lst.front().unwrap())?;
+    Ok(scm_bool(Path::new(name).exists()))
+}
+
+fn delete_file(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let name = file_name(lst.front().unwrap())?;
+    fs::remove_file(name).map_err(|e| file_error(name, &e))?;
+    Ok(get_none())
+}
+
+/// (load name)
+/// Evaluates the file in the environment load was called from. Errors raised by the file are
+/// wrapped in `RuntimeError::Loaded` so that their locations are reported in the loaded file.
+fn load(lst: &Lst, env: &PackedEnv) -> Ret {
+    let name = file_name(lst.front().unwrap())?;
+    let source = fs::read_to_string(name).map_err(|e| file_error(name, &e))?;
+
+    let loaded = |e: RuntimeError| {
+        let file = LoadedFile {
+            name: String::from(name),
+            source: source.clone(),
+        };
+        RuntimeError::Loaded(Rc::new(file), Box::new(e))
+    };
+
+    let mut res = get_none();
+    for code in ast::parse_source(&source) {
+        let code = code.map_err(|e| {
+            let err = RuntimeError::from(format!("Parse Error: {:?}", e.root()));
+            loaded(match e.span() {
+                Some(span) => err.at(span),
+                None => err,
+            })
+        })?;
+        res = code.exec(env).map_err(loaded)?;
+    }
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use ast;
+    use data::env::PackedEnv;
+    use data::*;
+    use stdlib::{get_std_env, get_std_env_with, Capability, EnvOptions};
+
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::rc::Rc;
+
+    fn eval_in(env: &PackedEnv, program: &str) -> Result<Rc<SchemeObject>, RuntimeError> {
+        let mut res = Ok(Rc::new(SchemeObject::None));
+        for code in ast::ObjectIterator::from(program.chars()) {
+            res = code.unwrap().exec(env);
+        }
+        res
+    }
+
+    /// A path in the temporary directory which is unique to this test
+    fn temp_path(test: &str) -> PathBuf {
+        env::temp_dir().join(format!("rustyscheme-{}-{}", process::id(), test))
+    }
+
+    #[test]
+    fn round_trip() {
+        let path = temp_path("round-trip");
+        let name = path.to_str().unwrap();
+        let program = format!(
+            "(call-with-output-file \"{0}\" (lambda (p) (write-string \"hello\" p) (newline p)))
+             (with-output-to-file \"{0}\" (lambda () (display \"bye\")))
+             (define in (open-input-file \"{0}\"))
+             (define line (read-line in))
+             (close-port in)
+             (list (file-exists? \"{0}\") line (read-char (open-input-file \"{0}\")))",
+            name
+        );
+
+        let res = eval_in(&get_std_env(), &program).unwrap();
+        assert_eq!(format!("{:?}", res), "(#t \"bye\" #\\b)");
+
+        eval_in(&get_std_env(), &format!("(delete-file \"{}\")", name)).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn binary_files() {
+        let path = temp_path("binary");
+        let name = path.to_str().unwrap();
+        let program = format!(
+            "(define out (open-binary-output-file \"{0}\"))
+             (write-bytevector (bytevector 0 255) out)
+             (close-port out)
+             (read-bytevector 10 (open-binary-input-file \"{0}\"))",
+            name
+        );
+
+        let res = eval_in(&get_std_env(), &program).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(*res, SchemeObject::Bytevector(vec![0, 255]));
+    }
+
+    #[test]
+    fn file_error() {
+        let path = temp_path("missing");
+        let program = format!(
+            "(guard (e ((file-error? e) \"missing\"))
+               (open-input-file \"{}\"))",
+            path.to_str().unwrap()
+        );
+
+        let res = eval_in(&get_std_env(), &program).unwrap();
+        assert_eq!(*res, SchemeObject::from("missing"));
+    }
+
+    #[test]
+    fn load() {
+        let path = temp_path("load");
+        fs::write(
+            &path,
+            "#!/usr/bin/env rustyscheme\n(define greeting \"hi\")",
+        )
+        .unwrap();
+
+        let env = get_std_env();
+        let program = format!("(load \"{}\") greeting", path.to_str().unwrap());
+        let res = eval_in(&env, &program);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(*res.unwrap(), SchemeObject::from("hi"));
+    }
+
+    #[test]
+    fn nested_load_report() {
+        let inner = temp_path("nested-inner.scm");
+        let outer = temp_path("nested-outer.scm");
+        fs::write(&inner, "(define x \"x\")\n\n\n   (nope x)\n").unwrap();
+        fs::write(
+            &outer,
+            format!("(define y 1)\n(load \"{}\")\n", inner.to_str().unwrap()),
+        )
+        .unwrap();
+
+        let env = get_std_env();
+        let program = format!("(load \"{}\")", outer.to_str().unwrap());
+        let err = eval_in(&env, &program).unwrap_err();
+        fs::remove_file(&inner).unwrap();
+        fs::remove_file(&outer).unwrap();
+
+        // reported in the innermost file, then where each file was loaded from
+        let (inner, outer) = (inner.to_str().unwrap(), outer.to_str().unwrap());
+        let expected = format!(
+            "{0}:4:4: Undefined binding: nope\n   (nope x)\n   ^^^^^^^^\n\
+             Loaded from {1}:2:1\n\
+             Backtrace (most recent call first):\n  0: (load \"{0}\") at {1}:2:1\n\
+             Loaded from <repl>:1:1\n\
+             Backtrace (most recent call first):\n  0: (load \"{1}\") at <repl>:1:1",
+            inner, outer
+        );
+        assert_eq!(err.report("<repl>", &program), expected);
+    }
+
+    #[test]
+    fn disabled() {
+        let env = get_std_env_with(&EnvOptions::sandbox(&[
+            Capability::Pure,
+            Capability::Io,
+            Capability::Process,
+        ]));
+        let err = eval_in(&env, "(open-input-file \"x\")").unwrap_err();
+        assert_eq!(
+            err.root(),
+            &RuntimeError::NameLookup(String::from("open-input-file"))
+        );
+
+        // string ports are still there
+        assert!(eval_in(&env, "(open-input-string \"x\")").is_ok());
+    }
+}
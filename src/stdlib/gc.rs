@@ -0,0 +1,69 @@
+//! Running the garbage collector from scheme
+
+use super::{Lst, Ret};
+use data::env::*;
+use data::{gc, SchemeObject, SymFrom};
+
+use std::collections::LinkedList;
+use std::rc::Rc;
+
+/// Fills (rustyscheme gc)
+pub fn add_gc_funcs(env: &PackedEnv) {
+    lib_funcs!(env,
+        /// Reclaim the functions and environments which only refer to each other. Returns how
+        /// many objects were reclaimed.
+        "gc" => scm_gc(0),
+        /// A list of (name value) pairs describing the collector: collections, freed,
+        /// environments, allocated and threshold (#f if it only runs when asked)
+        "gc-stats" => gc_stats(0)
+    );
+}
+
+/// (gc)
+fn scm_gc(_lst: &Lst, _env: &PackedEnv) -> Ret {
+    Ok(Rc::new(SchemeObject::Integer(gc::collect() as i64)))
+}
+
+/// (gc-stats)
+fn gc_stats(_lst: &Lst, _env: &PackedEnv) -> Ret {
+    let stats = gc::stats();
+    let entry = |name: &str, val: SchemeObject| {
+        let mut pair = LinkedList::new();
+        pair.push_back(SchemeObject::sym_from(name));
+        pair.push_back(val);
+        SchemeObject::from(pair)
+    };
+    let count = |n: usize| SchemeObject::Integer(n as i64);
+
+    let mut out = LinkedList::new();
+    out.push_back(entry("collections", count(stats.collections)));
+    out.push_back(entry("freed", count(stats.freed)));
+    out.push_back(entry("environments", count(stats.environments)));
+    out.push_back(entry("allocated", count(stats.allocated)));
+    out.push_back(entry(
+        "threshold",
+        stats.threshold.map_or(SchemeObject::from(false), count),
+    ));
+    Ok(Rc::new(SchemeObject::from(out)))
+}
+
+#[cfg(test)]
+mod tests {
+    use ast;
+    use data::gc;
+    use stdlib::get_std_env;
+
+    #[test]
+    fn scm_gc() {
+        gc::set_threshold(None);
+        gc::collect();
+        let env = get_std_env();
+        let code = ast::parse_tokens("(gc) (gc-stats)".chars()).unwrap();
+
+        // everything is still in use
+        assert_eq!(format!("{:?}", code[0].exec(&env).unwrap()), "0");
+        let stats = format!("{:?}", code[1].exec(&env).unwrap());
+        assert!(stats.starts_with("((collections "), "{}", stats);
+        assert!(stats.contains("(threshold "), "{}", stats);
+    }
+}
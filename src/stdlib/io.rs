@@ -0,0 +1,852 @@
+//! Ports and input/output procedures
+
+use super::{get_none, scm_bool, Lst, Ret};
+use ast::ObjectIterator;
+use data::env::*;
+use data::port::{self, CurrentPorts, Port};
+use data::print::{print, Style};
+use data::{RuntimeError, SchemeObject};
+use tokenise::TokenIterator;
+
+use std::io;
+use std::mem;
+use std::rc::Rc;
+
+/// Adds the port procedures from (scheme base) to `env`
+pub fn add_port_funcs(env: &PackedEnv) {
+    lib_funcs!(env,
+        /// (current-input-port) is the port read from when no port is given
+        "current-input-port" => current_input_port(0),
+        /// (current-output-port) is the port written to when no port is given
+        "current-output-port" => current_output_port(0),
+        /// (current-error-port) is the port errors are written to
+        "current-error-port" => current_error_port(0),
+        /// (open-input-string string) is a port which reads the characters of string
+        "open-input-string" => open_input_string(1),
+        /// (open-output-string) is a port which collects what is written to it in a string
+        "open-output-string" => open_output_string(0),
+        /// (get-output-string port) is everything written so far to a port from open-output-string
+        "get-output-string" => get_output_string(1),
+        /// (read-char [port]) is the next character, or the eof object at the end of the input
+        "read-char" => read_char(0..=1),
+        /// (peek-char [port]) is the next character without reading it, or the eof object
+        "peek-char" => peek_char(0..=1),
+        /// (read-line [port]) is the next line without its line ending, or the eof object
+        "read-line" => read_line(0..=1),
+        /// Can a character be read from port (or the current input port) without waiting?
+        "char-ready?" => char_ready(0..=1),
+        /// (newline [port]) writes a line ending
+        "newline" => newline(0..=1),
+        /// (write-char char [port]) writes char
+        "write-char" => write_char(1..=2),
+        /// (write-string string [port]) writes the characters of string
+        "write-string" => write_string(1..=2),
+        /// (flush-output-port [port]) writes out anything the port has buffered
+        "flush-output-port" => flush_output_port(0..=1),
+        /// Is obj a port?
+        "port?" => is_port(1),
+        /// Is obj a port which can be read from?
+        "input-port?" => is_input_port(1),
+        /// Is obj a port which can be written to?
+        "output-port?" => is_output_port(1),
+        /// (eof-object) is the object returned at the end of the input
+        "eof-object" => eof_object(0),
+        /// Is obj the eof object?
+        "eof-object?" => is_eof_object(1),
+        /// (close-port port) means nothing more can be read from or written to port
+        "close-port" => close_port(1),
+        /// (close-input-port port) closes a port which can be read from
+        "close-input-port" => close_input_port(1),
+        /// (close-output-port port) closes a port which can be written to
+        "close-output-port" => close_output_port(1),
+        /// Is obj a port of bytes?
+        "binary-port?" => is_binary_port(1),
+        /// Is obj a port of characters?
+        "textual-port?" => is_textual_port(1),
+        /// (open-input-bytevector bytevector) is a port which reads the bytes of bytevector
+        "open-input-bytevector" => open_input_bytevector(1),
+        /// (open-output-bytevector) is a port which collects the bytes written to it
+        "open-output-bytevector" => open_output_bytevector(0),
+        /// (get-output-bytevector port) is everything written so far to a port from
+        /// open-output-bytevector
+        "get-output-bytevector" => get_output_bytevector(1),
+        /// (read-u8 [port]) is the next byte, or the eof object at the end of the input
+        "read-u8" => read_u8(0..=1),
+        /// (peek-u8 [port]) is the next byte without reading it, or the eof object
+        "peek-u8" => peek_u8(0..=1),
+        /// Can a byte be read from port (the current input port by default) without waiting?
+        "u8-ready?" => u8_ready(0..=1),
+        /// (read-bytevector k port) is up to k bytes, or the eof object at the end of the input
+        "read-bytevector" => read_bytevector(2),
+        /// (write-u8 byte port) writes byte
+        "write-u8" => write_u8(2),
+        /// (write-bytevector bytevector port) writes the bytes of bytevector
+        "write-bytevector" => write_bytevector(2),
+        /// (bytevector byte...) is a bytevector holding the bytes
+        "bytevector" => bytevector(0..),
+        /// Is obj a bytevector?
+        "bytevector?" => is_bytevector(1),
+        /// (bytevector-length bytevector) is the number of bytes in bytevector
+        "bytevector-length" => bytevector_length(1),
+        /// (bytevector-u8-ref bytevector k) is byte k of bytevector, counting from 0
+        "bytevector-u8-ref" => bytevector_u8_ref(2)
+    );
+}
+
+/// Fills (scheme write)
+pub fn add_write_funcs(env: &PackedEnv) {
+    lib_funcs!(env,
+        /// (display obj [port]) writes obj for people to read: strings and characters are written
+        /// without quotes
+        "display" => display(1..=2),
+        /// (write obj [port]) writes obj so that read can read it back. Shared structure is only
+        /// labelled if it makes a cycle.
+        "write" => write(1..=2),
+        /// (write-shared obj [port]) is like write, but all shared structure is labelled.
+        /// Only records and foreign objects can be shared: lists are copied, so
+        /// (let ((x (list 1 2))) (write-shared (list x x))) prints ((1 2) (1 2)). There are no
+        /// pairs either, so (a . b) reads as a list of three symbols and is written (a |.| b).
+        "write-shared" => write_shared(1..=2),
+        /// (write-simple obj [port]) is like write, without labels. Never finishes for cycles.
+        "write-simple" => write_simple(1..=2)
+    );
+}
+
+/// Fills (scheme read)
+pub fn add_read_funcs(env: &PackedEnv) {
+    lib_funcs!(env,
+        /// (read [port]) is the next datum, or the eof object at the end of the input
+        "read" => read(0..=1),
+        /// (read-from-string string) is the first datum in string
+        "read-from-string" => read_from_string(1)
+    );
+}
+
+/// Report an I/O error to scheme
+fn io_error(e: &io::Error) -> RuntimeError {
+    RuntimeError::from(format!("I/O error: {}", e))
+}
+
+/// The ports code run in `env` uses when it isn't given one
+fn current_ports(env: &PackedEnv) -> CurrentPorts {
+    match env.borrow().ports() {
+        Some(ports) => ports.borrow().clone(),
+        None => port::current_ports(),
+    }
+}
+
+/// Replace the current input port for code run in `env`, returning the old one
+pub fn set_current_input(env: &PackedEnv, port: Port) -> Port {
+    match env.borrow().ports() {
+        Some(ports) => mem::replace(&mut ports.borrow_mut().input, port),
+        None => port::set_current_input(port),
+    }
+}
+
+/// Replace the current output port for code run in `env`, returning the old one
+pub fn set_current_output(env: &PackedEnv, port: Port) -> Port {
+    match env.borrow().ports() {
+        Some(ports) => mem::replace(&mut ports.borrow_mut().output, port),
+        None => port::set_current_output(port),
+    }
+}
+
+/// An optional port argument which has to be an input port
+fn input_port(arg: Option<&Rc<SchemeObject>>, env: &PackedEnv) -> Result<Port, RuntimeError> {
+    match arg.map(|a| &**a) {
+        None => Ok(current_ports(env).input),
+        Some(SchemeObject::Port(p)) if p.is_input() => Ok(p.clone()),
+        Some(o) => Err(RuntimeError::from(format!(
+            "Expected an input port, got {:?}",
+            o
+        ))),
+    }
+}
+
+/// An optional port argument which has to be an output port
+fn output_port(arg: Option<&Rc<SchemeObject>>, env: &PackedEnv) -> Result<Port, RuntimeError> {
+    match arg.map(|a| &**a) {
+        None => Ok(current_ports(env).output),
+        Some(SchemeObject::Port(p)) if p.is_output() => Ok(p.clone()),
+        Some(o) => Err(RuntimeError::from(format!(
+            "Expected an output port, got {:?}",
+            o
+        ))),
+    }
+}
+
+/// An optional port argument which has to be a binary input port. Defaults to the current input
+/// port, which is only binary if an embedder made it so.
+fn binary_input_port(
+    arg: Option<&Rc<SchemeObject>>,
+    env: &PackedEnv,
+) -> Result<Port, RuntimeError> {
+    match arg.map(|a| &**a) {
+        None => {
+            let port = current_ports(env).input;
+            if port.is_binary() {
+                Ok(port)
+            } else {
+                Err(RuntimeError::from("The current input port isn't binary"))
+            }
+        }
+        Some(SchemeObject::Port(p)) if p.is_input() && p.is_binary() => Ok(p.clone()),
+        Some(o) => Err(RuntimeError::from(format!(
+            "Expected a binary input port, got {:?}",
+            o
+        ))),
+    }
+}
+
+/// A port argument which has to be a binary output port
+fn binary_output_port(arg: Option<&Rc<SchemeObject>>) -> Result<Port, RuntimeError> {
+    match arg.map(|a| &**a) {
+        None => Err(RuntimeError::from("Expected a binary output port")),
+        Some(SchemeObject::Port(p)) if p.is_output() && p.is_binary() => Ok(p.clone()),
+        Some(o) => Err(RuntimeError::from(format!(
+            "Expected a binary output port, got {:?}",
+            o
+        ))),
+    }
+}
+
+/// An integer argument which has to fit in a byte
+fn byte(obj: &SchemeObject) -> Result<u8, RuntimeError> {
+    match *obj {
+        SchemeObject::Integer(i) if (0..=255).contains(&i) => Ok(i as u8),
+        ref o => Err(RuntimeError::from(format!("Expected a byte, got {:?}", o))),
+    }
+}
+
+/// A non-negative integer argument
+fn index(obj: &SchemeObject) -> Result<usize, RuntimeError> {
+    match *obj {
+        SchemeObject::Integer(i) if i >= 0 => Ok(i as usize),
+        ref o => Err(RuntimeError::from(format!(
+            "Expected a non-negative integer, got {:?}",
+            o
+        ))),
+    }
+}
+
+/// Shared implementation of display and the write procedures: (f obj [port])
+fn print_objs(lst: &Lst, env: &PackedEnv, style: Style) -> Ret {
+    output_port(lst.iter().nth(1), env)?
+        .write_str(&print(lst.front().unwrap(), style))
+        .map_err(|e| io_error(&e))?;
+
+    Ok(get_none())
+}
+
+fn display(lst: &Lst, env: &PackedEnv) -> Ret {
+    print_objs(lst, env, Style::Display)
+}
+
+fn write(lst: &Lst, env: &PackedEnv) -> Ret {
+    print_objs(lst, env, Style::Write)
+}
+
+fn write_shared(lst: &Lst, env: &PackedEnv) -> Ret {
+    print_objs(lst, env, Style::WriteShared)
+}
+
+fn write_simple(lst: &Lst, env: &PackedEnv) -> Ret {
+    print_objs(lst, env, Style::WriteSimple)
+}
+
+fn newline(lst: &Lst, env: &PackedEnv) -> Ret {
+    output_port(lst.front(), env)?
+        .write_str("\n")
+        .map_err(|e| io_error(&e))?;
+
+    Ok(get_none())
+}
+
+fn write_char(lst: &Lst, env: &PackedEnv) -> Ret {
+    let c = match **lst.front().unwrap() {
+        SchemeObject::Char(c) => c,
+        ref o => return Err(RuntimeError::from(format!("Expected a char, got {:?}", o))),
+    };
+
+    output_port(lst.iter().nth(1), env)?
+        .write_str(&c.to_string())
+        .map_err(|e| io_error(&e))?;
+
+    Ok(get_none())
+}
+
+fn write_string(lst: &Lst, env: &PackedEnv) -> Ret {
+    match **lst.front().unwrap() {
+        SchemeObject::String(ref s) => output_port(lst.iter().nth(1), env)?
+            .write_str(s)
+            .map_err(|e| io_error(&e))?,
+        ref o => {
+            return Err(RuntimeError::from(format!(
+                "Expected a string, got {:?}",
+                o
+            )))
+        }
+    };
+
+    Ok(get_none())
+}
+
+fn flush_output_port(lst: &Lst, env: &PackedEnv) -> Ret {
+    output_port(lst.front(), env)?
+        .flush()
+        .map_err(|e| io_error(&e))?;
+
+    Ok(get_none())
+}
+
+/// Parse the next datum from `port`, or return the eof object at the end of the input
+/// Characters are only read from the port up to the end of the datum
+fn read_datum(port: &Port) -> Ret {
+    let mut chars = port.chars();
+
+    let (res, lookahead) = {
+        let mut objects = ObjectIterator::new(TokenIterator::new(&mut chars));
+        let res = objects.next();
+        (res, objects.into_inner().take_lookahead())
+    };
+
+    // the character which ended the datum belongs to whatever is read next
+    if let Some(c) = lookahead {
+        port.unread_char(c).map_err(|e| io_error(&e))?;
+    }
+
+    if let Some(e) = chars.take_error() {
+        return Err(io_error(&e));
+    }
+
+    match res {
+        None => Ok(Rc::new(SchemeObject::Eof)),
+        Some(Ok(obj)) => Ok(Rc::new(obj)),
+        Some(Err(e)) => Err(RuntimeError::from(format!(
+            "read: Parse Error: {:?}",
+            e.root()
+        ))),
+    }
+}
+
+/// (read [port])
+fn read(lst: &Lst, env: &PackedEnv) -> Ret {
+    read_datum(&input_port(lst.front(), env)?)
+}
+
+/// (read-from-string string)
+/// The first datum in string
+fn read_from_string(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match **lst.front().unwrap() {
+        SchemeObject::String(ref s) => read_datum(&Port::input_string(s)),
+        ref o => Err(RuntimeError::from(format!(
+            "Expected a string, got {:?}",
+            o
+        ))),
+    }
+}
+
+/// A character or the eof object
+fn char_or_eof(c: Option<char>) -> Rc<SchemeObject> {
+    Rc::new(c.map_or(SchemeObject::Eof, SchemeObject::Char))
+}
+
+fn read_char(lst: &Lst, env: &PackedEnv) -> Ret {
+    let c = input_port(lst.front(), env)?
+        .read_char()
+        .map_err(|e| io_error(&e))?;
+
+    Ok(char_or_eof(c))
+}
+
+fn peek_char(lst: &Lst, env: &PackedEnv) -> Ret {
+    let c = input_port(lst.front(), env)?
+        .peek_char()
+        .map_err(|e| io_error(&e))?;
+
+    Ok(char_or_eof(c))
+}
+
+fn read_line(lst: &Lst, env: &PackedEnv) -> Ret {
+    let line = input_port(lst.front(), env)?
+        .read_line()
+        .map_err(|e| io_error(&e))?;
+
+    Ok(Rc::new(
+        line.map_or(SchemeObject::Eof, SchemeObject::String),
+    ))
+}
+
+fn char_ready(lst: &Lst, env: &PackedEnv) -> Ret {
+    let ready = input_port(lst.front(), env)?
+        .char_ready()
+        .map_err(|e| io_error(&e))?;
+
+    Ok(scm_bool(ready))
+}
+
+fn current_input_port(_lst: &Lst, env: &PackedEnv) -> Ret {
+    Ok(Rc::new(SchemeObject::Port(current_ports(env).input)))
+}
+
+fn current_output_port(_lst: &Lst, env: &PackedEnv) -> Ret {
+    Ok(Rc::new(SchemeObject::Port(current_ports(env).output)))
+}
+
+fn current_error_port(_lst: &Lst, env: &PackedEnv) -> Ret {
+    Ok(Rc::new(SchemeObject::Port(current_ports(env).error)))
+}
+
+fn open_input_string(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match **lst.front().unwrap() {
+        SchemeObject::String(ref s) => Ok(Rc::new(SchemeObject::Port(Port::input_string(s)))),
+        ref o => Err(RuntimeError::from(format!(
+            "Expected a string, got {:?}",
+            o
+        ))),
+    }
+}
+
+fn open_output_string(_lst: &Lst, _env: &PackedEnv) -> Ret {
+    Ok(Rc::new(SchemeObject::Port(Port::output_string())))
+}
+
+fn get_output_string(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let contents = match **lst.front().unwrap() {
+        SchemeObject::Port(ref p) => p.output_string_contents(),
+        _ => None,
+    };
+
+    match contents {
+        Some(s) => Ok(Rc::new(SchemeObject::from(s))),
+        None => Err(RuntimeError::from(
+            "get-output-string expects a port created by open-output-string",
+        )),
+    }
+}
+
+fn is_port(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match **lst.front().unwrap() {
+        SchemeObject::Port(_) => Ok(scm_bool(true)),
+        _ => Ok(scm_bool(false)),
+    }
+}
+
+fn is_input_port(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match **lst.front().unwrap() {
+        SchemeObject::Port(ref p) => Ok(scm_bool(p.is_input())),
+        _ => Ok(scm_bool(false)),
+    }
+}
+
+fn is_output_port(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match **lst.front().unwrap() {
+        SchemeObject::Port(ref p) => Ok(scm_bool(p.is_output())),
+        _ => Ok(scm_bool(false)),
+    }
+}
+
+fn eof_object(_lst: &Lst, _env: &PackedEnv) -> Ret {
+    Ok(Rc::new(SchemeObject::Eof))
+}
+
+fn is_eof_object(lst: &Lst, _env: &PackedEnv) -> Ret {
+    Ok(scm_bool(**lst.front().unwrap() == SchemeObject::Eof))
+}
+
+fn close_port(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match **lst.front().unwrap() {
+        SchemeObject::Port(ref p) => p.close().map_err(|e| io_error(&e))?,
+        ref o => return Err(RuntimeError::from(format!("Expected a port, got {:?}", o))),
+    }
+
+    Ok(get_none())
+}
+
+fn close_input_port(lst: &Lst, env: &PackedEnv) -> Ret {
+    input_port(lst.front(), env)?
+        .close()
+        .map_err(|e| io_error(&e))?;
+
+    Ok(get_none())
+}
+
+fn close_output_port(lst: &Lst, env: &PackedEnv) -> Ret {
+    output_port(lst.front(), env)?
+        .close()
+        .map_err(|e| io_error(&e))?;
+
+    Ok(get_none())
+}
+
+fn is_binary_port(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match **lst.front().unwrap() {
+        SchemeObject::Port(ref p) => Ok(scm_bool(p.is_binary())),
+        _ => Ok(scm_bool(false)),
+    }
+}
+
+fn is_textual_port(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match **lst.front().unwrap() {
+        SchemeObject::Port(ref p) => Ok(scm_bool(p.is_textual())),
+        _ => Ok(scm_bool(false)),
+    }
+}
+
+fn open_input_bytevector(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match **lst.front().unwrap() {
+        SchemeObject::Bytevector(ref b) => Ok(Rc::new(SchemeObject::Port(Port::input_bytevector(
+            b.clone(),
+        )))),
+        ref o => Err(RuntimeError::from(format!(
+            "Expected a bytevector, got {:?}",
+            o
+        ))),
+    }
+}
+
+fn open_output_bytevector(_lst: &Lst, _env: &PackedEnv) -> Ret {
+    Ok(Rc::new(SchemeObject::Port(Port::output_bytevector())))
+}
+
+fn get_output_bytevector(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let contents = match **lst.front().unwrap() {
+        SchemeObject::Port(ref p) => p.output_bytevector_contents(),
+        _ => None,
+    };
+
+    match contents {
+        Some(b) => Ok(Rc::new(SchemeObject::Bytevector(b))),
+        None => Err(RuntimeError::from(
+            "get-output-bytevector expects a port created by open-output-bytevector",
+        )),
+    }
+}
+
+/// A byte or the eof object
+fn byte_or_eof(b: Option<u8>) -> Rc<SchemeObject> {
+    Rc::new(b.map_or(SchemeObject::Eof, |b| SchemeObject::Integer(i64::from(b))))
+}
+
+fn read_u8(lst: &Lst, env: &PackedEnv) -> Ret {
+    let b = binary_input_port(lst.front(), env)?
+        .read_u8()
+        .map_err(|e| io_error(&e))?;
+
+    Ok(byte_or_eof(b))
+}
+
+fn peek_u8(lst: &Lst, env: &PackedEnv) -> Ret {
+    let b = binary_input_port(lst.front(), env)?
+        .peek_u8()
+        .map_err(|e| io_error(&e))?;
+
+    Ok(byte_or_eof(b))
+}
+
+fn u8_ready(lst: &Lst, env: &PackedEnv) -> Ret {
+    let ready = binary_input_port(lst.front(), env)?
+        .u8_ready()
+        .map_err(|e| io_error(&e))?;
+
+    Ok(scm_bool(ready))
+}
+
+/// (read-bytevector k port)
+fn read_bytevector(lst: &Lst, env: &PackedEnv) -> Ret {
+    let k = index(lst.front().unwrap())?;
+    let bytes = binary_input_port(lst.back(), env)?
+        .read_bytes(k)
+        .map_err(|e| io_error(&e))?;
+
+    Ok(Rc::new(
+        bytes.map_or(SchemeObject::Eof, SchemeObject::Bytevector),
+    ))
+}
+
+fn write_u8(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let b = byte(lst.front().unwrap())?;
+    binary_output_port(lst.back())?
+        .write_bytes(&[b])
+        .map_err(|e| io_error(&e))?;
+
+    Ok(get_none())
+}
+
+fn write_bytevector(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let port = binary_output_port(lst.back())?;
+
+    match **lst.front().unwrap() {
+        SchemeObject::Bytevector(ref b) => port.write_bytes(b).map_err(|e| io_error(&e))?,
+        ref o => {
+            return Err(RuntimeError::from(format!(
+                "Expected a bytevector, got {:?}",
+                o
+            )))
+        }
+    }
+
+    Ok(get_none())
+}
+
+fn bytevector(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let bytes: Result<Vec<u8>, RuntimeError> = lst.iter().map(|o| byte(o)).collect();
+    Ok(Rc::new(SchemeObject::Bytevector(bytes?)))
+}
+
+fn is_bytevector(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match **lst.front().unwrap() {
+        SchemeObject::Bytevector(_) => Ok(scm_bool(true)),
+        _ => Ok(scm_bool(false)),
+    }
+}
+
+/// The bytes of a bytevector argument
+fn bytes_arg(obj: &SchemeObject) -> Result<&Vec<u8>, RuntimeError> {
+    match *obj {
+        SchemeObject::Bytevector(ref b) => Ok(b),
+        ref o => Err(RuntimeError::from(format!(
+            "Expected a bytevector, got {:?}",
+            o
+        ))),
+    }
+}
+
+fn bytevector_length(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let len = bytes_arg(lst.front().unwrap())?.len();
+    Ok(Rc::new(SchemeObject::Integer(len as i64)))
+}
+
+fn bytevector_u8_ref(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let bytes = bytes_arg(lst.front().unwrap())?;
+    let k = index(lst.back().unwrap())?;
+
+    match bytes.get(k) {
+        Some(b) => Ok(Rc::new(SchemeObject::Integer(i64::from(*b)))),
+        None => Err(RuntimeError::from(format!(
+            "bytevector-u8-ref: index {} out of range",
+            k
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ast;
+    use data::port::{self, Port};
+    use data::*;
+    use stdlib::{get_std_env, get_std_env_with, Capability, EnvOptions};
+
+    use std::collections::LinkedList;
+    use std::rc::Rc;
+
+    fn eval(program: &str) -> Rc<SchemeObject> {
+        let env = get_std_env();
+        let mut res = Rc::new(SchemeObject::None);
+        for code in ast::ObjectIterator::from(program.chars()) {
+            res = code.unwrap().exec(&env).unwrap();
+        }
+        res
+    }
+
+    #[test]
+    fn string_ports() {
+        let program = "(define out (open-output-string))
+                       (define in (open-input-string \"ab\ncd\"))
+                       (write-char (read-char in) out)
+                       (write (peek-char in) out)
+                       (write-string (read-line in) out)
+                       (display (list (read-line in) (read-line in)) out)
+                       (newline out)
+                       (get-output-string out)";
+
+        assert_eq!(*eval(program), SchemeObject::from("a#\\bb(cd #<eof>)\n"));
+    }
+
+    #[test]
+    fn display_one_object() {
+        let program = "(define out (open-output-string))
+                       (display \"a\" out)
+                       (write \"c\" out)
+                       (get-output-string out)";
+        assert_eq!(*eval(program), SchemeObject::from("a\"c\""));
+
+        let env = get_std_env();
+        for program in &["(display \"a\" \"b\")", "(write 1 2 (current-output-port))"] {
+            let code = ast::parse_tokens(program.chars()).unwrap();
+            assert!(code[0].exec(&env).is_err(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn write_and_display() {
+        let program = "(define out (open-output-string))
+                       (define data (list \"a\\nb\" #\\c (list \"d\")))
+                       (display data out)
+                       (write data out)
+                       (write-simple data out)
+                       (write-shared data out)
+                       (get-output-string out)";
+
+        assert_eq!(
+            *eval(program),
+            SchemeObject::from(
+                "(a\nb c (d))(\"a\\nb\" #\\c (\"d\"))(\"a\\nb\" #\\c (\"d\"))(\"a\\nb\" #\\c (\"d\"))"
+            )
+        );
+    }
+
+    #[test]
+    fn current_output() {
+        let out = Port::output_string();
+        let old = port::set_current_output(out.clone());
+        eval("(display \"hello\") (newline)");
+        port::set_current_output(old);
+
+        assert_eq!(out.output_string_contents(), Some(String::from("hello\n")));
+    }
+
+    #[test]
+    fn sandbox_ports() {
+        let env = get_std_env_with(&EnvOptions::sandbox(&[Capability::Pure]));
+        let out = Port::output_string();
+        let old = port::set_current_output(out.clone());
+        let program = "(write-string \"hello\")
+                       (define s (open-output-string))
+                       (write-string \"there\" s)
+                       (list (read-char) (get-output-string s))";
+        let mut res = None;
+        for code in ast::ObjectIterator::from(program.chars()) {
+            res = Some(code.unwrap().exec(&env).unwrap());
+        }
+        port::set_current_output(old);
+
+        // the thread's current ports can't be used, but the code's own ports can
+        assert_eq!(out.output_string_contents(), Some(String::new()));
+        assert_eq!(format!("{:?}", res.unwrap()), "(#<eof> \"there\")");
+    }
+
+    #[test]
+    fn embedder_writer() {
+        use std::cell::RefCell;
+        use std::io::{self, Write};
+
+        /// a writer which shares its buffer with the test
+        struct Shared(Rc<RefCell<Vec<u8>>>);
+
+        impl Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.borrow_mut().extend_from_slice(buf);
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buf = Rc::new(RefCell::new(Vec::new()));
+        let old = port::set_current_error(Port::output(Shared(buf.clone())));
+        eval("(write-string \"oops\" (current-error-port))");
+        port::set_current_error(old);
+
+        assert_eq!(*buf.borrow(), b"oops".to_vec());
+    }
+
+    #[test]
+    fn predicates() {
+        let program = "(list (char-ready? (open-input-string \"\"))
+                             (input-port? (current-input-port))
+                             (output-port? (current-input-port))
+                             (eof-object? (read-char (open-input-string \"\")))
+                             (eof-object? (eof-object)))";
+
+        assert_eq!(eval(program).to_string(), "(#t #t #f #t #t)");
+    }
+
+    #[test]
+    fn read() {
+        let program = "(define in (open-input-string \"(a \\\"b\\\" #(#t)) c(d) ; e\"))
+                       (list (read in) (read in) (read-char in) (read in) (read-char in) (read in))";
+
+        assert_eq!(
+            format!("{:?}", eval(program)),
+            "((a \"b\" #(#t)) c #\\( d #\\) #<eof>)"
+        );
+    }
+
+    #[test]
+    fn read_matches_parser() {
+        let source = "(define (f x) (list x '(1 2) \"s\")) #u8(1 2) sym";
+        let parsed = ast::parse_tokens(source.chars()).unwrap();
+
+        let port = Port::input_string(source);
+        let old = port::set_current_input(port);
+        let read = eval("(list (read) (read) (read) (read))");
+        port::set_current_input(old);
+
+        let mut expected: Vec<SchemeObject> = parsed;
+        expected.push(SchemeObject::Eof);
+        assert_eq!(
+            *read,
+            SchemeObject::from(expected.into_iter().collect::<LinkedList<_>>())
+        );
+    }
+
+    #[test]
+    fn read_from_string() {
+        let program = "(read-from-string \"(config (debug #t))\")";
+        assert_eq!(eval(program).to_string(), "(config (debug #t))");
+        assert_eq!(*eval("(read-from-string \"  \")"), SchemeObject::Eof);
+    }
+
+    #[test]
+    fn bytevector_ports() {
+        let program = "(define out (open-output-bytevector))
+                       (write-u8 1 out)
+                       (write-bytevector (bytevector 2 3) out)
+                       (define in (open-input-bytevector (get-output-bytevector out)))
+                       (list (peek-u8 in) (read-u8 in) (read-bytevector 5 in) (read-u8 in)
+                             (binary-port? in) (textual-port? in))";
+
+        assert_eq!(eval(program).to_string(), "(1 1 #u8(2 3) #<eof> #t #f)");
+    }
+
+    #[test]
+    fn current_binary_input() {
+        let env = get_std_env();
+        let old = super::set_current_input(&env, Port::input_bytevector(vec![1, 2]));
+        let program = "(list (u8-ready?) (peek-u8) (read-u8) (read-u8) (read-u8))";
+        let code = ast::parse_tokens(program.chars()).unwrap();
+        assert_eq!(code[0].exec(&env).unwrap().to_string(), "(#t 1 1 2 #<eof>)");
+
+        super::set_current_input(&env, Port::input_string("abc"));
+        let code = ast::parse_tokens("(read-u8)".chars()).unwrap();
+        assert!(code[0].exec(&env).is_err());
+        super::set_current_input(&env, old);
+    }
+
+    #[test]
+    fn write_shared_limits() {
+        let program = "(define out (open-output-string))
+                       (let ((x (list 1 2))) (write-shared (list x x) out))
+                       (write-shared (read-from-string \"(a . b)\") out)
+                       (get-output-string out)";
+
+        assert_eq!(*eval(program), SchemeObject::from("((1 2) (1 2))(a |.| b)"));
+    }
+
+    #[test]
+    fn closed_port() {
+        let env = get_std_env();
+        let program = "(define in (open-input-string \"abc\"))
+                       (close-port in)
+                       (read-char in)";
+
+        let mut res = Ok(Rc::new(SchemeObject::None));
+        for code in ast::ObjectIterator::from(program.chars()) {
+            res = code.unwrap().exec(&env);
+        }
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,407 @@
+//! Promises (delay and force) and streams (SRFI 41)
+//!
+//! delay, delay-force and stream-cons are special forms (see compile.rs). A stream is a promise of
+//! either the empty list or a stream pair: a list of a promise of the first item and the stream of
+//! the rest.
+
+use super::{get_false, get_true, scm_bool, Lst, Ret};
+use data::env::*;
+use data::{Arity, BuiltinFn, Promise, RuntimeError, SchemeObject};
+
+use std::collections::LinkedList;
+use std::rc::Rc;
+
+/// Fills (scheme lazy)
+pub fn add_lazy_funcs(env: &PackedEnv) {
+    lib_funcs!(env,
+        /// The value of a promise, working it out the first time. Other objects are returned as
+        /// they are.
+        "force" => force(1),
+        /// A promise which has already been forced to obj (or obj if it is a promise)
+        "make-promise" => make_promise(1),
+        /// Is obj a promise?
+        "promise?" => is_promise(1)
+    );
+}
+
+/// Fills (srfi 41)
+pub fn add_stream_funcs(env: &PackedEnv) {
+    env.borrow_mut().set("stream-null", stream_null());
+    lib_funcs!(env,
+        /// Is obj a stream?
+        "stream?" => is_stream(1),
+        /// Is obj the empty stream?
+        "stream-null?" => is_stream_null(1),
+        /// Is obj a stream with at least one item?
+        "stream-pair?" => is_stream_pair(1),
+        /// The first item of a stream
+        "stream-car" => stream_car(1),
+        /// The stream of the items after the first
+        "stream-cdr" => stream_cdr(1),
+        /// (stream-map f stream) is the stream of f called on each item of stream
+        "stream-map" => stream_map(2),
+        /// (stream-filter pred stream) is the stream of the items for which pred isn't #f
+        "stream-filter" => stream_filter(2),
+        /// (stream-take n stream) is the stream of the first n items of stream
+        "stream-take" => stream_take(2),
+        /// (stream->list [n] stream) is a list of the items of stream (the first n of them if n is
+        /// given)
+        "stream->list" => stream_to_list(1..=2),
+        /// A stream of the items of a list
+        "list->stream" => list_to_stream(1)
+    );
+}
+
+/// (force obj)
+fn force(lst: &Lst, env: &PackedEnv) -> Ret {
+    match **lst.front().unwrap() {
+        SchemeObject::Promise(ref p) => p.force(env),
+        _ => Ok(lst.front().unwrap().clone()),
+    }
+}
+
+/// (make-promise obj)
+fn make_promise(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let obj = lst.front().unwrap();
+    match **obj {
+        SchemeObject::Promise(_) => Ok(obj.clone()),
+        _ => Ok(promise(Promise::done(obj.clone()))),
+    }
+}
+
+/// (promise? obj)
+fn is_promise(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match **lst.front().unwrap() {
+        SchemeObject::Promise(_) => Ok(get_true()),
+        _ => Ok(get_false()),
+    }
+}
+
+fn promise(p: Promise) -> Rc<SchemeObject> {
+    Rc::new(SchemeObject::Promise(Rc::new(p)))
+}
+
+/// A built-in function for a promise to call
+fn builtin(name: &str, f: BuiltinFn, arity: usize) -> Rc<SchemeObject> {
+    Rc::new(SchemeObject::RFunc(
+        String::from(name),
+        f,
+        Arity::from(arity),
+        None,
+    ))
+}
+
+/// A stream which is worked out by calling `step` with `args` when it is needed. `step` gives
+/// another stream.
+fn lazy_stream(name: &str, step: BuiltinFn, args: Vec<Rc<SchemeObject>>) -> Rc<SchemeObject> {
+    let step = builtin(name, step, args.len());
+    promise(Promise::delayed(step, args, true))
+}
+
+fn stream_null() -> Rc<SchemeObject> {
+    promise(Promise::done(Rc::new(
+        SchemeObject::from(LinkedList::new()),
+    )))
+}
+
+/// A stream whose first item is the promise `first`, followed by the stream `rest`
+fn make_stream_pair(first: Rc<SchemeObject>, rest: Rc<SchemeObject>) -> Rc<SchemeObject> {
+    let mut pair = LinkedList::new();
+    pair.push_back((*first).clone());
+    pair.push_back((*rest).clone());
+    promise(Promise::done(Rc::new(SchemeObject::from(pair))))
+}
+
+/// The promise of the first item of a stream and the stream of the rest
+type StreamPair = (Rc<SchemeObject>, Rc<SchemeObject>);
+
+/// The promise of the first item and the stream of the rest of `stream`, or None if it is empty
+/// `name` is the function which needs them, for errors.
+fn stream_pair(
+    stream: &SchemeObject,
+    name: &str,
+    env: &PackedEnv,
+) -> Result<Option<StreamPair>, RuntimeError> {
+    let not_stream = || RuntimeError::from(format!("{} expects a stream, got {:?}", name, stream));
+
+    let value = match stream {
+        SchemeObject::Promise(p) => p.force(env)?,
+        _ => return Err(not_stream()),
+    };
+
+    match *value {
+        SchemeObject::List(ref lst, _) if lst.is_empty() => Ok(None),
+        SchemeObject::List(ref lst, _) if lst.len() == 2 => match (lst.front(), lst.back()) {
+            (Some(first @ SchemeObject::Promise(_)), Some(rest @ SchemeObject::Promise(_))) => {
+                Ok(Some((Rc::new(first.clone()), Rc::new(rest.clone()))))
+            }
+            _ => Err(not_stream()),
+        },
+        _ => Err(not_stream()),
+    }
+}
+
+/// (stream? obj)
+fn is_stream(lst: &Lst, env: &PackedEnv) -> Ret {
+    is_promise(lst, env)
+}
+
+/// (stream-null? obj)
+fn is_stream_null(lst: &Lst, env: &PackedEnv) -> Ret {
+    match **lst.front().unwrap() {
+        SchemeObject::Promise(_) => Ok(scm_bool(
+            stream_pair(lst.front().unwrap(), "stream-null?", env)?.is_none(),
+        )),
+        _ => Ok(get_false()),
+    }
+}
+
+/// (stream-pair? obj)
+fn is_stream_pair(lst: &Lst, env: &PackedEnv) -> Ret {
+    match **lst.front().unwrap() {
+        SchemeObject::Promise(_) => Ok(scm_bool(
+            stream_pair(lst.front().unwrap(), "stream-pair?", env)?.is_some(),
+        )),
+        _ => Ok(get_false()),
+    }
+}
+
+/// The first item and the rest of a stream which mustn't be empty
+fn non_empty(
+    stream: &SchemeObject,
+    name: &str,
+    env: &PackedEnv,
+) -> Result<StreamPair, RuntimeError> {
+    stream_pair(stream, name, env)?
+        .ok_or_else(|| RuntimeError::from(format!("{} expects a non-empty stream", name)))
+}
+
+/// (stream-car stream)
+fn stream_car(lst: &Lst, env: &PackedEnv) -> Ret {
+    let (first, _) = non_empty(lst.front().unwrap(), "stream-car", env)?;
+    force(&vec![first].into_iter().collect(), env)
+}
+
+/// (stream-cdr stream)
+fn stream_cdr(lst: &Lst, env: &PackedEnv) -> Ret {
+    let (_, rest) = non_empty(lst.front().unwrap(), "stream-cdr", env)?;
+    Ok(rest)
+}
+
+/// (stream-map f stream)
+fn stream_map(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let args = lst.iter().cloned().collect();
+    Ok(lazy_stream("stream-map", stream_map_step, args))
+}
+
+/// Works out the first pair of (stream-map f stream)
+fn stream_map_step(lst: &Lst, env: &PackedEnv) -> Ret {
+    let func = lst.front().unwrap();
+    let (first, rest) = match stream_pair(lst.back().unwrap(), "stream-map", env)? {
+        Some(pair) => pair,
+        None => return Ok(stream_null()),
+    };
+
+    let call = builtin("stream-map", call_on_forced, 2);
+    let first = promise(Promise::delayed(call, vec![func.clone(), first], false));
+    let rest = lazy_stream("stream-map", stream_map_step, vec![func.clone(), rest]);
+    Ok(make_stream_pair(first, rest))
+}
+
+/// (f (force promise))
+fn call_on_forced(lst: &Lst, env: &PackedEnv) -> Ret {
+    let arg = force(&lst.iter().skip(1).cloned().collect(), env)?;
+    lst.front().unwrap().apply(&[arg], env)
+}
+
+/// (stream-filter pred stream)
+fn stream_filter(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let args = lst.iter().cloned().collect();
+    Ok(lazy_stream("stream-filter", stream_filter_step, args))
+}
+
+/// Works out the first pair of (stream-filter pred stream), skipping items until one matches
+fn stream_filter_step(lst: &Lst, env: &PackedEnv) -> Ret {
+    let pred = lst.front().unwrap();
+    let mut stream = lst.back().unwrap().clone();
+
+    while let Some((first, rest)) = stream_pair(&stream, "stream-filter", env)? {
+        let item = force(&vec![first.clone()].into_iter().collect(), env)?;
+        if *pred.apply(&[item], env)? != SchemeObject::from(false) {
+            let rest = lazy_stream(
+                "stream-filter",
+                stream_filter_step,
+                vec![pred.clone(), rest],
+            );
+            return Ok(make_stream_pair(first, rest));
+        }
+        stream = rest;
+    }
+
+    Ok(stream_null())
+}
+
+/// A count given to `name`
+fn count(obj: &SchemeObject, name: &str) -> Result<i64, RuntimeError> {
+    match *obj {
+        SchemeObject::Integer(n) if n >= 0 => Ok(n),
+        ref o => Err(RuntimeError::from(format!(
+            "{} expects a count of items, got {:?}",
+            name, o
+        ))),
+    }
+}
+
+/// (stream-take n stream)
+fn stream_take(lst: &Lst, _env: &PackedEnv) -> Ret {
+    count(lst.front().unwrap(), "stream-take")?;
+    let args = lst.iter().cloned().collect();
+    Ok(lazy_stream("stream-take", stream_take_step, args))
+}
+
+/// Works out the first pair of (stream-take n stream)
+fn stream_take_step(lst: &Lst, env: &PackedEnv) -> Ret {
+    let n = count(lst.front().unwrap(), "stream-take")?;
+    if n == 0 {
+        return Ok(stream_null());
+    }
+
+    match stream_pair(lst.back().unwrap(), "stream-take", env)? {
+        Some((first, rest)) => {
+            let n = Rc::new(SchemeObject::Integer(n - 1));
+            let rest = lazy_stream("stream-take", stream_take_step, vec![n, rest]);
+            Ok(make_stream_pair(first, rest))
+        }
+        None => Ok(stream_null()),
+    }
+}
+
+/// (stream->list [n] stream)
+fn stream_to_list(lst: &Lst, env: &PackedEnv) -> Ret {
+    let mut n = match lst.len() {
+        2 => Some(count(lst.front().unwrap(), "stream->list")?),
+        _ => None,
+    };
+    let mut stream = lst.back().unwrap().clone();
+
+    let mut out = LinkedList::new();
+    while n != Some(0) {
+        match stream_pair(&stream, "stream->list", env)? {
+            Some((first, rest)) => {
+                let item = force(&vec![first].into_iter().collect(), env)?;
+                out.push_back((*item).clone());
+                stream = rest;
+                n = n.map(|n| n - 1);
+            }
+            None => break,
+        }
+    }
+
+    Ok(Rc::new(SchemeObject::from(out)))
+}
+
+/// (list->stream lst)
+fn list_to_stream(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let items = match **lst.front().unwrap() {
+        SchemeObject::List(ref items, _) => items,
+        ref o => {
+            return Err(RuntimeError::from(format!(
+                "list->stream expects a list, got {:?}",
+                o
+            )))
+        }
+    };
+
+    let mut stream = stream_null();
+    for item in items.iter().rev() {
+        let first = promise(Promise::done(Rc::new(item.clone())));
+        stream = make_stream_pair(first, stream);
+    }
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast;
+    use stdlib::get_std_env;
+
+    fn eval(env: &PackedEnv, program: &str) -> Rc<SchemeObject> {
+        let mut res = Rc::new(SchemeObject::None);
+        for code in ast::ObjectIterator::from(program.chars()) {
+            res = code.unwrap().exec(env).unwrap();
+        }
+        res
+    }
+
+    #[test]
+    fn memoized() {
+        let env = get_std_env();
+        let program = "(define out (open-output-string))
+                       (define p (delay (let ((_ (write-string \"forced \" out))) 1)))
+                       (list (promise? p) (force p) (force p) (get-output-string out))";
+
+        let res = eval(&env, program);
+        assert_eq!(format!("{:?}", res), "(#t 1 1 \"forced \")");
+        assert_eq!(format!("{:?}", eval(&env, "(force 3)")), "3");
+        assert_eq!(format!("{:?}", eval(&env, "(force (make-promise 3))")), "3");
+    }
+
+    #[test]
+    fn iterative_forcing() {
+        let text = "a".repeat(100_000);
+        let program = format!(
+            "(define (drain port)
+               (delay-force
+                 (if (eof-object? (read-char port)) (delay \"done\") (drain port))))
+             (force (drain (open-input-string \"{}\")))",
+            text
+        );
+
+        assert_eq!(*eval(&get_std_env(), &program), SchemeObject::from("done"));
+    }
+
+    #[test]
+    fn dropping_long_streams() {
+        let env = get_std_env();
+        eval(&env, "(define (from n) (stream-cons n (from (+ n 1))))");
+
+        // every promise of the stream has been forced and is kept by s until it is redefined
+        let program = "(define s (from 0))
+                       (stream->list 20000 s)
+                       (define s 0)
+                       \"dropped\"";
+        assert_eq!(*eval(&env, program), SchemeObject::from("dropped"));
+
+        let program = "(define s (stream-take 20000 (from 0)))
+                       (stream-null? (stream-filter (lambda (x) #f) s))
+                       (define s 0)
+                       \"dropped\"";
+        assert_eq!(*eval(&env, program), SchemeObject::from("dropped"));
+    }
+
+    #[test]
+    fn streams() {
+        let env = get_std_env();
+        eval(&env, "(define (repeat x) (stream-cons x (repeat x)))");
+
+        let res = eval(
+            &env,
+            "(stream->list (stream-take 3 (stream-map (lambda (x) (list x x)) (repeat 1))))",
+        );
+        assert_eq!(format!("{:?}", res), "((1 1) (1 1) (1 1))");
+
+        let res = eval(
+            &env,
+            "(stream->list (stream-filter eof-object? (list->stream (list 1 (eof-object) 2))))",
+        );
+        assert_eq!(format!("{:?}", res), "(#<eof>)");
+
+        let res = eval(
+            &env,
+            "(list (stream-car (repeat 2)) (stream-null? stream-null) (stream-pair? (repeat 2))
+                   (stream->list 2 (stream-cdr (repeat 3))))",
+        );
+        assert_eq!(format!("{:?}", res), "(2 #t #t (3 3))");
+    }
+}
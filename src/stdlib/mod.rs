@@ -0,0 +1,391 @@
+//! Scheme standard library
+
+use data::env::*;
+use data::library::{self, Libraries, LibraryBuilder};
+use data::limits::Limits;
+use data::port::CurrentPorts;
+use data::{RuntimeError, SchemeObject};
+use std::cell::RefCell;
+use std::collections::{HashSet, LinkedList};
+use std::rc::Rc;
+
+/// short-hand for adding functions to an environment
+/// env is the name of the `PackedEnv ` to add to
+/// name is the name of the function in the environment
+/// Use `"scheme-name" => rust_name` for names which aren't valid rust identifiers
+/// Functions can be documented with a doc comment followed by `"scheme-name" => rust_name(arity)`
+/// where arity is `n`, `min..` or `min..=max`. Calls with the wrong number of arguments are then
+/// rejected before the function is run.
+macro_rules! lib_funcs {
+    ($env:ident, $($(#[doc = $doc:literal])+ $scm_name:literal => $name:ident($arity:expr)),*) => {{
+        $(
+            $env.borrow_mut().set(
+                String::from($scm_name),
+                Rc::new(SchemeObject::RFunc(
+                    String::from($scm_name),
+                    $name,
+                    $crate::data::Arity::from($arity),
+                    Some(concat!($($doc, "\n"),+)),
+                )),
+            );
+        )*
+    }};
+    ($env:ident, $($name:ident),*) => {{
+        lib_funcs!($env, $(stringify!($name) => $name),*)
+    }};
+    ($env:ident, $($scm_name:expr => $name:ident),*) => {{
+        $(
+            $env.borrow_mut().set(
+                String::from($scm_name),
+                Rc::new(SchemeObject::RFunc(
+                    String::from($scm_name),
+                    $name,
+                    $crate::data::Arity::any(),
+                    None,
+                )),
+            );
+        )*
+    }};
+}
+
+// raise, error and error objects
+mod error;
+// file ports and the file system
+mod file;
+// running the garbage collector
+mod gc;
+// ports and input/output
+mod io;
+// promises and streams
+mod lazy;
+// integers and arithmetic
+mod number;
+// finding out about procedures
+mod procedure;
+// command line arguments and exit
+mod process;
+// symbols and strings
+mod symbol;
+
+pub use self::process::set_command_line;
+
+/// What code run in an environment is allowed to do. Each standard library needs one of these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Computing with values: (scheme base), (scheme lazy), (srfi 41) and the (rustyscheme ...)
+    /// libraries. Without `Io` the current ports used by the port procedures in (scheme base)
+    /// are null ports, so only ports the code makes itself (e.g. string ports) do anything.
+    Pure,
+    /// Reading and writing values on ports: (scheme read) and (scheme write), and the thread's
+    /// current ports (see `port::set_current_output`)
+    Io,
+    /// The running program: (scheme process-context) with `exit` and `command-line`
+    Process,
+    /// Opening, creating and deleting files, loading source files and finding libraries in files
+    File,
+}
+
+impl Capability {
+    /// Every capability
+    pub const ALL: [Capability; 4] = [
+        Capability::Pure,
+        Capability::Io,
+        Capability::Process,
+        Capability::File,
+    ];
+}
+
+/// Choices an embedder can make about what goes in the standard library
+#[derive(Debug, Clone, PartialEq)]
+pub struct EnvOptions {
+    /// What code is allowed to do. Libraries needing anything else raise an error when imported.
+    pub capabilities: Vec<Capability>,
+    /// Limits on the code run in the environment (none by default)
+    pub limits: Limits,
+}
+
+impl EnvOptions {
+    /// Options for an environment which can only do what `capabilities` allow
+    pub fn sandbox(capabilities: &[Capability]) -> Self {
+        Self {
+            capabilities: capabilities.to_vec(),
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for EnvOptions {
+    fn default() -> Self {
+        Self {
+            capabilities: Capability::ALL.to_vec(),
+            limits: Limits::default(),
+        }
+    }
+}
+
+/// The standard libraries, the capability they need and the functions which build them
+const STD_LIBRARIES: [(&str, Capability, LibraryBuilder); 11] = [
+    ("(scheme base)", Capability::Pure, add_base_funcs),
+    ("(scheme write)", Capability::Io, io::add_write_funcs),
+    ("(scheme read)", Capability::Io, io::add_read_funcs),
+    (
+        "(scheme process-context)",
+        Capability::Process,
+        process::add_process_funcs,
+    ),
+    ("(scheme lazy)", Capability::Pure, lazy::add_lazy_funcs),
+    ("(scheme file)", Capability::File, file::add_file_funcs),
+    ("(scheme load)", Capability::File, file::add_load_funcs),
+    (
+        "(rustyscheme procedure)",
+        Capability::Pure,
+        procedure::add_procedure_funcs,
+    ),
+    (
+        "(rustyscheme symbol)",
+        Capability::Pure,
+        symbol::add_gensym_funcs,
+    ),
+    ("(rustyscheme gc)", Capability::Pure, gc::add_gc_funcs),
+    ("(srfi 41)", Capability::Pure, lazy::add_stream_funcs),
+];
+
+/// Returns an environment containing the standard library
+pub fn get_std_env() -> PackedEnv {
+    get_std_env_with(&EnvOptions::default())
+}
+
+/// Returns an environment containing the parts of the standard library chosen by `options`
+/// The standard libraries which are allowed are imported already. Importing the others raises a
+/// `RuntimeError::Forbidden`, which can be caught.
+pub fn get_std_env_with(options: &EnvOptions) -> PackedEnv {
+    let env = Environment::new(None);
+
+    let mut libraries = Libraries::new();
+    let mut names = Vec::new();
+    for &(name, capability, build) in STD_LIBRARIES.iter() {
+        if options.capabilities.contains(&capability) {
+            libraries.add_builtin(name, build);
+            names.push(name);
+        } else {
+            libraries.forbid(name);
+        }
+    }
+    if !options.capabilities.contains(&Capability::File) {
+        libraries.set_search_path(Vec::new());
+    }
+    env.borrow_mut()
+        .set_libraries(Rc::new(RefCell::new(libraries)));
+    env.borrow_mut().set_limits(options.limits);
+    if !options.capabilities.contains(&Capability::Io) {
+        env.borrow_mut().set_ports(CurrentPorts::null());
+    }
+
+    for name in names {
+        library::import_library(&env, name).expect("standard libraries can be imported");
+    }
+
+    // we don't expect regular changes to the global environment from now on so shrink it
+    env.borrow_mut().shrink();
+
+    env
+}
+
+/// Fills (scheme base)
+fn add_base_funcs(env: &PackedEnv) {
+    //trace_macros!(true);
+    lib_funcs!(env,
+        /// Make a list of the arguments
+        "list" => list(0..),
+        /// (apply f arg... lst) calls f with the args followed by the items of lst
+        "apply" => apply(1..),
+        /// Call f on each item of lst, returning a list of the results
+        "map" => map(2),
+        /// Call f on each item of lst in order
+        "for-each" => for_each(2),
+        /// Return each of the arguments as a separate value (see call-with-values)
+        "values" => values(0..),
+        /// (call-with-values producer consumer) calls consumer with the values producer returns
+        "call-with-values" => call_with_values(2),
+        /// Are a and b the same, comparing lists, vectors, strings and records by their contents?
+        "equal?" => equal(2)
+    );
+    error::add_error_funcs(env);
+    io::add_port_funcs(env);
+    number::add_number_funcs(env);
+    symbol::add_symbol_funcs(env);
+}
+
+// short-hand
+type Lst = LinkedList<Rc<SchemeObject>>;
+type Ret = Result<Rc<SchemeObject>, RuntimeError>;
+
+/// Macro to share implementation of `get_none`, `get_true` and `get_false`
+/// The idea is to have only one copy of the Null, true and false objects per thread
+/// The generated function returns an Rc to these objects
+/// Unique true, false and None objects can result from direct construction e.g.
+/// `Rc::new(SchemeObject::from(true))`
+macro_rules! const_obj {
+    ($(#[$attr:meta])*, $name:ident, $init: expr) => {
+        $(#[$attr])*
+        /// see definition of `const_obj!`
+        pub fn $name() -> Rc<SchemeObject> {
+            thread_local! {
+                static OBJ: Rc<SchemeObject> = Rc::new($init);
+            }
+
+            OBJ.with(|o| o.clone())
+        }
+    }
+}
+
+const_obj! {
+    /// share references to `SchemeObject::None`
+    , get_none, SchemeObject::None
+}
+
+const_obj! {
+    /// share references to #t
+    , get_true, SchemeObject::from(true)
+}
+
+const_obj! {
+    /// share references to #f
+    , get_false, SchemeObject::from(false)
+}
+
+/// #t or #f
+fn scm_bool(b: bool) -> Rc<SchemeObject> {
+    if b {
+        get_true()
+    } else {
+        get_false()
+    }
+}
+
+// Actually define standard library functions:
+
+// Ideally we would define these functions within lib_func! so that the function doesn't need to be duplicated. Unfortunately you can't just pass a function body into a macro because the argument names won't be defined
+
+fn list(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let lst: LinkedList<SchemeObject> = lst.iter().map(|obj| (**obj).clone()).collect();
+    Ok(Rc::new(SchemeObject::from(lst)))
+}
+
+/// (values obj...)
+fn values(lst: &Lst, _env: &PackedEnv) -> Ret {
+    if lst.len() == 1 {
+        return Ok(lst.front().unwrap().clone());
+    }
+    Ok(Rc::new(SchemeObject::Values(lst.iter().cloned().collect())))
+}
+
+/// (call-with-values producer consumer) when it isn't called by name (see the compiler)
+fn call_with_values(lst: &Lst, env: &PackedEnv) -> Ret {
+    let mut args = lst.iter();
+    let (producer, consumer) = (args.next().unwrap(), args.next().unwrap());
+    let vals = producer.apply(&[], env)?;
+    consumer.apply(&SchemeObject::values(&vals), env)
+}
+
+/// (equal? a b)
+fn equal(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let mut args = lst.iter();
+    let (a, b) = (args.next().unwrap(), args.next().unwrap());
+    Ok(Rc::new(SchemeObject::from(is_equal(a, b))))
+}
+
+/// Compare `a` and `b` by their contents
+/// This doesn't recurse, so deep structures can be compared, and pairs of records which have
+/// already been reached are taken to be equal, so comparing cycles of records finishes.
+fn is_equal(a: &Rc<SchemeObject>, b: &Rc<SchemeObject>) -> bool {
+    use data::SchemeObject::*;
+
+    // pairs still to be compared. Lists and vectors inside them are compared straight away, while
+    // the pair keeps them alive.
+    let mut roots = vec![(a.clone(), b.clone())];
+    let mut seen = HashSet::new();
+
+    while let Some((a, b)) = roots.pop() {
+        let mut pairs: Vec<(&SchemeObject, &SchemeObject)> = vec![(&a, &b)];
+
+        while let Some(pair) = pairs.pop() {
+            match pair {
+                (List(l1, _), List(l2, _)) if l1.len() == l2.len() => {
+                    pairs.extend(l1.iter().zip(l2))
+                }
+                (Vector(v1), Vector(v2)) if v1.len() == v2.len() => pairs.extend(v1.iter().zip(v2)),
+                (Quoted(o1), Quoted(o2)) => pairs.push((o1, o2)),
+                (Values(v1), Values(v2)) if v1.len() == v2.len() => {
+                    roots.extend(v1.iter().cloned().zip(v2.iter().cloned()))
+                }
+                (Record(r1), Record(r2)) if r1.record_type() == r2.record_type() => {
+                    if seen.insert((Rc::as_ptr(r1), Rc::as_ptr(r2))) {
+                        roots.extend(r1.values().into_iter().zip(r2.values()));
+                    }
+                }
+                (List(_, _), _) | (Vector(_), _) | (Values(_), _) | (Record(_), _) => return false,
+                (o1, o2) => {
+                    if o1 != o2 {
+                        return false;
+                    }
+                }
+            }
+        }
+    }
+
+    true
+}
+
+/// Get the items of a list argument
+/// '(...) evaluates to a `SchemeObject::Quoted` so accept that too
+fn list_items(obj: &SchemeObject) -> Result<Vec<Rc<SchemeObject>>, RuntimeError> {
+    match obj {
+        SchemeObject::List(lst, _) => Ok(lst.iter().cloned().map(Rc::new).collect()),
+        SchemeObject::Quoted(inner) => list_items(inner),
+        o => Err(RuntimeError::from(format!("Expected a list, got {:?}", o))),
+    }
+}
+
+/// Split the arguments to a higher order function (f lst) into the function and the list items
+fn func_and_list(lst: &Lst) -> Result<(Rc<SchemeObject>, Vec<Rc<SchemeObject>>), RuntimeError> {
+    let func = lst.front().unwrap().clone();
+    let items = list_items(lst.back().unwrap())?;
+    Ok((func, items))
+}
+
+/// (apply f arg1 ... args)
+fn apply(lst: &Lst, env: &PackedEnv) -> Ret {
+    let mut iter = lst.iter();
+
+    let func = iter.next().unwrap();
+
+    // the last argument is a list of further arguments
+    let mut args: Vec<Rc<SchemeObject>> = iter.cloned().collect();
+    if let Some(last) = args.pop() {
+        args.extend(list_items(&last)?);
+    }
+
+    func.apply(&args, env)
+}
+
+fn map(lst: &Lst, env: &PackedEnv) -> Ret {
+    let (func, items) = func_and_list(lst)?;
+
+    let mut out = LinkedList::new();
+    for item in items {
+        out.push_back((*func.apply(&[item], env)?).clone());
+    }
+
+    Ok(Rc::new(SchemeObject::from(out)))
+}
+
+fn for_each(lst: &Lst, env: &PackedEnv) -> Ret {
+    let (func, items) = func_and_list(lst)?;
+
+    for item in items {
+        func.apply(&[item], env)?;
+    }
+
+    Ok(get_none())
+}
@@ -0,0 +1,142 @@
+//! Integers: predicates, comparison and arithmetic
+//!
+//! The only numbers are exact integers which fit in 64 bits (`SchemeObject::Integer`). Arithmetic
+//! which would overflow raises an error rather than wrapping.
+
+use super::{Lst, Ret};
+use data::env::*;
+use data::{RuntimeError, SchemeObject};
+
+use std::rc::Rc;
+
+/// Adds the number procedures from (scheme base) to `env`
+pub fn add_number_funcs(env: &PackedEnv) {
+    lib_funcs!(env,
+        /// Is obj a number?
+        "number?" => is_integer(1),
+        /// Is obj an integer? (all numbers are)
+        "integer?" => is_integer(1),
+        /// Are the numbers all equal?
+        "=" => num_eq(1..),
+        /// Does each number come before the next?
+        "<" => num_lt(1..),
+        /// Does each number come after the next?
+        ">" => num_gt(1..),
+        /// (+ n...) is the sum of the numbers, or 0 if there are none
+        "+" => add(0..),
+        /// (- n) negates n. (- n m...) subtracts each m from n.
+        "-" => sub(1..),
+        /// (* n...) is the product of the numbers, or 1 if there are none
+        "*" => mul(0..)
+    );
+}
+
+/// The integer in an argument of `func`
+fn integer(func: &str, obj: &SchemeObject) -> Result<i64, RuntimeError> {
+    match obj {
+        SchemeObject::Integer(i) => Ok(*i),
+        o => Err(RuntimeError::from(format!(
+            "{} expects numbers, got {:?}",
+            func, o
+        ))),
+    }
+}
+
+fn integers(func: &str, lst: &Lst) -> Result<Vec<i64>, RuntimeError> {
+    lst.iter().map(|obj| integer(func, obj)).collect()
+}
+
+/// (number? obj) and (integer? obj)
+fn is_integer(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let is = matches!(**lst.front().unwrap(), SchemeObject::Integer(_));
+    Ok(Rc::new(SchemeObject::from(is)))
+}
+
+/// Does `holds` hold for each pair of neighbouring arguments?
+fn compare(func: &str, lst: &Lst, holds: fn(i64, i64) -> bool) -> Ret {
+    let nums = integers(func, lst)?;
+    let res = nums.windows(2).all(|pair| holds(pair[0], pair[1]));
+    Ok(Rc::new(SchemeObject::from(res)))
+}
+
+/// (= n...)
+fn num_eq(lst: &Lst, _env: &PackedEnv) -> Ret {
+    compare("=", lst, |a, b| a == b)
+}
+
+/// (< n...)
+fn num_lt(lst: &Lst, _env: &PackedEnv) -> Ret {
+    compare("<", lst, |a, b| a < b)
+}
+
+/// (> n...)
+fn num_gt(lst: &Lst, _env: &PackedEnv) -> Ret {
+    compare(">", lst, |a, b| a > b)
+}
+
+/// Combine the numbers in `nums` from left to right, starting with `start`
+fn fold(func: &str, start: i64, nums: &[i64], op: fn(i64, i64) -> Option<i64>) -> Ret {
+    let mut acc = start;
+    for &n in nums {
+        acc = op(acc, n).ok_or_else(|| RuntimeError::from(format!("{}: overflow", func)))?;
+    }
+    Ok(Rc::new(SchemeObject::Integer(acc)))
+}
+
+/// (+ n...)
+fn add(lst: &Lst, _env: &PackedEnv) -> Ret {
+    fold("+", 0, &integers("+", lst)?, i64::checked_add)
+}
+
+/// (- n m...)
+fn sub(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let nums = integers("-", lst)?;
+    match nums.split_first() {
+        Some((&n, [])) => fold("-", 0, &[n], i64::checked_sub),
+        Some((&n, rest)) => fold("-", n, rest, i64::checked_sub),
+        None => unreachable!("arity is checked"),
+    }
+}
+
+/// (* n...)
+fn mul(lst: &Lst, _env: &PackedEnv) -> Ret {
+    fold("*", 1, &integers("*", lst)?, i64::checked_mul)
+}
+
+#[cfg(test)]
+mod tests {
+    use ast;
+    use data::{RuntimeError, SchemeObject};
+    use stdlib::get_std_env;
+
+    #[test]
+    fn arithmetic() {
+        let env = get_std_env();
+        let program = "(list (+) (+ 1 2 3) (- 5) (- 10 1 2) (* 2 3 4)
+                             (= 1 1 1) (= 1 2) (< 1 2 3) (< 1 3 2) (> 3 2)
+                             (number? 1) (integer? \"1\"))";
+        let code = ast::parse_tokens(program.chars()).unwrap();
+        let res = code[0].exec(&env).unwrap();
+        assert_eq!(format!("{:?}", res), "(0 6 -5 7 24 #t #f #t #f #t #t #f)");
+    }
+
+    #[test]
+    fn errors() {
+        let env = get_std_env();
+        let code = ast::parse_tokens("(+ 1 \"a\") (* 9223372036854775807 2)".chars()).unwrap();
+
+        let err = code[0].exec(&env).unwrap_err();
+        assert_eq!(
+            err.root(),
+            &RuntimeError::from("+ expects numbers, got \"a\"")
+        );
+        let err = code[1].exec(&env).unwrap_err();
+        assert_eq!(err.root(), &RuntimeError::from("*: overflow"));
+        assert_eq!(
+            *ast::parse_tokens("-3".chars()).unwrap()[0]
+                .exec(&env)
+                .unwrap(),
+            SchemeObject::Integer(-3)
+        );
+    }
+}
@@ -0,0 +1,161 @@
+//! Finding out about procedures: their arity, name, documentation and source
+
+use super::{get_false, Lst, Ret};
+use data::env::*;
+use data::{RuntimeError, SchemeObject, SymFrom};
+
+use std::collections::LinkedList;
+use std::rc::Rc;
+
+/// Fills (rustyscheme procedure)
+pub fn add_procedure_funcs(env: &PackedEnv) {
+    lib_funcs!(env,
+        /// (procedure-arity f) is (min max): the fewest and the most arguments f accepts. max is
+        /// #f when there is no limit.
+        "procedure-arity" => procedure_arity(1),
+        /// The name f was defined with as a symbol, or #f
+        "procedure-name" => procedure_name(1),
+        /// The docstring of f, or #f
+        "procedure-documentation" => procedure_documentation(1),
+        /// The lambda expression which made f, or #f for built-in procedures
+        "procedure-source" => procedure_source(1)
+    );
+}
+
+/// The procedure given to `name`
+fn procedure<'a>(lst: &'a Lst, name: &str) -> Result<&'a SchemeObject, RuntimeError> {
+    let obj = lst.front().unwrap();
+
+    match **obj {
+        SchemeObject::RFunc(_, _, _, _) | SchemeObject::SFunc(_, _, _) => Ok(obj),
+        _ => Err(RuntimeError::from(format!(
+            "{} expects a procedure, got {:?}",
+            name, obj
+        ))),
+    }
+}
+
+/// (procedure-arity f)
+fn procedure_arity(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let arity = procedure(lst, "procedure-arity")?.arity().unwrap();
+
+    let mut out = LinkedList::new();
+    out.push_back(SchemeObject::Integer(arity.min as i64));
+    out.push_back(match arity.max {
+        Some(max) => SchemeObject::Integer(max as i64),
+        None => SchemeObject::from(false),
+    });
+    Ok(Rc::new(SchemeObject::from(out)))
+}
+
+/// (procedure-name f)
+fn procedure_name(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match procedure(lst, "procedure-name")?.procedure_name() {
+        Some(name) => Ok(Rc::new(SchemeObject::sym_from(name))),
+        None => Ok(get_false()),
+    }
+}
+
+/// (procedure-documentation f)
+fn procedure_documentation(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match procedure(lst, "procedure-documentation")?.documentation() {
+        Some(doc) => Ok(Rc::new(SchemeObject::from(doc.as_str()))),
+        None => Ok(get_false()),
+    }
+}
+
+/// (procedure-source f)
+fn procedure_source(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match procedure(lst, "procedure-source")?.source() {
+        Some(source) => Ok(Rc::new(source)),
+        None => Ok(get_false()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast;
+    use stdlib::get_std_env;
+
+    fn eval_in(env: &PackedEnv, program: &str) -> Result<Rc<SchemeObject>, RuntimeError> {
+        let mut res = Ok(Rc::new(SchemeObject::None));
+        for code in ast::ObjectIterator::from(program.chars()) {
+            res = code.unwrap().exec(env);
+        }
+        res
+    }
+
+    #[test]
+    fn scheme_procedures() {
+        let env = get_std_env();
+        eval_in(
+            &env,
+            "(define (swap a b) \"Swap a and b.\" (list b a))
+             (define anon (list (lambda (x) x)))",
+        )
+        .unwrap();
+
+        let res = eval_in(
+            &env,
+            "(list (procedure-arity swap) (procedure-name swap) (procedure-documentation swap))",
+        )
+        .unwrap();
+        assert_eq!(format!("{:?}", res), "((2 2) swap \"Swap a and b.\")");
+
+        let res = eval_in(&env, "(procedure-source swap)").unwrap();
+        assert_eq!(
+            format!("{:?}", res),
+            "(lambda (a b) \"Swap a and b.\" (list b a))"
+        );
+        assert_eq!(
+            format!("{:?}", eval_in(&env, "(swap 1 2)").unwrap()),
+            "(2 1)"
+        );
+
+        let res = eval_in(
+            &env,
+            "(apply (lambda (f) (list (procedure-name f) (procedure-documentation f))) anon)",
+        )
+        .unwrap();
+        assert_eq!(format!("{:?}", res), "(#f #f)");
+    }
+
+    #[test]
+    fn builtin_procedures() {
+        let env = get_std_env();
+
+        let res = eval_in(
+            &env,
+            "(list (procedure-arity exit) (procedure-arity list) (procedure-name for-each)
+                   (procedure-source list))",
+        )
+        .unwrap();
+        assert_eq!(format!("{:?}", res), "((0 1) (0 #f) for-each #f)");
+
+        let res = eval_in(&env, "(procedure-documentation command-line)").unwrap();
+        assert_eq!(
+            *res,
+            SchemeObject::from(
+                "The name of the program followed by its arguments, as a list of strings"
+            )
+        );
+    }
+
+    #[test]
+    fn arity_checked() {
+        let env = get_std_env();
+
+        let res = eval_in(&env, "(map list)").unwrap_err();
+        assert_eq!(
+            *res.root(),
+            RuntimeError::from("map expects 2 arguments, got 1")
+        );
+
+        let res = eval_in(&env, "(procedure-name 1)").unwrap_err();
+        assert_eq!(
+            *res.root(),
+            RuntimeError::from("procedure-name expects a procedure, got 1")
+        );
+    }
+}
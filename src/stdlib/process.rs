@@ -0,0 +1,147 @@
+//! The running program: command line arguments and exiting
+
+use super::{Lst, Ret};
+use data::env::*;
+use data::port;
+use data::{RuntimeError, SchemeObject};
+
+use std::cell::RefCell;
+use std::collections::LinkedList;
+use std::env;
+use std::rc::Rc;
+
+thread_local! {
+    /// Arguments given by `set_command_line`
+    static COMMAND_LINE: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Set what `(command-line)` returns e.g. the script name followed by its arguments
+/// By default it returns the arguments of this process.
+pub fn set_command_line(args: Vec<String>) {
+    COMMAND_LINE.with(|c| *c.borrow_mut() = Some(args));
+}
+
+/// Fills (scheme process-context)
+pub fn add_process_funcs(env: &PackedEnv) {
+    lib_funcs!(env,
+        /// Leave the program. #t (the default) means success, #f failure and integers from 0 to
+        /// 255 are used as the exit status. Programs embedding the interpreter see a
+        /// `RuntimeError::Exit`.
+        "exit" => exit(0..=1),
+        /// The name of the program followed by its arguments, as a list of strings
+        "command-line" => command_line(0)
+    );
+}
+
+/// (command-line)
+fn command_line(_lst: &Lst, _env: &PackedEnv) -> Ret {
+    let args = COMMAND_LINE
+        .with(|c| c.borrow().clone())
+        .unwrap_or_else(|| env::args().collect());
+
+    let args: LinkedList<SchemeObject> = args.into_iter().map(SchemeObject::from).collect();
+    Ok(Rc::new(SchemeObject::from(args)))
+}
+
+/// The exit status meant by the argument to exit
+/// #t (or no argument) is success, #f is failure and integers from 0 to 255 are used as they are
+/// (the operating system only keeps the low byte of anything bigger)
+pub fn exit_status(obj: Option<&SchemeObject>) -> Result<i32, RuntimeError> {
+    match obj {
+        None | Some(SchemeObject::Bool(true)) => Ok(0),
+        Some(SchemeObject::Bool(false)) => Ok(1),
+        Some(SchemeObject::Integer(i)) if (0..=255).contains(i) => Ok(*i as i32),
+        Some(SchemeObject::Integer(i)) => Err(RuntimeError::from(format!(
+            "exit status should be from 0 to 255, got {}",
+            i
+        ))),
+        Some(o) => Err(RuntimeError::from(format!(
+            "exit expects a boolean or an integer, got {:?}",
+            o
+        ))),
+    }
+}
+
+/// (exit [status])
+/// Evaluation stops with `RuntimeError::Exit` and whatever is running the code decides what to do
+fn exit(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let status = exit_status(lst.front().map(|s| &**s))?;
+
+    // output which hasn't been flushed yet would be lost if the program exits
+    let _ = port::current_output().flush();
+    let _ = port::current_error().flush();
+
+    Err(RuntimeError::Exit(status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast;
+    use stdlib::{get_std_env, get_std_env_with, Capability, EnvOptions};
+
+    #[test]
+    fn status() {
+        assert_eq!(exit_status(None), Ok(0));
+        assert_eq!(exit_status(Some(&SchemeObject::from(false))), Ok(1));
+        assert_eq!(exit_status(Some(&SchemeObject::Integer(3))), Ok(3));
+        assert!(exit_status(Some(&SchemeObject::from("3"))).is_err());
+    }
+
+    #[test]
+    fn status_out_of_range() {
+        let env = get_std_env();
+        let code = ast::parse_tokens(
+            "(exit 256) (exit -1) (exit 99999999999) (guard (e (#t \"caught\")) (exit 256))"
+                .chars(),
+        )
+        .unwrap();
+
+        for (code, status) in code.iter().zip(&["256", "-1", "99999999999"]) {
+            let err = code.exec(&env).unwrap_err();
+            let msg = format!("exit status should be from 0 to 255, got {}", status);
+            assert_eq!(err.root(), &RuntimeError::from(msg));
+        }
+        // a scheme error rather than an exit
+        assert_eq!(*code[3].exec(&env).unwrap(), SchemeObject::from("caught"));
+        assert_eq!(exit_status(Some(&SchemeObject::Integer(255))), Ok(255));
+    }
+
+    #[test]
+    fn exit_escapes() {
+        let env = get_std_env();
+        let code = ast::parse_tokens("(guard (e (#t \"caught\")) (exit 3))".chars()).unwrap();
+        let err = code[0].exec(&env).unwrap_err();
+        assert_eq!(err.root(), &RuntimeError::Exit(3));
+    }
+
+    #[test]
+    fn sandboxed() {
+        let env = get_std_env_with(&EnvOptions::sandbox(&[Capability::Pure]));
+        let code = ast::parse_tokens(
+            "(exit)
+             (import (scheme process-context))
+             (guard (e (#t \"caught\")) (import (scheme process-context)))"
+                .chars(),
+        )
+        .unwrap();
+
+        let err = code[0].exec(&env).unwrap_err();
+        assert_eq!(err.root(), &RuntimeError::NameLookup(String::from("exit")));
+        let err = code[1].exec(&env).unwrap_err();
+        assert_eq!(
+            err.root(),
+            &RuntimeError::Forbidden(String::from("(scheme process-context)"))
+        );
+        assert_eq!(*code[2].exec(&env).unwrap(), SchemeObject::from("caught"));
+    }
+
+    #[test]
+    fn command_line() {
+        set_command_line(vec![String::from("script.scm"), String::from("arg")]);
+
+        let code = ast::parse_tokens("(command-line)".chars()).unwrap();
+        let res = code[0].exec(&get_std_env()).unwrap();
+        assert_eq!(format!("{:?}", res), "(\"script.scm\" \"arg\")");
+    }
+}
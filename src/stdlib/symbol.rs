@@ -0,0 +1,127 @@
+//! Converting between symbols and strings, and generating symbols
+
+use super::{Lst, Ret};
+use data::env::*;
+use data::{RuntimeError, SchemeObject, Sym};
+
+use std::rc::Rc;
+
+/// Adds the symbol procedures from (scheme base) to `env`
+pub fn add_symbol_funcs(env: &PackedEnv) {
+    lib_funcs!(env,
+        /// The symbol named by a string
+        "string->symbol" => string_to_symbol(1),
+        /// The name of a symbol as a string
+        "symbol->string" => symbol_to_string(1)
+    );
+}
+
+/// Fills (rustyscheme symbol)
+pub fn add_gensym_funcs(env: &PackedEnv) {
+    lib_funcs!(env,
+        /// (gensym [prefix]) makes a new symbol which isn't equal to any other symbol, even one
+        /// with the same name. The name is prefix (default "g") followed by a number.
+        "gensym" => gensym(0..=1),
+        /// Another name for gensym
+        "generate-uninterned-symbol" => gensym(0..=1)
+    );
+}
+
+/// (string->symbol "name")
+fn string_to_symbol(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match **lst.front().unwrap() {
+        SchemeObject::String(ref s) => Ok(Rc::new(SchemeObject::Symbol(Sym::intern(s)))),
+        ref o => Err(RuntimeError::from(format!(
+            "string->symbol expects a string, got {:?}",
+            o
+        ))),
+    }
+}
+
+/// The symbol in an argument
+/// 'name evaluates to a `SchemeObject::Quoted` so accept that too
+fn symbol(obj: &SchemeObject) -> Option<Sym> {
+    match obj {
+        SchemeObject::Symbol(s) => Some(*s),
+        SchemeObject::Quoted(inner) => symbol(inner),
+        _ => None,
+    }
+}
+
+/// (symbol->string sym)
+fn symbol_to_string(lst: &Lst, _env: &PackedEnv) -> Ret {
+    match symbol(lst.front().unwrap()) {
+        Some(s) => Ok(Rc::new(SchemeObject::from(&*s.name()))),
+        None => Err(RuntimeError::from(format!(
+            "symbol->string expects a symbol, got {:?}",
+            lst.front().unwrap()
+        ))),
+    }
+}
+
+/// (gensym [prefix])
+fn gensym(lst: &Lst, _env: &PackedEnv) -> Ret {
+    let prefix = match lst.front().map(|p| (&**p, symbol(p))) {
+        None => String::from("g"),
+        Some((SchemeObject::String(s), _)) => s.clone(),
+        Some((_, Some(s))) => s.to_string(),
+        Some((o, None)) => {
+            return Err(RuntimeError::from(format!(
+                "gensym expects a string or symbol prefix, got {:?}",
+                o
+            )))
+        }
+    };
+
+    Ok(Rc::new(SchemeObject::Symbol(Sym::uninterned(&prefix))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ast;
+    use stdlib::get_std_env;
+
+    fn eval(env: &PackedEnv, program: &str) -> Rc<SchemeObject> {
+        let mut res = Rc::new(SchemeObject::None);
+        for code in ast::ObjectIterator::from(program.chars()) {
+            res = code.unwrap().exec(env).unwrap();
+        }
+        res
+    }
+
+    #[test]
+    fn conversions() {
+        let env = get_std_env();
+
+        let res = eval(
+            &env,
+            "(list (symbol->string 'abc) (string->symbol \"a b\"))",
+        );
+        assert_eq!(format!("{:?}", res), "(\"abc\" |a b|)");
+
+        // and read back as the same symbol
+        let res = eval(
+            &env,
+            "(equal? (read (open-input-string \"|a b|\")) (string->symbol \"a b\"))",
+        );
+        assert_eq!(*res, SchemeObject::from(true));
+
+        let sym = eval(&env, "(string->symbol \"abc\")");
+        assert_eq!(*sym, SchemeObject::Symbol(Sym::from("abc")));
+    }
+
+    #[test]
+    fn gensyms() {
+        let env = get_std_env();
+
+        let g1 = eval(&env, "(gensym)");
+        let g2 = eval(&env, "(generate-uninterned-symbol \"tmp\")");
+        assert_ne!(g1, g2);
+        assert!(format!("{:?}", g2).starts_with("tmp"));
+
+        // not even equal to the symbol with the same name
+        let same_name = SchemeObject::Symbol(Sym::intern(&g2.to_string()));
+        assert_ne!(*g2, same_name);
+    }
+}
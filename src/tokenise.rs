@@ -1,16 +1,51 @@
 //! Module for turning a character stream input of scheme source code into an iterator of (string) tokens
 
-use std::collections::VecDeque;
+use span::{Position, Span};
 use std::iter::IntoIterator;
 use std::iter::Iterator;
 
+/// A token and where it came from in the source
+#[derive(Debug, PartialEq, Clone)]
+pub struct Token {
+    /// The text of the token (with escape characters removed)
+    pub text: String,
+    /// Location of the token in the source
+    pub span: Span,
+    /// Was any of the token between bars? If so it is always a symbol e.g. |12| or ||
+    pub quoted: bool,
+}
+
+impl Token {
+    /// Create a new `Token`
+    pub fn new(text: String, span: Span) -> Self {
+        Self {
+            text,
+            span,
+            quoted: false,
+        }
+    }
+
+    /// Mark the token as (partly) written between bars
+    fn quoted(mut self, quoted: bool) -> Self {
+        self.quoted = quoted;
+        self
+    }
+}
+
 /// Iterator for tokens
+/// Characters are pulled from the source one at a time and never more than one character past the
+/// end of the current token (see `take_lookahead`)
 pub struct TokenIterator<T>
 where
     T: Iterator<Item = char>,
 {
-    pending: VecDeque<String>,
     source: T,
+    /// a character which ended the last token but is part of the next one
+    lookahead: Option<char>,
+    /// was the last token '#'? (so a backslash begins a character e.g. #\a)
+    after_hash: bool,
+    /// position of the next character to be tokenised
+    pos: Position,
 }
 
 impl<T> TokenIterator<T>
@@ -23,8 +58,38 @@ where
         I: IntoIterator<Item = T::Item, IntoIter = T>,
     {
         Self {
-            pending: VecDeque::with_capacity(2),
             source: source.into_iter(),
+            lookahead: None,
+            after_hash: false,
+            pos: Position::start(),
+        }
+    }
+
+    /// Count positions from `pos` rather than from the start of the source
+    /// Used when the source continues some earlier input e.g. in the REPL
+    pub fn starting_at(mut self, pos: Position) -> Self {
+        self.pos = pos;
+        self
+    }
+
+    /// Position of the next character to be tokenised
+    pub fn position(&self) -> Position {
+        self.pos
+    }
+
+    /// Take the character which was read from the source to find the end of the last token
+    /// (e.g. the '(' in "abc(") but hasn't been tokenised yet.
+    /// Used to hand the character back when tokenising stops part way through a source which
+    /// will be read from again e.g. a port
+    pub fn take_lookahead(&mut self) -> Option<char> {
+        self.lookahead.take()
+    }
+
+    /// The next character to tokenise
+    fn next_char(&mut self) -> Option<char> {
+        match self.lookahead.take() {
+            Some(c) => Some(c),
+            None => self.source.next(),
         }
     }
 }
@@ -44,31 +109,71 @@ fn is_special(c: char) -> bool {
     c == '(' || c == ')' || c == '\'' || c == '#'
 }
 
+/// The character meant by `c` after a backslash in a string
+fn unescape(c: char) -> char {
+    match c {
+        'n' => '\n',
+        't' => '\t',
+        'r' => '\r',
+        c => c,
+    }
+}
+
+/// Characters which end the token before them and then begin a token (or comment) of their own
+/// '#' only begins a token: inside one it is an ordinary character e.g. a#
+fn is_delimiter(c: char) -> bool {
+    (is_special(c) && c != '#') || c == '"' || c == ';'
+}
+
 impl<T> Iterator for TokenIterator<T>
 where
     T: Iterator<Item = char>,
 {
-    type Item = String;
+    type Item = Token;
 
     fn next(&mut self) -> Option<Self::Item> {
-        // clear any pending items
-        let next = self.pending.pop_front();
-        if next.is_some() {
-            return next;
-        }
-
-        // else we have to do some work:
+        let token = self.next_token();
+        self.after_hash = token.as_ref().is_some_and(|t| t.text == "#");
+        token
+    }
+}
 
+impl<T> TokenIterator<T>
+where
+    T: Iterator<Item = char>,
+{
+    /// Does the work for `next`
+    fn next_token(&mut self) -> Option<Token> {
         // this will be the item returned
         let mut current = String::new();
+        // location of the first and last characters of current
+        let mut start = self.pos;
+        let mut end = self.pos;
 
         // state of the tokeniser
         let mut escaped = false; // '\\'
         let mut in_comment = false; // ';'
         let mut in_string = false; // '"'
+        let mut in_bars = false; // '|' e.g. |a symbol|
+        let mut quoted = false; // have there been any bars in this token?
 
         // iterate through available characters in the source iterator
-        for c in &mut self.source {
+        while let Some(c) = self.next_char() {
+            // a delimiter ends the current token. Keep it for the next call so that we don't read
+            // any further ahead
+            if (!current.is_empty() || quoted)
+                && !in_string
+                && !in_bars
+                && !escaped
+                && is_delimiter(c)
+            {
+                self.lookahead = Some(c);
+                return Some(Token::new(current, Span::new(start, end)).quoted(quoted));
+            }
+
+            let here = self.pos;
+            self.pos.advance(c);
+
             // comments end at the end of lines
             if in_comment {
                 if c == '\n' {
@@ -80,14 +185,24 @@ where
             }
 
             // don't do anything with characters which are escaped
+            // (except for \n etc. in strings)
             if escaped {
                 escaped = false;
-                current.push(c);
+                current.push(if in_string { unescape(c) } else { c });
+                end = here;
                 continue;
             }
 
             // begin escaping
             if c == '\\' {
+                if current.is_empty() {
+                    start = here;
+
+                    // #\x is a character. Keep the backslash so that it can be told apart from #x
+                    if self.after_hash {
+                        current.push(c);
+                    }
+                }
                 escaped = true;
                 continue;
             }
@@ -96,74 +211,74 @@ where
             if in_string {
                 // add the character to the current token
                 current.push(c);
+                end = here;
 
                 if c == '"' {
                     // end of a string
-                    self.pending.push_back(current);
-                    return self.pending.pop_front();
+                    return Some(Token::new(current, Span::new(start, end)));
                 }
 
                 continue;
             }
 
+            // characters between bars are part of the token, whatever they are
+            if c == '|' {
+                if current.is_empty() && !quoted {
+                    start = here;
+                }
+                in_bars = !in_bars;
+                quoted = true;
+                end = here;
+                continue;
+            }
+
+            if in_bars {
+                current.push(c);
+                end = here;
+                continue;
+            }
+
             // if we are starting a string
             if c == '"' {
                 in_string = true;
-                // push old current token
-                if !current.is_empty() {
-                    self.pending.push_back(current);
-                    current = String::new();
-                }
-
-                // begin the new current token
                 current.push(c);
+                start = here;
+                end = here;
                 continue;
             }
 
             // if we are starting a comment
             if c == ';' {
                 in_comment = true;
-                // push old current token
-                if !current.is_empty() {
-                    self.pending.push_back(current);
-                    current = String::new();
-                    // don't return here because we want to keep the in_comment state
-                }
-
                 continue;
             }
 
-            // if we need to split at a token other than whitespace
-            if is_special(c) {
-                // flush the previous token
-                if !current.is_empty() {
-                    self.pending.push_back(current);
-                }
-
-                // add this character (e.g. '(' as a token
-                self.pending.push_back(c.to_string());
-
-                // safe to return because we can't have any state variables true
-                return self.pending.pop_front();
+            // add this character (e.g. '(') as a token
+            if is_special(c) && current.is_empty() && !quoted {
+                return Some(Token::new(c.to_string(), Span::at(here)));
             }
 
             // if we need to split at a token we don't keep
             if c.is_whitespace() {
                 // push current token
-                if !current.is_empty() {
+                if !current.is_empty() || quoted {
                     // safe to return because no state variables can be true
-                    return Some(current);
+                    return Some(Token::new(current, Span::new(start, end)).quoted(quoted));
                 }
 
             // else just add a normal character to the current token
             } else {
+                if current.is_empty() {
+                    start = here;
+                }
                 current.push(c);
+                end = here;
             }
         } // end of source iterator
 
         // flush any remaining stuff
-        if !current.is_empty() {
-            return Some(current);
+        if !current.is_empty() || quoted {
+            return Some(Token::new(current, Span::new(start, end)).quoted(quoted));
         }
 
         None
@@ -177,11 +292,14 @@ where
     I: Into<TokenIterator<T>>,
 {
     let iter = to_tokens.into();
-    iter.collect()
+    iter.map(|t| t.text).collect()
 }
 
 #[cfg(test)]
 mod tests {
+    use span::{Position, Span};
+    use tokenise::TokenIterator;
+
     fn run_test(tv: &str, expected: &Vec<&str>) {
         let res = super::tokenise(tv.chars());
         assert_eq!(res, *expected);
@@ -245,6 +363,32 @@ mod tests {
         )
     }
 
+    #[test]
+    fn bars() {
+        run_test(
+            "(|a b| |(x)|y \\|z\\|)",
+            &vec!["(", "a b", "(x)y", "|z|", ")"],
+        )
+    }
+
+    #[test]
+    fn hash_inside_token() {
+        run_test("a# #t b#c", &vec!["a#", "#", "t", "b#c"])
+    }
+
+    #[test]
+    fn string_escapes() {
+        run_test("\"a\\nb\\\\\"", &vec!["\"a\nb\\\""])
+    }
+
+    #[test]
+    fn characters() {
+        run_test(
+            "#\\a #\\space #\\( #\\\\ \\a",
+            &vec!["#", "\\a", "#", "\\space", "#", "\\(", "#", "\\\\", "a"],
+        )
+    }
+
     #[test]
     fn comment_in_string() {
         run_test(
@@ -253,4 +397,35 @@ mod tests {
             &vec!["\"; this is not a comment\""],
         )
     }
+
+    #[test]
+    fn spans() {
+        let pos = |line, col| Position { line, col };
+        let spans: Vec<Span> = TokenIterator::new("(ab\n  \"c d\")".chars())
+            .map(|t| t.span)
+            .collect();
+
+        assert_eq!(
+            spans,
+            vec![
+                Span::at(pos(1, 1)),
+                Span::new(pos(1, 2), pos(1, 3)),
+                Span::new(pos(2, 3), pos(2, 7)),
+                Span::at(pos(2, 8)),
+            ]
+        );
+    }
+
+    #[test]
+    fn lookahead() {
+        let mut chars = "abc(d) e".chars();
+        let rest: String = {
+            let mut tokens = TokenIterator::new(&mut chars);
+            assert_eq!(tokens.next().unwrap().text, "abc");
+            // the '(' was read to find the end of "abc" but nothing after it was
+            tokens.take_lookahead().into_iter().collect()
+        };
+
+        assert_eq!(rest + &chars.collect::<String>(), "(d) e");
+    }
 }